@@ -0,0 +1,103 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use blockchain::bitcoin::{
+    addr_crawler::{AddrCrawler, NodeRecord, DEFAULT_CRAWL_CONCURRENCY, DEFAULT_CRAWL_DEPTH},
+    bitcoin_peer_discovery::BitcoinPeerDiscovery,
+    BitcoinConfiguration,
+};
+use clap::{Parser, ValueEnum};
+use futures::StreamExt;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    // one JSON object per line
+    Json,
+    Csv,
+}
+
+// Walk the network with getaddr, starting from the remote address or the DNS seeds,
+// and write one record per node with the result of its handshake
+#[derive(Debug, Parser)]
+struct CrawlerArgs {
+    #[clap(flatten)]
+    config: BitcoinConfiguration,
+
+    #[clap(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    // The file to write the records to, the standard output when not set
+    #[clap(long, short = 'o')]
+    output: Option<PathBuf>,
+
+    // The getaddr hops from the initial peers
+    #[clap(long, default_value_t = DEFAULT_CRAWL_DEPTH)]
+    depth: usize,
+
+    // The nodes visited at the same time
+    #[clap(long, default_value_t = DEFAULT_CRAWL_CONCURRENCY)]
+    concurrency: usize,
+
+    // Milliseconds between connecting to two nodes
+    #[clap(long, default_value_t = 100)]
+    interval_ms: u64,
+}
+
+// Write the records in the requested format, flushing each of them so an interrupted crawl keeps what it found
+enum RecordWriter {
+    Json(Box<dyn Write>),
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+}
+
+impl RecordWriter {
+    fn write(&mut self, record: &NodeRecord) -> io::Result<()> {
+        match self {
+            RecordWriter::Json(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+                writer.flush()
+            }
+            RecordWriter::Csv(writer) => {
+                writer.serialize(record)?;
+                writer.flush()
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+    let args = CrawlerArgs::parse();
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    let mut writer = match args.format {
+        Format::Json => RecordWriter::Json(output),
+        Format::Csv => RecordWriter::Csv(Box::new(csv::Writer::from_writer(output))),
+    };
+
+    let config = Arc::new(args.config);
+    let crawler = AddrCrawler::new(config.clone(), BitcoinPeerDiscovery::new(config))
+        .with_max_depth(args.depth)
+        .with_concurrency(args.concurrency)
+        .with_interval(Duration::from_millis(args.interval_ms));
+
+    let (mut visited, mut reachable) = (0, 0);
+    let mut records = crawler.census().await;
+    while let Some(record) = records.next().await {
+        visited += 1;
+        if record.failure.is_none() {
+            reachable += 1;
+        }
+        writer.write(&record)?;
+    }
+    eprintln!("Visited {} nodes, {} reachable", visited, reachable);
+    Ok(())
+}
@@ -0,0 +1,618 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc, future::BoxFuture, stream::FuturesUnordered, FutureExt, Stream, StreamExt,
+};
+use serde::Serialize;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::debug;
+
+use crate::protocols::peer_discovery::PeerDiscovery;
+
+use super::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    handshake::{BitcoinConnectionProtocol, Established},
+    messages::{BitcoinMessage, ServiceFlags},
+    BitcoinConfiguration,
+};
+
+// The hops of getaddr crawled from the initial peers when none is configured
+pub const DEFAULT_CRAWL_DEPTH: usize = 2;
+// The peers crawled at the same time, and the pause between two of them
+pub const DEFAULT_CRAWL_CONCURRENCY: usize = 8;
+pub const DEFAULT_CRAWL_INTERVAL: Duration = Duration::from_millis(100);
+// How long a crawled peer has to answer the getaddr
+pub const DEFAULT_ADDR_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bitcoin Core relays new addresses by 10 at most, a larger addr is the answer to our getaddr
+const MAX_RELAYED_ADDRESSES: usize = 10;
+// Short addr messages are collected until the peer stays quiet this long
+const ADDR_QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// What was learned about a node visited by a census.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeRecord {
+    pub address: SocketAddr,
+    /// The getaddr hops from the initial peers.
+    pub depth: usize,
+    pub protocol_version: Option<u32>,
+    pub services: Option<u64>,
+    pub user_agent: Option<String>,
+    pub start_height: Option<i32>,
+    /// The duration of the handshake, in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Why the handshake failed, none when it succeeded.
+    pub failure: Option<String>,
+}
+
+impl NodeRecord {
+    fn failed(address: SocketAddr, depth: usize, failure: String) -> Self {
+        NodeRecord {
+            address,
+            depth,
+            protocol_version: None,
+            services: None,
+            user_agent: None,
+            start_height: None,
+            latency_ms: None,
+            failure: Some(failure),
+        }
+    }
+}
+
+// Discover peers by asking the peers of another discovery for the addresses they know, and then the peers of those.
+// Every address is yielded once, whether it came from the initial discovery or from an addr or addrv2 answer.
+// Addresses that can not be dialed directly, like Tor or I2P, are skipped.
+pub struct AddrCrawler<D> {
+    config: Arc<BitcoinConfiguration>,
+    initial: D,
+    max_depth: usize,
+    concurrency: usize,
+    interval: Duration,
+    addr_timeout: Duration,
+}
+
+impl<D> AddrCrawler<D>
+where
+    D: PeerDiscovery<Info = BitcoinConnectionInfo>,
+{
+    pub fn new(config: Arc<BitcoinConfiguration>, initial: D) -> Self {
+        AddrCrawler {
+            config,
+            initial,
+            max_depth: DEFAULT_CRAWL_DEPTH,
+            concurrency: DEFAULT_CRAWL_CONCURRENCY,
+            interval: DEFAULT_CRAWL_INTERVAL,
+            addr_timeout: DEFAULT_ADDR_TIMEOUT,
+        }
+    }
+
+    // The initial peers are at depth 0, the addresses they answer at depth 1 and so on.
+    // Only the peers below the maximum depth are crawled, 0 yields the initial peers alone.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // The minimal pause between connecting to two crawled peers
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_addr_timeout(mut self, timeout: Duration) -> Self {
+        self.addr_timeout = timeout;
+        self
+    }
+
+    // Run the handshake with every node found up to the maximum depth, including the nodes at that depth,
+    // and yield one record per node as its handshake completes or fails
+    pub async fn census(&self) -> Pin<Box<dyn Stream<Item = NodeRecord> + Send + Sync>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.spawn(Output::Census(sender)).await;
+        Box::pin(receiver)
+    }
+
+    // The crawl runs on its own task, the results are streamed as they are learned
+    async fn spawn(&self, output: Output) {
+        let crawl = Crawl {
+            config: self.config.clone(),
+            max_depth: self.max_depth,
+            concurrency: self.concurrency,
+            interval: self.interval,
+            addr_timeout: self.addr_timeout,
+            output,
+        };
+        tokio::spawn(crawl.run(self.initial.discover_peers().await));
+    }
+}
+
+impl<D> PeerDiscovery for AddrCrawler<D>
+where
+    D: PeerDiscovery<Info = BitcoinConnectionInfo> + Sync,
+{
+    type Info = BitcoinConnectionInfo;
+
+    async fn discover_peers(&self) -> Pin<Box<dyn Stream<Item = Self::Info> + Send + Sync>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.spawn(Output::Peers(sender)).await;
+        Box::pin(receiver)
+    }
+}
+
+// What a crawl yields: the discovered addresses, or a record of every visited node
+enum Output {
+    Peers(mpsc::UnboundedSender<BitcoinConnectionInfo>),
+    Census(mpsc::UnboundedSender<NodeRecord>),
+}
+
+impl Output {
+    fn is_closed(&self) -> bool {
+        match self {
+            Output::Peers(sender) => sender.is_closed(),
+            Output::Census(sender) => sender.is_closed(),
+        }
+    }
+}
+
+// The outcome of connecting to a node
+struct Visit {
+    record: NodeRecord,
+    depth: usize,
+    // the addresses answered to the getaddr with their services, empty when it was not sent
+    addresses: Vec<(SocketAddr, ServiceFlags)>,
+}
+
+// The state of a single crawl, owned by its task
+struct Crawl {
+    config: Arc<BitcoinConfiguration>,
+    max_depth: usize,
+    concurrency: usize,
+    interval: Duration,
+    addr_timeout: Duration,
+    output: Output,
+}
+
+impl Crawl {
+    async fn run(
+        self,
+        mut initial: Pin<Box<dyn Stream<Item = BitcoinConnectionInfo> + Send + Sync>>,
+    ) {
+        let mut seen = HashSet::new();
+        // the peers waiting to be visited with their depth
+        let mut pending: VecDeque<(BitcoinConnectionInfo, usize)> = VecDeque::new();
+        let mut visiting: FuturesUnordered<BoxFuture<'static, Visit>> = FuturesUnordered::new();
+        let network = self.config.network;
+        let mut initial_done = false;
+        let mut ticker = time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            // the receiver was dropped, no more peers are needed
+            if self.output.is_closed() {
+                return;
+            }
+            let learned: Vec<(BitcoinConnectionInfo, usize)> = tokio::select! {
+                peer = initial.next(), if !initial_done => match peer {
+                    Some(peer) => vec![(peer, 0)],
+                    None => {
+                        initial_done = true;
+                        continue;
+                    }
+                },
+                Some(visit) = visiting.next(), if !visiting.is_empty() => {
+                    if let Output::Census(sender) = &self.output {
+                        if sender.unbounded_send(visit.record).is_err() {
+                            return;
+                        }
+                    }
+                    let depth = visit.depth + 1;
+                    visit
+                        .addresses
+                        .into_iter()
+                        .map(|(address, services)| {
+                            let peer =
+                                BitcoinConnectionInfo::new(address, network).with_services(services);
+                            (peer, depth)
+                        })
+                        .collect()
+                }
+                _ = ticker.tick(), if !pending.is_empty() && visiting.len() < self.concurrency => {
+                    if let Some((peer, depth)) = pending.pop_front() {
+                        // the nodes at the maximum depth are only visited by a census, and not asked for more addresses
+                        let request = depth < self.max_depth;
+                        visiting.push(
+                            visit(peer, depth, request, self.config.clone(), self.addr_timeout)
+                                .boxed(),
+                        );
+                    }
+                    continue;
+                }
+                else => return,
+            };
+            for (peer, depth) in learned {
+                if !seen.insert(peer.public_address) {
+                    continue;
+                }
+                match &self.output {
+                    Output::Peers(sender) => {
+                        if depth < self.max_depth {
+                            pending.push_back((peer.clone(), depth));
+                        }
+                        if sender.unbounded_send(peer).is_err() {
+                            return;
+                        }
+                    }
+                    Output::Census(_) => pending.push_back((peer, depth)),
+                }
+            }
+        }
+    }
+}
+
+// Run the handshake with the node and, when requested, ask for the addresses it knows
+async fn visit(
+    peer: BitcoinConnectionInfo,
+    depth: usize,
+    request: bool,
+    config: Arc<BitcoinConfiguration>,
+    timeout: Duration,
+) -> Visit {
+    let address = peer.public_address;
+    let mut established = match BitcoinConnectionProtocol::new(peer, config).connect().await {
+        Ok(established) => established,
+        Err(e) => {
+            debug!("failed to crawl {}, reason: {}", address, e);
+            return Visit {
+                record: NodeRecord::failed(address, depth, e.to_string()),
+                depth,
+                addresses: Vec::new(),
+            };
+        }
+    };
+    let report = established.report();
+    let record = NodeRecord {
+        address,
+        depth,
+        protocol_version: Some(report.remote_version),
+        services: Some(report.services.bits()),
+        user_agent: Some(report.user_agent.clone()),
+        start_height: Some(report.start_height),
+        latency_ms: Some(report.total_latency().as_millis() as u64),
+        failure: None,
+    };
+    let addresses = if request {
+        request_addresses(&mut established, timeout).await
+    } else {
+        Vec::new()
+    };
+    Visit {
+        record,
+        depth,
+        addresses,
+    }
+}
+
+// Ask the peer for the addresses it knows and return the ones that can be dialed
+async fn request_addresses(
+    established: &mut Established,
+    timeout: Duration,
+) -> Vec<(SocketAddr, ServiceFlags)> {
+    let address = established.connection_info().public_address;
+    match time::timeout(timeout, receive_addresses(established)).await {
+        Ok(Ok(addresses)) => {
+            debug!("{} answered {} addresses", address, addresses.len());
+            addresses
+        }
+        Ok(Err(e)) => {
+            debug!("failed to crawl {}, reason: {}", address, e);
+            Vec::new()
+        }
+        Err(_) => {
+            debug!("{} did not answer the getaddr in time", address);
+            Vec::new()
+        }
+    }
+}
+
+// The answer to getaddr is made of many addresses, a relay of new addresses holds a few only
+async fn receive_addresses(
+    established: &mut Established,
+) -> std::io::Result<Vec<(SocketAddr, ServiceFlags)>> {
+    established.send(BitcoinMessage::GetAddr).await?;
+    let mut received = Vec::new();
+    // set once a short addr arrived: it is the whole answer of a peer knowing few addresses, or a relay
+    let mut heard = false;
+    loop {
+        let message = if heard {
+            match time::timeout(ADDR_QUIET_PERIOD, established.next()).await {
+                Ok(message) => message,
+                Err(_) => return Ok(received),
+            }
+        } else {
+            established.next().await
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let addresses: Vec<(SocketAddr, ServiceFlags)> = match message? {
+            BitcoinMessage::Addr(addr) => addr
+                .addresses
+                .into_iter()
+                .map(|address| {
+                    let socket_address = SocketAddr::new(address.ip.into(), address.port);
+                    (socket_address, address.services)
+                })
+                .collect(),
+            BitcoinMessage::AddrV2(addr) => addr
+                .addresses
+                .into_iter()
+                .filter_map(|address| {
+                    address
+                        .address
+                        .ip()
+                        .map(|ip| (SocketAddr::new(ip, address.port), address.services))
+                })
+                .collect(),
+            BitcoinMessage::Ping(ping) => {
+                established.send(BitcoinMessage::Pong(ping)).await?;
+                continue;
+            }
+            _ => continue,
+        };
+        let answer = addresses.len() > MAX_RELAYED_ADDRESSES;
+        received.extend(
+            addresses
+                .into_iter()
+                .filter(|(address, _)| address.port() != 0),
+        );
+        if answer {
+            return Ok(received);
+        }
+        heard = true;
+    }
+    if heard {
+        return Ok(received);
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "The peer closed the connection before answering",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::{net::TcpListener, time::Instant};
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_listener::BitcoinListener,
+        fake_node::{regtest_config, StaticDiscovery},
+        messages::{
+            AddrMessage, AddrV2Message, NetworkAddress, ServiceFlags, TimestampedAddress,
+            TimestampedNetworkAddress, PROTOCOL_VERSION,
+        },
+    };
+
+    // A peer answering every getaddr with the given message
+    async fn answering_peer(
+        answer: BitcoinMessage,
+    ) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        answering_peer_with(vec![answer]).await
+    }
+
+    // A peer answering every getaddr with the given messages, in order
+    async fn answering_peer_with(
+        answers: Vec<BitcoinMessage>,
+    ) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok(mut peer) = listener.accept().await {
+                let answers = answers.clone();
+                tokio::spawn(async move {
+                    while let Some(Ok(message)) = peer.next().await {
+                        if message == BitcoinMessage::GetAddr {
+                            for answer in &answers {
+                                _ = peer.send(answer.clone()).await;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    fn addr(addresses: &[SocketAddr]) -> BitcoinMessage {
+        BitcoinMessage::Addr(AddrMessage {
+            addresses: addresses
+                .iter()
+                .map(|address| TimestampedAddress {
+                    timestamp: 0,
+                    services: ServiceFlags::NETWORK,
+                    ip: match address.ip() {
+                        std::net::IpAddr::V4(ip) => ip.into(),
+                        std::net::IpAddr::V6(ip) => ip.into(),
+                    },
+                    port: address.port(),
+                })
+                .collect(),
+        })
+    }
+
+    // Addresses nobody listens on
+    async fn unreachable() -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        Ok(TcpListener::bind("127.0.0.1:0").await?.local_addr()?)
+    }
+
+    async fn crawl(crawler: AddrCrawler<StaticDiscovery>) -> HashSet<SocketAddr> {
+        crawler
+            .discover_peers()
+            .await
+            .map(|peer| peer.public_address)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn crawl_up_to_max_depth() -> Result<(), Box<dyn std::error::Error>> {
+        let deep = unreachable().await?;
+        let second = answering_peer(addr(&[deep, unreachable().await?])).await?;
+        let dead = unreachable().await?;
+        // the answer repeats the initial peer, it is yielded once
+        let first = answering_peer(BitcoinMessage::AddrV2(AddrV2Message {
+            addresses: [
+                NetworkAddress::Ipv4(Ipv4Addr::LOCALHOST),
+                NetworkAddress::Ipv4(Ipv4Addr::LOCALHOST),
+                NetworkAddress::Ipv4(Ipv4Addr::LOCALHOST),
+                "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion".parse()?,
+            ]
+            .into_iter()
+            .zip([second.port(), dead.port(), 0, 8333])
+            .map(|(address, port)| TimestampedNetworkAddress {
+                timestamp: 0,
+                services: ServiceFlags::NETWORK,
+                address,
+                port,
+            })
+            .collect(),
+        }))
+        .await?;
+
+        let discovered = crawl(
+            AddrCrawler::new(
+                Arc::new(regtest_config()),
+                StaticDiscovery(vec![first, first]),
+            )
+            .with_max_depth(1),
+        )
+        .await;
+        // the onion address and the port 0 are skipped, the second peer is not crawled
+        assert_eq!(discovered, HashSet::from([first, second, dead]));
+
+        let discovered = crawl(
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(vec![first]))
+                .with_max_depth(2),
+        )
+        .await;
+        assert_eq!(discovered.len(), 5);
+        assert!(discovered.contains(&deep));
+
+        let discovered = crawl(
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(vec![first]))
+                .with_max_depth(0),
+        )
+        .await;
+        assert_eq!(discovered, HashSet::from([first]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pause_between_crawled_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(answering_peer(addr(&[unreachable().await?, unreachable().await?])).await?);
+        }
+        let started = Instant::now();
+        let discovered = crawl(
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(peers))
+                .with_max_depth(1)
+                .with_interval(Duration::from_millis(100)),
+        )
+        .await;
+        assert_eq!(discovered.len(), 9);
+        // the first peer is crawled right away, the two others after a pause each
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_relayed_addresses_before_answer() -> Result<(), Box<dyn std::error::Error>> {
+        let relayed: Vec<SocketAddr> = vec!["10.0.1.1:8333".parse()?, "10.0.1.2:8333".parse()?];
+        let answer: Vec<SocketAddr> = (1..=20)
+            .map(|index| SocketAddr::from(([10, 0, 2, index], 8333)))
+            .collect();
+        let peer = answering_peer_with(vec![addr(&relayed), addr(&answer)]).await?;
+
+        let discovered = crawl(
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(vec![peer]))
+                .with_max_depth(1)
+                .with_addr_timeout(Duration::from_secs(10)),
+        )
+        .await;
+        // the relay is not taken for the answer, both are kept
+        assert!(answer.iter().all(|address| discovered.contains(address)));
+        assert!(relayed.iter().all(|address| discovered.contains(address)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_answer_of_single_address() -> Result<(), Box<dyn std::error::Error>> {
+        let known: SocketAddr = "10.0.3.1:8333".parse()?;
+        let peer = answering_peer(addr(&[known])).await?;
+
+        let started = Instant::now();
+        let discovered = crawl(
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(vec![peer]))
+                .with_max_depth(1)
+                .with_addr_timeout(Duration::from_secs(10)),
+        )
+        .await;
+        assert_eq!(discovered, HashSet::from([peer, known]));
+        // the answer ends once the peer is quiet, not at the timeout
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_every_visited_node() -> Result<(), Box<dyn std::error::Error>> {
+        let dead = unreachable().await?;
+        let second = answering_peer(addr(&[unreachable().await?, unreachable().await?])).await?;
+        let first = answering_peer(addr(&[second, dead])).await?;
+
+        let mut records: Vec<NodeRecord> =
+            AddrCrawler::new(Arc::new(regtest_config()), StaticDiscovery(vec![first]))
+                .with_max_depth(1)
+                .census()
+                .await
+                .collect()
+                .await;
+        records.sort_by_key(|record| record.depth);
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0].address, first);
+        assert_eq!(records[0].depth, 0);
+        assert_eq!(records[0].protocol_version, Some(PROTOCOL_VERSION));
+        assert!(records[0].latency_ms.is_some());
+        assert!(records[0].failure.is_none());
+
+        // the nodes at the maximum depth get a handshake, but their addresses are not asked for
+        let reached = records
+            .iter()
+            .find(|record| record.address == second)
+            .unwrap();
+        assert_eq!(reached.depth, 1);
+        assert!(reached.failure.is_none());
+        let failed = records
+            .iter()
+            .find(|record| record.address == dead)
+            .unwrap();
+        assert!(failed.failure.is_some());
+        assert!(failed.user_agent.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::messages::ServiceFlags;
+
+// The tables are split into buckets of limited size, an address only fits the few buckets its key and network group hash to.
+// A single network group can not fill the tables with its addresses that way.
+const NEW_BUCKETS: usize = 1024;
+const TRIED_BUCKETS: usize = 256;
+const BUCKET_SIZE: usize = 64;
+// The number of buckets of each table a network group spreads over
+const NEW_BUCKETS_PER_GROUP: u64 = 64;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+// The chance to pick from the tried table when both tables have addresses
+const TRIED_BIAS: f64 = 0.7;
+// An address attempted this recently is rarely picked again
+const RECENT_ATTEMPT_SECS: i64 = 10 * 60;
+
+/// The address manager shared by the discovery and the connection manager.
+pub type SharedAddressManager = Arc<Mutex<AddressManager>>;
+
+/// What is known about an address of a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressInfo {
+    pub address: SocketAddr,
+    pub services: ServiceFlags,
+    /// When the address was last announced or connected, in seconds since the unix epoch.
+    pub last_seen: i64,
+    pub last_attempt: Option<i64>,
+    pub last_success: Option<i64>,
+    /// The failed attempts since the last success.
+    pub attempts: u32,
+}
+
+impl AddressInfo {
+    fn new(address: SocketAddr, services: ServiceFlags, last_seen: i64) -> Self {
+        AddressInfo {
+            address,
+            services,
+            last_seen,
+            last_attempt: None,
+            last_success: None,
+            attempts: 0,
+        }
+    }
+
+    // The relative chance to select the address, lower for addresses that keep failing or were just attempted
+    fn chance(&self, now: i64) -> f64 {
+        let mut chance = 1.0;
+        if self
+            .last_attempt
+            .is_some_and(|attempt| now - attempt < RECENT_ATTEMPT_SECS)
+        {
+            chance *= 0.01;
+        }
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Table {
+    New,
+    Tried,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    info: AddressInfo,
+    table: Table,
+}
+
+// The content of the peers file, the buckets are derived again from the key when loading
+#[derive(Serialize, Deserialize)]
+struct PeersFile {
+    key: u64,
+    entries: Vec<Entry>,
+}
+
+// Remember the addresses of peers between runs, in the spirit of the addrman of Bitcoin Core.
+// Addresses start in the new table and move to the tried table once a handshake with them succeeds.
+#[derive(Debug)]
+pub struct AddressManager {
+    // randomizes the buckets, so the placement of addresses can not be predicted by others
+    key: u64,
+    entries: HashMap<SocketAddr, Entry>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+    // where the addresses are persisted, kept in memory only when none
+    path: Option<PathBuf>,
+}
+
+impl Default for AddressManager {
+    fn default() -> Self {
+        AddressManager::new()
+    }
+}
+
+impl AddressManager {
+    pub fn new() -> Self {
+        AddressManager {
+            key: rand::random(),
+            entries: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKETS],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKETS],
+            path: None,
+        }
+    }
+
+    // Persist to the path, overwriting what it contains
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    // Load the addresses persisted at the path, starting empty when the file does not exist yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let manager = match fs::read(&path) {
+            Ok(content) => {
+                let file: PeersFile = serde_json::from_slice(&content)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let mut manager = AddressManager {
+                    key: file.key,
+                    ..AddressManager::new()
+                };
+                for entry in file.entries {
+                    manager.insert(entry);
+                }
+                debug!("loaded {} addresses from {:?}", manager.len(), path);
+                manager
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => AddressManager::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(manager.with_path(path))
+    }
+
+    // Write the addresses to the file they were opened from, nothing to do for an in-memory manager
+    pub fn persist(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = PeersFile {
+            key: self.key,
+            entries: self.entries.values().cloned().collect(),
+        };
+        let content = serde_json::to_vec(&file)?;
+        // replace the file at once, a crash while writing must not lose the previous content
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, content)?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&AddressInfo> {
+        self.entries.get(address).map(|entry| &entry.info)
+    }
+
+    // Whether a handshake with the address succeeded before
+    pub fn is_tried(&self, address: &SocketAddr) -> bool {
+        self.entries
+            .get(address)
+            .is_some_and(|entry| entry.table == Table::Tried)
+    }
+
+    // Learn about an address, or refresh when it was last seen
+    pub fn add(&mut self, address: SocketAddr, services: ServiceFlags) {
+        let now = now();
+        match self.entries.get_mut(&address) {
+            Some(entry) => {
+                entry.info.last_seen = entry.info.last_seen.max(now);
+                entry.info.services |= services;
+            }
+            None => self.insert(Entry {
+                info: AddressInfo::new(address, services, now),
+                table: Table::New,
+            }),
+        }
+    }
+
+    // The handshake with the address succeeded, move it to the tried table
+    pub fn record_success(&mut self, address: SocketAddr) {
+        let now = now();
+        let mut entry = self.remove(&address).unwrap_or(Entry {
+            info: AddressInfo::new(address, ServiceFlags::NONE, now),
+            table: Table::New,
+        });
+        entry.info.last_seen = now;
+        entry.info.last_attempt = Some(now);
+        entry.info.last_success = Some(now);
+        entry.info.attempts = 0;
+        entry.table = Table::Tried;
+        self.insert(entry);
+    }
+
+    // The connection or the handshake with the address failed
+    pub fn record_failure(&mut self, address: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&address) {
+            entry.info.last_attempt = Some(now());
+            entry.info.attempts = entry.info.attempts.saturating_add(1);
+        }
+    }
+
+    // Pick an address to connect to, biased toward the tried table and against addresses that keep failing
+    pub fn select(&self) -> Option<SocketAddr> {
+        self.select_many(1).pop()
+    }
+
+    // Pick up to `count` distinct addresses.
+    // The candidates of each table are gathered once and every pick takes its address out of them, selecting the whole table stays linear.
+    pub fn select_many(&self, count: usize) -> Vec<SocketAddr> {
+        let mut rng = rand::thread_rng();
+        let now = now();
+        let (mut new, mut tried) = (Vec::new(), Vec::new());
+        for entry in self.entries.values() {
+            match entry.table {
+                Table::New => new.push(&entry.info),
+                Table::Tried => tried.push(&entry.info),
+            }
+        }
+        let mut selected = Vec::with_capacity(count.min(self.len()));
+        while selected.len() < count {
+            let candidates = match (new.is_empty(), tried.is_empty()) {
+                (true, true) => break,
+                (false, true) => &mut new,
+                (true, false) => &mut tried,
+                (false, false) if rng.gen_bool(TRIED_BIAS) => &mut tried,
+                (false, false) => &mut new,
+            };
+            // raise the bar a little every round, so even bad addresses are picked eventually
+            let mut factor = 1.0;
+            loop {
+                let index = rng.gen_range(0..candidates.len());
+                if rng.gen::<f64>() < (candidates[index].chance(now) * factor).min(1.0) {
+                    selected.push(candidates.swap_remove(index).address);
+                    break;
+                }
+                factor *= 1.2;
+            }
+        }
+        selected
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let address = entry.info.address;
+        let (bucket, capacity_table) = match entry.table {
+            Table::New => (self.new_bucket(&address), Table::New),
+            Table::Tried => (self.tried_bucket(&address), Table::Tried),
+        };
+        if self.bucket(capacity_table, bucket).len() >= BUCKET_SIZE {
+            self.evict(capacity_table, bucket);
+        }
+        match capacity_table {
+            Table::New => self.new_buckets[bucket].push(address),
+            Table::Tried => self.tried_buckets[bucket].push(address),
+        }
+        self.entries.insert(address, entry);
+    }
+
+    fn remove(&mut self, address: &SocketAddr) -> Option<Entry> {
+        let entry = self.entries.remove(address)?;
+        let bucket = match entry.table {
+            Table::New => &mut self.new_buckets[new_bucket(self.key, address)],
+            Table::Tried => &mut self.tried_buckets[tried_bucket(self.key, address)],
+        };
+        bucket.retain(|other| other != address);
+        Some(entry)
+    }
+
+    // Make room in a full bucket.
+    // The new table forgets the address seen the longest ago, the tried table moves its oldest success back to the new table.
+    fn evict(&mut self, table: Table, bucket: usize) {
+        let oldest = self
+            .bucket(table, bucket)
+            .iter()
+            .filter_map(|address| self.entries.get(address))
+            .min_by_key(|entry| match table {
+                Table::New => entry.info.last_seen,
+                Table::Tried => entry.info.last_success.unwrap_or_default(),
+            })
+            .map(|entry| entry.info.address);
+        if let Some(mut entry) = oldest.and_then(|address| self.remove(&address)) {
+            if table == Table::Tried {
+                entry.table = Table::New;
+                self.insert(entry);
+            }
+        }
+    }
+
+    fn bucket(&self, table: Table, bucket: usize) -> &[SocketAddr] {
+        match table {
+            Table::New => &self.new_buckets[bucket],
+            Table::Tried => &self.tried_buckets[bucket],
+        }
+    }
+
+    fn new_bucket(&self, address: &SocketAddr) -> usize {
+        new_bucket(self.key, address)
+    }
+
+    fn tried_bucket(&self, address: &SocketAddr) -> usize {
+        tried_bucket(self.key, address)
+    }
+}
+
+fn new_bucket(key: u64, address: &SocketAddr) -> usize {
+    let slot = keyed_hash(key, address) % NEW_BUCKETS_PER_GROUP;
+    (keyed_hash(key, &(group(address), slot)) % NEW_BUCKETS as u64) as usize
+}
+
+fn tried_bucket(key: u64, address: &SocketAddr) -> usize {
+    let slot = keyed_hash(key, address) % TRIED_BUCKETS_PER_GROUP;
+    (keyed_hash(key, &(group(address), slot)) % TRIED_BUCKETS as u64) as usize
+}
+
+fn keyed_hash(key: u64, value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The network group of the address: the /16 of IPv4 and the /32 of IPv6 addresses, usually run by the same operator
+fn group(address: &SocketAddr) -> Vec<u8> {
+    match address.ip() {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.octets()[..2].to_vec(),
+            None => ip.octets()[..4].to_vec(),
+        },
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(index: u32) -> SocketAddr {
+        SocketAddr::new(IpAddr::from(index.to_be_bytes()), 8333)
+    }
+
+    #[test]
+    fn move_to_tried_on_success() {
+        let mut manager = AddressManager::new();
+        manager.add(address(1), ServiceFlags::NETWORK);
+        assert!(!manager.is_tried(&address(1)));
+
+        manager.record_failure(address(1));
+        assert_eq!(manager.get(&address(1)).unwrap().attempts, 1);
+
+        manager.record_success(address(1));
+        let info = manager.get(&address(1)).unwrap();
+        assert!(manager.is_tried(&address(1)));
+        assert_eq!(info.attempts, 0);
+        assert!(info.last_success.is_some());
+        assert_eq!(manager.len(), 1);
+    }
+
+    #[test]
+    fn limit_addresses_of_one_group() {
+        let mut manager = AddressManager::new();
+        // every address in 10.0.0.0/16 belongs to the same group
+        for index in 0..10_000 {
+            manager.add(address(0x0A00_0000 + index), ServiceFlags::NETWORK);
+        }
+        assert!(manager.len() <= NEW_BUCKETS_PER_GROUP as usize * BUCKET_SIZE);
+        // another group still has room
+        manager.add(address(0x0B00_0000), ServiceFlags::NETWORK);
+        assert!(manager.get(&address(0x0B00_0000)).is_some());
+    }
+
+    #[test]
+    fn prefer_tried_addresses() {
+        let mut manager = AddressManager::new();
+        for index in 0..50 {
+            manager.add(address(index << 16), ServiceFlags::NETWORK);
+        }
+        manager.record_success(address(1 << 16));
+
+        let tried = (0..1000)
+            .filter(|_| manager.select() == Some(address(1 << 16)))
+            .count();
+        // one tried address among fifty new ones is picked most of the time
+        assert!(tried > 500, "tried address selected {} times", tried);
+    }
+
+    #[test]
+    fn select_distinct_addresses() {
+        let mut manager = AddressManager::new();
+        for index in 0..5 {
+            manager.add(address(index << 16), ServiceFlags::NETWORK);
+        }
+        let mut selected = manager.select_many(10);
+        selected.sort();
+        selected.dedup();
+        assert_eq!(selected.len(), 5);
+        assert!(AddressManager::new().select().is_none());
+    }
+
+    #[test]
+    fn select_whole_table() {
+        let mut manager = AddressManager::new();
+        for index in 0..20_000 {
+            manager.add(address((index << 12) + 1), ServiceFlags::NETWORK);
+        }
+        for index in 0..100 {
+            manager.record_failure(address((index << 12) + 1));
+        }
+        let mut selected = manager.select_many(manager.len());
+        assert_eq!(selected.len(), manager.len());
+        selected.sort();
+        selected.dedup();
+        assert_eq!(selected.len(), manager.len());
+    }
+
+    #[test]
+    fn persist_and_reload() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("peers-{}.json", rand::random::<u64>()));
+        let mut manager = AddressManager::open(&path)?;
+        assert!(manager.is_empty());
+        manager.add(address(1 << 16), ServiceFlags::NETWORK);
+        manager.add(
+            address(2 << 16),
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+        );
+        manager.record_success(address(2 << 16));
+        manager.persist()?;
+
+        let reloaded = AddressManager::open(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(reloaded.len(), 2);
+        assert!(reloaded.is_tried(&address(2 << 16)));
+        assert_eq!(
+            reloaded.get(&address(1 << 16)),
+            manager.get(&address(1 << 16))
+        );
+        Ok(())
+    }
+}
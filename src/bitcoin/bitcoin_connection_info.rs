@@ -2,7 +2,10 @@ use std::net::SocketAddr;
 
 use crate::protocols::connection_info::ConnectionInfo;
 
-use super::messages::VersionMessage;
+use super::{
+    messages::{FeeFilterMessage, SendCompactMessage, ServiceFlags, VersionMessage},
+    network::Network,
+};
 
 #[derive(Clone, Debug)]
 
@@ -10,7 +13,96 @@ pub struct BitcoinConnectionInfo {
     // The public address that the peer listen on to incoming connections
     pub public_address: SocketAddr,
 
+    // The network the peer must belong to, its messages are framed with the magic of this network
+    pub network: Network,
+
+    // The host name to dial instead of the ip of the public address, e.g. a .onion address reachable through the proxy only
+    pub host: Option<String>,
+
+    // Our end of the connection, the address of the listener for inbound peers
+    pub local_address: Option<SocketAddr>,
+
+    // The services the peer offers as announced by whoever told us its address, none when unknown
+    pub services: ServiceFlags,
+
     pub(crate) version: Option<VersionMessage>,
+
+    // The remote clock minus ours in seconds, measured when the version of the peer arrived
+    pub(crate) clock_offset: Option<i64>,
+
+    // The features the peer announced between its version and verack messages
+    pub features: NegotiatedFeatures,
+}
+
+impl BitcoinConnectionInfo {
+    pub fn new(public_address: SocketAddr, network: Network) -> Self {
+        BitcoinConnectionInfo {
+            public_address,
+            network,
+            host: None,
+            local_address: None,
+            services: ServiceFlags::NONE,
+            version: None,
+            clock_offset: None,
+            features: NegotiatedFeatures::default(),
+        }
+    }
+
+    // Dial the host name on the port of the public address
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    pub fn with_local_address(mut self, address: SocketAddr) -> Self {
+        self.local_address = Some(address);
+        self
+    }
+
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
 }
 
 impl ConnectionInfo for BitcoinConnectionInfo {}
+
+// BIP 152 compact blocks relay as announced by sendcmpct
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactBlocksFeature {
+    // the peer asks to receive new blocks as cmpctblock without inv announcements
+    pub high_bandwidth: bool,
+    pub version: u64,
+}
+
+// Feature negotiation messages that are allowed before the verack message
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NegotiatedFeatures {
+    // BIP 152 sendcmpct, the highest version announced by the peer
+    pub compact_blocks: Option<CompactBlocksFeature>,
+    // BIP 339 wtxidrelay
+    pub wtxid_relay: bool,
+    // BIP 155 sendaddrv2
+    pub addr_v2: bool,
+    // BIP 133 feefilter, in satoshis per kilobyte
+    pub fee_filter: Option<i64>,
+}
+
+impl NegotiatedFeatures {
+    pub(crate) fn record_send_compact(&mut self, message: SendCompactMessage) {
+        // peers may announce several versions, keep the highest one
+        if self
+            .compact_blocks
+            .is_none_or(|current| message.version > current.version)
+        {
+            self.compact_blocks = Some(CompactBlocksFeature {
+                high_bandwidth: message.announce,
+                version: message.version,
+            });
+        }
+    }
+
+    pub(crate) fn record_fee_filter(&mut self, message: FeeFilterMessage) {
+        self.fee_filter = Some(message.fee_rate);
+    }
+}
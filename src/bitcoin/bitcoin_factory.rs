@@ -1,13 +1,35 @@
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+
 use super::{
-    bitcoin_peer::BitcoinPeer, bitcoin_peer_discovery::BitcoinPeerDiscovery, BitcoinConfiguration,
+    address_manager::AddressManager, bitcoin_peer::BitcoinPeer,
+    bitcoin_peer_discovery::BitcoinPeerDiscovery, BitcoinConfiguration,
 };
-use crate::protocols::peer::Peer;
-
 pub struct BitcoinPeerFactory;
 
 impl BitcoinPeerFactory {
-    pub fn new_peer(config: BitcoinConfiguration) -> impl Peer {
-        let peer_discovery = BitcoinPeerDiscovery::new(config);
-        BitcoinPeer::new(peer_discovery)
+    pub fn new_peer(config: BitcoinConfiguration) -> BitcoinPeer {
+        // the discovery and every connection share the same configuration
+        let config = Arc::new(config);
+        // the discovery reads the remembered addresses, the connection manager records the outcome of the dials
+        let address_manager = Arc::new(Mutex::new(Self::address_manager(&config)));
+        let peer_discovery =
+            BitcoinPeerDiscovery::new(config.clone()).with_address_manager(address_manager.clone());
+        BitcoinPeer::new(config, peer_discovery).with_address_manager(address_manager)
+    }
+
+    // Load the remembered addresses, a broken file is replaced instead of preventing the start
+    fn address_manager(config: &BitcoinConfiguration) -> AddressManager {
+        let Some(path) = &config.address_file else {
+            return AddressManager::new();
+        };
+        AddressManager::open(path).unwrap_or_else(|e| {
+            warn!(
+                "failed to load the addresses from {:?}, reason: {}",
+                path, e
+            );
+            AddressManager::new().with_path(path)
+        })
     }
 }
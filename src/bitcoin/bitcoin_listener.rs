@@ -0,0 +1,325 @@
+use std::{io::ErrorKind, net::SocketAddr, pin::Pin, sync::Arc};
+
+use futures::{stream, Stream};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+};
+use tracing::{debug, error};
+
+use super::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    handshake::{BitcoinConnectionProtocol, BitcoinHandshakeError, Established, HandshakeState},
+    v2_transport::{accept_v2, is_v1_version_prefix, PeerStream, V1_PREFIX_LENGTH},
+    BitcoinConfiguration,
+};
+
+// Accept incoming tcp connections and run the responder side of the handshake on each of them,
+// in plaintext or encrypted as the peer chooses when the v2 transport is enabled
+pub struct BitcoinListener {
+    listener: TcpListener,
+    // incoming peers must belong to the configured network
+    config: Arc<BitcoinConfiguration>,
+}
+
+impl BitcoinListener {
+    pub async fn bind(
+        address: SocketAddr,
+        config: BitcoinConfiguration,
+    ) -> Result<Self, BitcoinHandshakeError> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(BitcoinListener {
+            listener,
+            config: Arc::new(config),
+        })
+    }
+
+    // The address the listener is bound to, useful when binding to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr, BitcoinHandshakeError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Wait for the next incoming connection and complete the handshake with it
+    pub async fn accept(&self) -> Result<Established, BitcoinHandshakeError> {
+        let (channel, remote_address) = self.listener.accept().await?;
+        handshake(channel, remote_address, self.config.clone()).await
+    }
+
+    // Stream of established inbound peers.
+    // Every accepted connection runs its handshake on its own task, so a slow peer does not delay the others.
+    // At most max_inbound connections are handshaking or waiting to be taken from the stream, the next ones
+    // are accepted once a slot frees up.
+    // The accept loop stops once the returned stream is dropped.
+    pub fn incoming(
+        self,
+    ) -> Pin<Box<dyn Stream<Item = Result<Established, BitcoinHandshakeError>> + Send>> {
+        // a slot travels with its peer through the channel and frees up once the peer is taken
+        let (sender, receiver) = mpsc::channel(self.config.max_inbound.max(1));
+        let slots = Arc::new(Semaphore::new(self.config.max_inbound));
+        tokio::spawn(async move {
+            loop {
+                let slot = tokio::select! {
+                    slot = slots.clone().acquire_owned() => slot.expect("the slots are never closed"),
+                    _ = sender.closed() => break,
+                };
+                let accepted = tokio::select! {
+                    accepted = self.listener.accept() => accepted,
+                    _ = sender.closed() => break,
+                };
+                match accepted {
+                    Ok((channel, remote_address)) => {
+                        let sender = sender.clone();
+                        let config = self.config.clone();
+                        tokio::spawn(async move {
+                            let peer = handshake(channel, remote_address, config).await;
+                            // the receiver may be gone already, nothing to do with the peer then
+                            _ = sender.send((peer, slot)).await;
+                        });
+                    }
+                    Err(e) => {
+                        error!("failed to accept incoming connection, reason: {:?}", e);
+                        if sender.send((Err(e.into()), slot)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|(peer, _slot)| (peer, receiver))
+        }))
+    }
+}
+
+async fn handshake(
+    channel: TcpStream,
+    remote_address: SocketAddr,
+    config: Arc<BitcoinConfiguration>,
+) -> Result<Established, BitcoinHandshakeError> {
+    debug!("accepted incoming connection from {}", remote_address);
+    // for inbound connections we only know the address the peer connected from
+    let mut connection_info = BitcoinConnectionInfo::new(remote_address, config.network);
+    if let Ok(local_address) = channel.local_addr() {
+        connection_info = connection_info.with_local_address(local_address);
+    }
+    let channel = if config.v2_transport {
+        tokio::time::timeout(config.message_timeout, detect_transport(channel, &config))
+            .await
+            .map_err(|_| BitcoinHandshakeError::Timeout(HandshakeState::AwaitVersion))??
+    } else {
+        PeerStream::V1(channel)
+    };
+    BitcoinConnectionProtocol::new_inbound(channel, connection_info, config)
+        .connect()
+        .await
+}
+
+// A v1 peer starts with its version message, anything else is the public key of a v2 peer.
+// The caller bounds the wait with the message timeout.
+async fn detect_transport(
+    channel: TcpStream,
+    config: &BitcoinConfiguration,
+) -> Result<PeerStream, BitcoinHandshakeError> {
+    // a handle on the same socket peeks without waiting, so the readiness is consumed only
+    // when the prefix is incomplete and the next bytes of the peer wake us up again
+    let peeker: std::net::TcpStream = socket2::SockRef::from(&channel).try_clone()?.into();
+    let mut prefix = [0u8; V1_PREFIX_LENGTH];
+    loop {
+        channel.readable().await?;
+        let peeked = channel.try_io(Interest::READABLE, || match peeker.peek(&mut prefix)? {
+            peeked if peeked > 0 && peeked < V1_PREFIX_LENGTH => Err(ErrorKind::WouldBlock.into()),
+            peeked => Ok(peeked),
+        });
+        match peeked {
+            Ok(0) => {
+                return Err(BitcoinHandshakeError::ConnectionFailed(
+                    "The peer closed the connection before its first message".to_owned(),
+                ))
+            }
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if is_v1_version_prefix(&prefix, config.network) {
+        return Ok(PeerStream::V1(channel));
+    }
+    Ok(PeerStream::V2(Box::new(
+        accept_v2(channel, config.network).await?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Encoder;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::bitcoin::{
+        fake_node::{regtest_builder, regtest_config},
+        messages::{BitcoinCodec, BitcoinMessage, VersionMessage},
+        network::Network,
+    };
+
+    #[traced_test]
+    #[tokio::test]
+    async fn accept_outbound_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let listen_address = listener.local_addr()?;
+
+        let outbound = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listen_address, Network::Regtest),
+            Arc::new(regtest_config()),
+        );
+        let (inbound_result, outbound_result) = tokio::join!(listener.accept(), outbound.connect());
+
+        let inbound = inbound_result?;
+        let outbound = outbound_result?;
+        // each side received the version of the other
+        assert!(inbound.connection_info().version.is_some());
+        let version = outbound.connection_info().version.as_ref().unwrap();
+        // the listener announces the address it is bound to, and the address it was dialed from
+        assert_eq!(version.sender(), listen_address);
+        assert_eq!(version.receiver().ip(), listen_address.ip());
+        assert!(version.relay());
+        Ok(())
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn incoming_yields_established_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let listen_address = listener.local_addr()?;
+        let mut incoming = listener.incoming();
+
+        for _ in 0..2 {
+            let outbound = BitcoinConnectionProtocol::new(
+                BitcoinConnectionInfo::new(listen_address, Network::Regtest),
+                Arc::new(regtest_config()),
+            );
+            outbound.connect().await?;
+            let inbound = incoming.next().await.expect("listener stopped")?;
+            assert!(inbound.connection_info().version.is_some());
+        }
+        Ok(())
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn hold_connections_beyond_max_inbound() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind(
+            "127.0.0.1:0".parse()?,
+            regtest_builder().max_inbound(1).build(),
+        )
+        .await?;
+        let listen_address = listener.local_addr()?;
+        let mut incoming = listener.incoming();
+        let connect = || {
+            BitcoinConnectionProtocol::new(
+                BitcoinConnectionInfo::new(listen_address, Network::Regtest),
+                Arc::new(regtest_config()),
+            )
+            .connect()
+        };
+
+        connect().await?;
+        // the first peer is not taken yet, the second one waits in the accept backlog
+        let mut second = tokio::spawn(connect());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), &mut second)
+                .await
+                .is_err()
+        );
+
+        incoming.next().await.expect("listener stopped")?;
+        second.await??;
+        incoming.next().await.expect("listener stopped")?;
+        Ok(())
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn reject_connection_to_ourselves() -> Result<(), Box<dyn std::error::Error>> {
+        // the listener and the outbound connection share the nonces of the same configuration
+        let config = regtest_config();
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, config.clone()).await?;
+        let outbound = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            Arc::new(config),
+        );
+        let (inbound_result, outbound_result) = tokio::join!(listener.accept(), outbound.connect());
+
+        assert!(matches!(
+            inbound_result,
+            Err(BitcoinHandshakeError::SelfConnection)
+        ));
+        assert!(outbound_result.is_err());
+        Ok(())
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn accept_v1_and_v2_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind(
+            "127.0.0.1:0".parse()?,
+            regtest_builder().v2_transport(true).build(),
+        )
+        .await?;
+        let listen_address = listener.local_addr()?;
+        let info = BitcoinConnectionInfo::new(listen_address, Network::Regtest);
+
+        // an encrypted peer, the key exchange is driven by hand so it cannot fall back to v1
+        let v2_peer = BitcoinConnectionProtocol::new_with_transport(
+            info.clone(),
+            Arc::new(regtest_config()),
+            async move {
+                let channel = TcpStream::connect(listen_address).await?;
+                crate::bitcoin::v2_transport::connect_v2(channel, Network::Regtest).await
+            },
+        );
+        let (inbound, outbound) = tokio::join!(listener.accept(), v2_peer.connect());
+        assert!(inbound?.connection_info().version.is_some());
+        outbound?;
+
+        // a plaintext peer
+        let v1_peer = BitcoinConnectionProtocol::new(info, Arc::new(regtest_config()));
+        let (inbound, outbound) = tokio::join!(listener.accept(), v1_peer.connect());
+        assert!(inbound?.connection_info().version.is_some());
+        outbound?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_prefix_sent_in_pieces() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        let mut version = BytesMut::new();
+        BitcoinCodec::new(Network::Regtest).encode(
+            BitcoinMessage::Version(VersionMessage::new("slow peer", 0)),
+            &mut version,
+        )?;
+
+        let config = regtest_builder().v2_transport(true).build();
+        let sender = async {
+            // the magic alone, the rest of the prefix arrives later
+            client.write_all(&version[..4]).await?;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            client.write_all(&version[4..]).await?;
+            Ok::<_, std::io::Error>(())
+        };
+        let (detected, sent) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), detect_transport(server, &config)),
+            sender
+        );
+        sent?;
+        assert!(matches!(detected?, Ok(PeerStream::V1(_))));
+        Ok(())
+    }
+}
@@ -1,32 +1,49 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use futures::StreamExt;
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::sync::{watch, Mutex};
 
-use crate::protocols::{
-    peer::{LocalPeer, Peer, PeerState},
-    peer_discovery::PeerDiscovery,
-};
+use crate::protocols::peer::{LocalPeer, Peer, PeerState};
 
 use super::{
-    bitcoin_connection_info::BitcoinConnectionInfo, bitcoin_peer_discovery::BitcoinPeerDiscovery,
-    handshake::BitcoinConnectionProtocol,
+    address_manager::SharedAddressManager,
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    bitcoin_peer_discovery::BitcoinPeerDiscovery,
+    connection_manager::ConnectionManager,
+    handshake::{BitcoinConnectionProtocol, HandshakeReport},
+    keepalive::KeepAliveConnection,
+    BitcoinConfiguration,
 };
 
 pub struct BitcoinPeer {
     peer_state: Option<PeerState>,
-    peer_discovery: BitcoinPeerDiscovery,
-    connected_peers: Arc<Mutex<Vec<RemotePeer>>>,
+    connection_manager: ConnectionManager<BitcoinPeerDiscovery>,
 }
 
 impl BitcoinPeer {
-    pub fn new(peer_discovery: BitcoinPeerDiscovery) -> Self {
+    pub fn new(config: Arc<BitcoinConfiguration>, peer_discovery: BitcoinPeerDiscovery) -> Self {
         BitcoinPeer {
             peer_state: None,
-            peer_discovery,
-            connected_peers: Arc::new(Mutex::new(Vec::new())),
+            connection_manager: ConnectionManager::new(config, peer_discovery),
         }
     }
+
+    // Remember the outcome of the dials in the address manager
+    pub fn with_address_manager(mut self, address_manager: SharedAddressManager) -> Self {
+        self.connection_manager = self
+            .connection_manager
+            .with_address_manager(address_manager);
+        self
+    }
+
+    // The remote peers that completed the handshake
+    pub fn connected_peers(&self) -> Arc<Mutex<Vec<RemotePeer>>> {
+        self.connection_manager.connected_peers()
+    }
+
+    // Keep the target number of outbound connections forever, replacing the peers that drop
+    pub async fn maintain(&self) {
+        self.connection_manager.maintain().await
+    }
 }
 
 impl LocalPeer for BitcoinPeer {
@@ -38,29 +55,10 @@ impl Peer for BitcoinPeer {
         self.peer_state.as_ref()
     }
 
+    // Connect to the discovered peers in parallel until the target number of outbound connections is reached
     async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let connected_peers = self.connected_peers.clone();
-        let peers_stream = self.peer_discovery.discover_peers().await;
-        if peers_stream
-            .map(RemotePeer::new)
-            .then(move |mut peer| {
-                let connected_peers = connected_peers.clone();
-                async move {
-                    // try to connect to the peer, if succeed - store this peer for later use
-                    match peer.connect().await {
-                        Ok(_) => {
-                            let mut connected_peers = connected_peers.lock().await;
-                            connected_peers.push(peer);
-                            true
-                        }
-                        Err(_) => false,
-                    }
-                }
-            })
-            // in this version we connect only to one peer
-            .any(|result| async move { result })
-            .await
-        {
+        if self.connection_manager.fill().await > 0 {
+            self.peer_state = Some(PeerState::Authenticated);
             Ok(())
         } else {
             Err("Unable to connect to at least one peer".into())
@@ -68,22 +66,65 @@ impl Peer for BitcoinPeer {
     }
 }
 
-struct RemotePeer {
-    // The underlined tcp stream that communicate with the other
-    channel: Option<TcpStream>,
+pub struct RemotePeer {
+    // The established connection used to communicate with the other, kept alive with pings
+    connection: Option<KeepAliveConnection>,
     // The connection information used to connect to the remote peer
     connection_info: BitcoinConnectionInfo,
+    // What was negotiated during the handshake, kept after the connection is taken
+    report: Option<HandshakeReport>,
+    // The latest round trip time measured by the pings of the connection
+    round_trip_time: Option<watch::Receiver<Option<Duration>>>,
+    // The parameters of the local peer used during the handshake
+    config: Arc<BitcoinConfiguration>,
     peer_state: Option<PeerState>,
 }
 
 impl RemotePeer {
-    fn new(connection_info: BitcoinConnectionInfo) -> Self {
+    pub(crate) fn new(
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+    ) -> Self {
         RemotePeer {
-            channel: None,
+            connection: None,
             peer_state: None,
             connection_info,
+            report: None,
+            round_trip_time: None,
+            config,
         }
     }
+
+    pub fn connection_info(&self) -> &BitcoinConnectionInfo {
+        &self.connection_info
+    }
+
+    pub fn report(&self) -> Option<&HandshakeReport> {
+        self.report.as_ref()
+    }
+
+    // Whether the connection is still alive, a dropped connection can not be used anymore
+    pub fn is_connected(&self) -> bool {
+        self.round_trip_time
+            .as_ref()
+            .is_some_and(|round_trip_time| round_trip_time.has_changed().is_ok())
+    }
+
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time
+            .as_ref()
+            .and_then(|round_trip_time| *round_trip_time.borrow())
+    }
+
+    // The connection to exchange messages with, available once connected
+    pub fn connection(&mut self) -> Option<&mut KeepAliveConnection> {
+        self.connection.as_mut()
+    }
+
+    // Take the ownership of the connection, for example to move it to another task
+    pub fn take_connection(&mut self) -> Option<KeepAliveConnection> {
+        self.connection.take()
+    }
 }
 impl Peer for RemotePeer {
     fn get_state(&self) -> Option<&PeerState> {
@@ -92,12 +133,20 @@ impl Peer for RemotePeer {
 
     // try to connect to the remote peer with the information from the connection_info
     async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut connection_protocol = BitcoinConnectionProtocol::new(self.connection_info.clone());
+        let connection_protocol =
+            BitcoinConnectionProtocol::new(self.connection_info.clone(), self.config.clone());
         match connection_protocol.connect().await {
-            Ok(value) => {
-                self.channel = Some(value.0);
+            Ok(established) => {
+                self.connection_info = established.connection_info().clone();
+                self.report = Some(established.report().clone());
+                let connection = KeepAliveConnection::new(
+                    established,
+                    self.config.ping_interval,
+                    self.config.ping_timeout,
+                );
+                self.round_trip_time = Some(connection.watch_round_trip_time());
+                self.connection = Some(connection);
                 self.peer_state = Some(PeerState::Authenticated);
-                self.connection_info = value.1;
                 Ok(())
             }
             Err(e) => {
@@ -1,31 +1,89 @@
-use futures::{stream, Stream};
-use std::pin::Pin;
+use futures::{stream, Stream, StreamExt};
+use std::{pin::Pin, sync::Arc};
 
 use crate::protocols::peer_discovery::PeerDiscovery;
 
-use super::{bitcoin_connection_info::BitcoinConnectionInfo, BitcoinConfiguration};
+use super::{
+    address_manager::SharedAddressManager,
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    dns_seed::{DnsResolver, DnsSeedDiscovery, SystemResolver},
+    BitcoinConfiguration,
+};
 
-pub struct BitcoinPeerDiscovery {
-    config: BitcoinConfiguration,
+// The remembered addresses dialed before the DNS seeds are queried, more would only delay the seeds
+// when the remembered addresses are stale
+const MAX_REMEMBERED_PEERS: usize = 1000;
+
+// Discover the configured remote peer.
+// When none is configured, the addresses remembered by the address manager come first and the DNS seeds are queried once they run out.
+pub struct BitcoinPeerDiscovery<R = SystemResolver> {
+    config: Arc<BitcoinConfiguration>,
+    dns_seeds: DnsSeedDiscovery<R>,
+    address_manager: Option<SharedAddressManager>,
+}
+
+impl BitcoinPeerDiscovery<SystemResolver> {
+    pub fn new(config: Arc<BitcoinConfiguration>) -> Self {
+        BitcoinPeerDiscovery::with_resolver(config, SystemResolver)
+    }
 }
 
-impl BitcoinPeerDiscovery {
-    pub fn new(config: BitcoinConfiguration) -> Self {
-        BitcoinPeerDiscovery { config }
+impl<R: DnsResolver> BitcoinPeerDiscovery<R> {
+    pub fn with_resolver(config: Arc<BitcoinConfiguration>, resolver: R) -> Self {
+        BitcoinPeerDiscovery {
+            dns_seeds: DnsSeedDiscovery::with_resolver(config.network, resolver),
+            config,
+            address_manager: None,
+        }
+    }
+
+    pub fn with_address_manager(mut self, address_manager: SharedAddressManager) -> Self {
+        self.address_manager = Some(address_manager);
+        self
+    }
+
+    // The remembered addresses in the order they should be dialed, with the services they were remembered with
+    fn remembered_peers(&self) -> Vec<BitcoinConnectionInfo> {
+        let Some(address_manager) = &self.address_manager else {
+            return Vec::new();
+        };
+        let address_manager = address_manager
+            .lock()
+            .expect("address manager lock poisoned");
+        address_manager
+            .select_many(MAX_REMEMBERED_PEERS)
+            .into_iter()
+            .filter_map(|address| address_manager.get(&address))
+            .map(|info| {
+                BitcoinConnectionInfo::new(info.address, self.config.network)
+                    .with_services(info.services)
+            })
+            .collect()
     }
 }
 
-impl PeerDiscovery for BitcoinPeerDiscovery {
+impl<R: DnsResolver> PeerDiscovery for BitcoinPeerDiscovery<R> {
     type Info = BitcoinConnectionInfo;
     async fn discover_peers(&self) -> Pin<Box<dyn Stream<Item = Self::Info> + Send + Sync>> {
-        let connection_info = BitcoinConnectionInfo {
-            public_address: self.config.discover_remote_peer_address,
-            version: None,
-        };
-        let stream = stream::once(async move { connection_info });
-        // require since Once itself does not implement Unpin
-        let pinned_stream = Box::pin(stream);
-        pinned_stream
+        let network = self.config.network;
+        match self.config.discover_remote_peer_address {
+            // an explicit remote peer replaces the seeds
+            Some(mut address) => {
+                if address.port() == 0 {
+                    address.set_port(network.default_port());
+                }
+                Box::pin(stream::once(async move {
+                    BitcoinConnectionInfo::new(address, network)
+                }))
+            }
+            None => {
+                let dns_seeds = self.dns_seeds.clone();
+                // the seeds are only queried when the stream is polled past the remembered addresses
+                let seeded =
+                    stream::once(async move { dns_seeds.discover_peers().await }).flatten();
+                Box::pin(stream::iter(self.remembered_peers()).chain(seeded))
+            }
+        }
     }
 }
 
@@ -33,19 +91,26 @@ impl PeerDiscovery for BitcoinPeerDiscovery {
 mod tests {
     use clap::Parser;
     use futures::StreamExt;
-    use std::net::SocketAddr;
+    use std::net::{IpAddr, SocketAddr};
     use std::str::FromStr;
+    use std::sync::Mutex;
 
     use super::*;
+    use crate::bitcoin::{
+        address_manager::AddressManager, fake_node::regtest_config, messages::ServiceFlags,
+        network::Network,
+    };
 
     #[test]
     fn test_discover_single_peer() {
         let expected_remote_peer_address = "127.0.0.1:8333";
-        // Set up the environment variable
-        std::env::set_var("DISCOVER_REMOTE_PEER_ADDRESS", expected_remote_peer_address);
 
         // Create a BitcoinPeerDiscovery instance
-        let discovery = BitcoinPeerDiscovery::new(BitcoinConfiguration::parse());
+        let discovery = BitcoinPeerDiscovery::new(Arc::new(
+            BitcoinConfiguration::builder()
+                .remote_address(SocketAddr::from_str(expected_remote_peer_address).unwrap())
+                .build(),
+        ));
 
         // Run the async test
         let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -61,4 +126,72 @@ mod tests {
             }
         });
     }
+
+    #[tokio::test]
+    async fn use_default_port_of_network() {
+        let config =
+            BitcoinConfiguration::try_parse_from(["test", "-A", "127.0.0.1", "-N", "regtest"])
+                .unwrap();
+        let discovery = BitcoinPeerDiscovery::new(Arc::new(config));
+        let peer_connection_info = discovery.discover_peers().await.next().await.unwrap();
+        assert_eq!(
+            peer_connection_info.public_address,
+            SocketAddr::from_str("127.0.0.1:18444").unwrap()
+        );
+        assert_eq!(peer_connection_info.network, Network::Regtest);
+    }
+
+    // Answer every query with the same address
+    struct FixedResolver(IpAddr);
+
+    impl DnsResolver for FixedResolver {
+        async fn resolve(&self, _host: &str) -> std::io::Result<Vec<IpAddr>> {
+            Ok(vec![self.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn query_seeds_without_remote_address() {
+        let discovery = BitcoinPeerDiscovery::with_resolver(
+            Arc::new(BitcoinConfiguration::default()),
+            FixedResolver("10.0.0.1".parse().unwrap()),
+        );
+        let peers: Vec<_> = discovery.discover_peers().await.collect().await;
+        assert_eq!(peers.len(), Network::Mainnet.dns_seeds().len());
+        assert_eq!(
+            peers[0].public_address,
+            SocketAddr::from_str("10.0.0.1:8333").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_to_discover_on_regtest_without_remote_address() {
+        let discovery = BitcoinPeerDiscovery::with_resolver(
+            Arc::new(regtest_config()),
+            FixedResolver("10.0.0.1".parse().unwrap()),
+        );
+        assert!(discovery.discover_peers().await.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn remembered_addresses_before_seeds() {
+        let remembered = SocketAddr::from_str("10.0.0.2:8333").unwrap();
+        let mut address_manager = AddressManager::new();
+        address_manager.add(remembered, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+        address_manager.record_success(remembered);
+        let discovery = BitcoinPeerDiscovery::with_resolver(
+            Arc::new(BitcoinConfiguration::default()),
+            FixedResolver("10.0.0.1".parse().unwrap()),
+        )
+        .with_address_manager(Arc::new(Mutex::new(address_manager)));
+
+        let peers: Vec<_> = discovery.discover_peers().await.collect().await;
+        assert_eq!(peers.len(), Network::Mainnet.dns_seeds().len() + 1);
+        assert_eq!(peers[0].public_address, remembered);
+        // dialed with the services it was remembered with
+        assert_eq!(
+            peers[0].services,
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS
+        );
+    }
 }
@@ -0,0 +1,317 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{future, StreamExt};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::protocols::{peer::Peer, peer_discovery::PeerDiscovery};
+
+use super::{
+    address_manager::SharedAddressManager, bitcoin_connection_info::BitcoinConnectionInfo,
+    bitcoin_peer::RemotePeer, BitcoinConfiguration,
+};
+
+// How often `maintain` looks for dropped peers and dials replacements
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keep the configured number of outbound connections established.
+// Candidates from the discovery are dialed in parallel, up to the configured number of dials at a time.
+pub struct ConnectionManager<D> {
+    config: Arc<BitcoinConfiguration>,
+    discovery: D,
+    connected_peers: Arc<Mutex<Vec<RemotePeer>>>,
+    // learns the outcome of every dial, persisted after each fill
+    address_manager: Option<SharedAddressManager>,
+}
+
+impl<D> ConnectionManager<D>
+where
+    D: PeerDiscovery<Info = BitcoinConnectionInfo>,
+{
+    pub fn new(config: Arc<BitcoinConfiguration>, discovery: D) -> Self {
+        ConnectionManager {
+            config,
+            discovery,
+            connected_peers: Arc::new(Mutex::new(Vec::new())),
+            address_manager: None,
+        }
+    }
+
+    pub fn with_address_manager(mut self, address_manager: SharedAddressManager) -> Self {
+        self.address_manager = Some(address_manager);
+        self
+    }
+
+    // The remote peers that completed the handshake
+    pub fn connected_peers(&self) -> Arc<Mutex<Vec<RemotePeer>>> {
+        self.connected_peers.clone()
+    }
+
+    // Forget the peers that dropped, then dial discovered peers until the target is reached or the candidates run out.
+    // Returns the number of established connections.
+    pub async fn fill(&self) -> usize {
+        let (missing, known_addresses) = {
+            let mut connected_peers = self.connected_peers.lock().await;
+            connected_peers.retain(|peer| {
+                let connected = peer.is_connected();
+                if !connected {
+                    info!("peer {} dropped", peer.connection_info().public_address);
+                }
+                connected
+            });
+            let known_addresses: HashSet<SocketAddr> = connected_peers
+                .iter()
+                .map(|peer| peer.connection_info().public_address)
+                .collect();
+            (
+                self.config
+                    .target_outbound
+                    .saturating_sub(connected_peers.len()),
+                known_addresses,
+            )
+        };
+        if missing > 0 {
+            self.dial(missing, known_addresses).await;
+            self.persist_addresses();
+        }
+        self.connected_peers.lock().await.len()
+    }
+
+    // Keep the target number of connections forever, replacing the peers that drop
+    pub async fn maintain(&self) {
+        let mut interval = tokio::time::interval(MAINTAIN_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.fill().await;
+        }
+    }
+
+    fn persist_addresses(&self) {
+        if let Some(address_manager) = &self.address_manager {
+            let address_manager = address_manager
+                .lock()
+                .expect("address manager lock poisoned");
+            if let Err(e) = address_manager.persist() {
+                warn!("failed to persist the addresses, reason: {}", e);
+            }
+        }
+    }
+
+    async fn dial(&self, missing: usize, mut known_addresses: HashSet<SocketAddr>) {
+        let config = self.config.clone();
+        let address_manager = self.address_manager.clone();
+        let connected_peers = self.connected_peers.clone();
+        self.discovery
+            .discover_peers()
+            .await
+            // the discovery may repeat addresses, dial every address once
+            .filter(move |connection_info| {
+                future::ready(known_addresses.insert(connection_info.public_address))
+            })
+            .map(move |connection_info| {
+                // remember the address before the dial, so its outcome is recorded whatever discovered it
+                if let Some(address_manager) = &address_manager {
+                    let mut address_manager = address_manager
+                        .lock()
+                        .expect("address manager lock poisoned");
+                    if address_manager
+                        .get(&connection_info.public_address)
+                        .is_none()
+                    {
+                        address_manager
+                            .add(connection_info.public_address, connection_info.services);
+                    }
+                }
+                let mut peer = RemotePeer::new(connection_info, config.clone());
+                let address_manager = address_manager.clone();
+                async move {
+                    let result = peer.connect().await;
+                    if let Some(address_manager) = address_manager {
+                        let mut address_manager = address_manager
+                            .lock()
+                            .expect("address manager lock poisoned");
+                        let address = peer.connection_info().public_address;
+                        match &result {
+                            Ok(_) => address_manager.record_success(address),
+                            Err(_) => address_manager.record_failure(address),
+                        }
+                    }
+                    match result {
+                        Ok(_) => Some(peer),
+                        Err(e) => {
+                            debug!(
+                                "failed to connect to {}, reason: {}",
+                                peer.connection_info().public_address,
+                                e
+                            );
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(self.config.max_concurrent_dials.max(1))
+            .filter_map(future::ready)
+            // the dials still in flight are dropped once enough peers connected
+            .take(missing)
+            .for_each(|peer| {
+                let connected_peers = connected_peers.clone();
+                async move {
+                    info!("connected to {}", peer.connection_info().public_address);
+                    connected_peers.lock().await.push(peer);
+                }
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, time::Instant};
+
+    use super::*;
+    use crate::bitcoin::{
+        address_manager::AddressManager,
+        bitcoin_listener::BitcoinListener,
+        fake_node::{regtest_builder, regtest_config, StaticDiscovery},
+        messages::ServiceFlags,
+        Established,
+    };
+
+    fn config(target_outbound: usize) -> Arc<BitcoinConfiguration> {
+        Arc::new(
+            regtest_builder()
+                .target_outbound(target_outbound)
+                .message_timeout(Duration::from_secs(5))
+                .build(),
+        )
+    }
+
+    // A listener completing the handshake with every incoming connection, the connections are kept open by its task
+    async fn listening_peer(
+    ) -> Result<(SocketAddr, tokio::task::JoinHandle<()>), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let address = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut established: Vec<Established> = Vec::new();
+            let mut incoming = listener.incoming();
+            while let Some(peer) = incoming.next().await {
+                established.extend(peer);
+            }
+        });
+        Ok((address, task))
+    }
+
+    #[tokio::test]
+    async fn connect_up_to_target() -> Result<(), Box<dyn std::error::Error>> {
+        let mut addresses = Vec::new();
+        for _ in 0..3 {
+            addresses.push(listening_peer().await?.0);
+        }
+        // the repeated address is dialed once
+        addresses.push(addresses[0]);
+
+        let manager = ConnectionManager::new(config(2), StaticDiscovery(addresses));
+        assert_eq!(manager.fill().await, 2);
+        // the target is reached, nothing more to dial
+        assert_eq!(manager.fill().await, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dial_candidates_in_parallel() -> Result<(), Box<dyn std::error::Error>> {
+        // peers that accept the connection but never answer the version
+        let mut silent = Vec::new();
+        let mut addresses = Vec::new();
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addresses.push(listener.local_addr()?);
+            silent.push(listener);
+        }
+        let (address, _) = listening_peer().await?;
+        addresses.push(address);
+
+        let started = Instant::now();
+        let manager = ConnectionManager::new(config(1), StaticDiscovery(addresses));
+        assert_eq!(manager.fill().await, 1);
+        // dialed one by one, the silent peers would have taken their message timeout each
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_dropped_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let manager =
+            ConnectionManager::new(config(1), StaticDiscovery(vec![listener.local_addr()?]));
+
+        let (connected, inbound) = tokio::join!(manager.fill(), listener.accept());
+        assert_eq!(connected, 1);
+        // the remote side goes away
+        drop(inbound?);
+        let peers = manager.connected_peers();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peers.lock().await[0].is_connected() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        let (connected, inbound) = tokio::join!(manager.fill(), listener.accept());
+        assert_eq!(connected, 1);
+        assert!(peers.lock().await[0].is_connected());
+        drop(inbound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_outcome_of_dials() -> Result<(), Box<dyn std::error::Error>> {
+        let (reachable, _) = listening_peer().await?;
+        let unreachable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut address_manager = AddressManager::new();
+        address_manager.add(unreachable, ServiceFlags::NONE);
+        let address_manager = Arc::new(std::sync::Mutex::new(address_manager));
+
+        let manager =
+            ConnectionManager::new(config(2), StaticDiscovery(vec![unreachable, reachable]))
+                .with_address_manager(address_manager.clone());
+        assert_eq!(manager.fill().await, 1);
+
+        let address_manager = address_manager.lock().unwrap();
+        assert!(address_manager.is_tried(&reachable));
+        assert_eq!(address_manager.get(&unreachable).unwrap().attempts, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remember_discovered_addresses() -> Result<(), Box<dyn std::error::Error>> {
+        let (reachable, _) = listening_peer().await?;
+        let unreachable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let address_manager = Arc::new(std::sync::Mutex::new(AddressManager::new()));
+
+        let manager =
+            ConnectionManager::new(config(2), StaticDiscovery(vec![unreachable, reachable]))
+                .with_address_manager(address_manager.clone());
+        assert_eq!(manager.fill().await, 1);
+
+        // the discovery announced the services of both addresses
+        let address_manager = address_manager.lock().unwrap();
+        assert!(address_manager.is_tried(&reachable));
+        assert_eq!(
+            address_manager.get(&reachable).unwrap().services,
+            ServiceFlags::NETWORK
+        );
+        let failed = address_manager.get(&unreachable).unwrap();
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(failed.services, ServiceFlags::NETWORK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_when_candidates_run_out() -> Result<(), Box<dyn std::error::Error>> {
+        // nothing listens on the address once the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let manager = ConnectionManager::new(config(2), StaticDiscovery(vec![address]));
+        assert_eq!(manager.fill().await, 0);
+        Ok(())
+    }
+}
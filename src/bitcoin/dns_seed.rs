@@ -0,0 +1,213 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+};
+
+use futures::{channel::mpsc, stream, SinkExt, Stream, StreamExt};
+use tracing::{debug, warn};
+
+use crate::protocols::peer_discovery::PeerDiscovery;
+
+use super::{
+    bitcoin_connection_info::BitcoinConnectionInfo, messages::ServiceFlags, network::Network,
+};
+
+// The seeds queried at the same time
+const CONCURRENT_QUERIES: usize = 4;
+
+/// Resolve a host name to its addresses.
+///
+/// Implemented with the resolver of the operating system by `SystemResolver`, tests substitute their own.
+pub trait DnsResolver: Send + Sync + 'static {
+    fn resolve(&self, host: &str) -> impl Future<Output = io::Result<Vec<IpAddr>>> + Send;
+}
+
+/// Resolve with the resolver of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl DnsResolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        // the port is required by the lookup but not part of the answer
+        let addresses = tokio::net::lookup_host((host, 0)).await?;
+        Ok(addresses.map(|address| address.ip()).collect())
+    }
+}
+
+// Discover peers from the DNS seeds of the network.
+// Seeds answer with nodes offering the requested services when queried with the `x<hex services>.` subdomain.
+pub struct DnsSeedDiscovery<R = SystemResolver> {
+    network: Network,
+    services: ServiceFlags,
+    resolver: Arc<R>,
+}
+
+// Clones share the resolver
+impl<R> Clone for DnsSeedDiscovery<R> {
+    fn clone(&self) -> Self {
+        DnsSeedDiscovery {
+            network: self.network,
+            services: self.services,
+            resolver: self.resolver.clone(),
+        }
+    }
+}
+
+impl DnsSeedDiscovery<SystemResolver> {
+    pub fn new(network: Network) -> Self {
+        DnsSeedDiscovery::with_resolver(network, SystemResolver)
+    }
+}
+
+impl<R: DnsResolver> DnsSeedDiscovery<R> {
+    pub fn with_resolver(network: Network, resolver: R) -> Self {
+        DnsSeedDiscovery {
+            network,
+            // full nodes serving witness data, what the seeds announce as x9
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            resolver: Arc::new(resolver),
+        }
+    }
+
+    // Ask the seeds for nodes offering these services, none queries the seeds without a filter
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    // The host names to query, one per seed
+    fn hosts(&self) -> Vec<String> {
+        self.network
+            .dns_seeds()
+            .iter()
+            .map(|seed| {
+                if self.services == ServiceFlags::NONE {
+                    seed.to_string()
+                } else {
+                    format!("x{:x}.{}", self.services.bits(), seed)
+                }
+            })
+            .collect()
+    }
+}
+
+impl<R: DnsResolver> PeerDiscovery for DnsSeedDiscovery<R> {
+    type Info = BitcoinConnectionInfo;
+
+    async fn discover_peers(&self) -> Pin<Box<dyn Stream<Item = Self::Info> + Send + Sync>> {
+        let (mut sender, receiver) = mpsc::unbounded();
+        let resolver = self.resolver.clone();
+        let network = self.network;
+        // the seeds only answer nodes offering the requested services
+        let services = self.services;
+        let hosts = self.hosts();
+        // the seeds are queried on their own task, the addresses are streamed as each seed answers
+        tokio::spawn(async move {
+            let mut answers = stream::iter(hosts)
+                .map(|host| {
+                    let resolver = resolver.clone();
+                    async move {
+                        let answer = resolver.resolve(&host).await;
+                        (host, answer)
+                    }
+                })
+                .buffer_unordered(CONCURRENT_QUERIES);
+            while let Some((host, answer)) = answers.next().await {
+                match answer {
+                    Ok(addresses) => {
+                        debug!("seed {} answered {} addresses", host, addresses.len());
+                        let mut peers = stream::iter(addresses).map(|ip| {
+                            Ok(BitcoinConnectionInfo::new(
+                                SocketAddr::new(ip, network.default_port()),
+                                network,
+                            )
+                            .with_services(services))
+                        });
+                        // the receiver was dropped, no more peers are needed
+                        if sender.send_all(&mut peers).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("failed to query seed {}, reason: {}", host, e),
+                }
+            }
+        });
+        Box::pin(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    // Answer from a fixed table and remember the queried hosts
+    #[derive(Default)]
+    struct StubResolver {
+        answers: HashMap<String, Vec<IpAddr>>,
+        queried: Mutex<Vec<String>>,
+    }
+
+    impl DnsResolver for StubResolver {
+        async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+            self.queried.lock().unwrap().push(host.to_owned());
+            self.answers
+                .get(host)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown host"))
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_addresses_of_every_seed() {
+        let mut resolver = StubResolver::default();
+        resolver.answers.insert(
+            "x9.seed.testnet4.bitcoin.sprovoost.nl".to_owned(),
+            vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+        );
+        // the other seed fails, its peers are missing but the stream goes on
+        let discovery = DnsSeedDiscovery::with_resolver(Network::Testnet4, resolver);
+
+        let mut peers: Vec<_> = discovery
+            .discover_peers()
+            .await
+            .map(|peer| {
+                // the seeds answer nodes offering the requested services
+                assert_eq!(peer.services, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+                peer.public_address
+            })
+            .collect()
+            .await;
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![
+                "10.0.0.1:48333".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:48333".parse().unwrap()
+            ]
+        );
+        let mut queried = discovery.resolver.queried.lock().unwrap().clone();
+        queried.sort();
+        assert_eq!(
+            queried,
+            [
+                "x9.seed.testnet4.bitcoin.sprovoost.nl",
+                "x9.seed.testnet4.wiz.biz"
+            ]
+        );
+    }
+
+    #[test]
+    fn query_seeds_for_requested_services() {
+        let discovery = DnsSeedDiscovery::with_resolver(Network::Signet, StubResolver::default());
+        assert_eq!(discovery.hosts()[0], "x9.seed.signet.bitcoin.sprovoost.nl");
+        let discovery = discovery.with_services(ServiceFlags::NETWORK);
+        assert_eq!(discovery.hosts()[0], "x1.seed.signet.bitcoin.sprovoost.nl");
+        let discovery = discovery.with_services(ServiceFlags::NONE);
+        assert_eq!(discovery.hosts()[0], "seed.signet.bitcoin.sprovoost.nl");
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::{stream, SinkExt, Stream, StreamExt};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_util::codec::{Encoder, Framed};
+use tracing::debug;
+
+use crate::protocols::peer_discovery::PeerDiscovery;
+
+use super::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    messages::{BitcoinCodec, BitcoinMessage, SendCompactMessage, ServiceFlags, VersionMessage},
+    network::Network,
+    BitcoinConfiguration, BitcoinConfigurationBuilder,
+};
+
+// The offset of the checksum in the message header: magic, command and payload length come first
+const CHECKSUM_OFFSET: usize = 20;
+
+/// How the fake node plays the responder side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakeNodeBehaviour {
+    /// Answer the version and acknowledge ours like a regular node.
+    Normal,
+    /// Wait before answering the version.
+    Slow(Duration),
+    /// Send sendcmpct between the version and the verack, as Bitcoin Core does.
+    SendCompactBeforeVerAck,
+    /// Answer with a version whose checksum does not match its payload.
+    BadChecksum,
+    /// Answer with a version framed with the magic of another network.
+    WrongMagic,
+    /// Close the connection once our version is received, without answering it.
+    DisconnectMidHandshake,
+}
+
+/// A scriptable Bitcoin node listening on localhost, to test the handshake without a real node.
+///
+/// Every accepted connection plays the configured behaviour, then answers pings and records the
+/// received messages until the connection is closed.
+#[derive(Debug, Clone)]
+pub struct FakeNode {
+    network: Network,
+    behaviour: FakeNodeBehaviour,
+    version: VersionMessage,
+}
+
+impl FakeNode {
+    pub fn new(network: Network, behaviour: FakeNodeBehaviour) -> Self {
+        FakeNode {
+            network,
+            behaviour,
+            version: VersionMessage::new("/fake-node:0.1/", 0),
+        }
+    }
+
+    // The version answered to the peers, e.g. an obsolete protocol version or missing services
+    pub fn with_version(mut self, version: VersionMessage) -> Self {
+        self.version = version;
+        self
+    }
+
+    // Listen on a free port of the loopback interface, the node stops when the handle is dropped
+    pub async fn listen(self) -> io::Result<FakeNodeHandle> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local_addr = listener.local_addr()?;
+        let network = self.network;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(accept_loop(listener, self, received.clone()));
+        Ok(FakeNodeHandle {
+            local_addr,
+            network,
+            received,
+            task,
+        })
+    }
+}
+
+/// A running fake node.
+#[derive(Debug)]
+pub struct FakeNodeHandle {
+    local_addr: SocketAddr,
+    network: Network,
+    received: Arc<Mutex<Vec<BitcoinMessage>>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeNodeHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // The connection info to dial the node with
+    pub fn connection_info(&self) -> BitcoinConnectionInfo {
+        BitcoinConnectionInfo::new(self.local_addr, self.network)
+    }
+
+    // The messages received so far from every connection, in order
+    pub fn received(&self) -> Vec<BitcoinMessage> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeNodeHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    node: FakeNode,
+    received: Arc<Mutex<Vec<BitcoinMessage>>>,
+) {
+    while let Ok((channel, remote_address)) = listener.accept().await {
+        let node = node.clone();
+        let received = received.clone();
+        tokio::spawn(async move {
+            if let Err(e) = play(channel, node, received).await {
+                debug!(
+                    "fake node connection {} closed, reason: {}",
+                    remote_address, e
+                );
+            }
+        });
+    }
+}
+
+// Play the behaviour of the node on one connection
+async fn play(
+    channel: TcpStream,
+    node: FakeNode,
+    received: Arc<Mutex<Vec<BitcoinMessage>>>,
+) -> io::Result<()> {
+    let mut framed = Framed::new(channel, BitcoinCodec::new(node.network));
+    // the dialing peer speaks first
+    match framed.next().await {
+        Some(message) => received.lock().unwrap().push(message?),
+        None => return Ok(()),
+    }
+
+    let version = BitcoinMessage::Version(node.version.clone());
+    match node.behaviour {
+        FakeNodeBehaviour::DisconnectMidHandshake => return Ok(()),
+        FakeNodeBehaviour::BadChecksum => {
+            let mut buffer = encode(node.network, version)?;
+            buffer[CHECKSUM_OFFSET] ^= 0xff;
+            framed.get_mut().write_all(&buffer).await?;
+        }
+        FakeNodeBehaviour::WrongMagic => {
+            let other_network = match node.network {
+                Network::Mainnet => Network::Regtest,
+                _ => Network::Mainnet,
+            };
+            let buffer = encode(other_network, version)?;
+            framed.get_mut().write_all(&buffer).await?;
+        }
+        FakeNodeBehaviour::Slow(delay) => {
+            tokio::time::sleep(delay).await;
+            framed.send(version).await?;
+            framed.send(BitcoinMessage::VerAck).await?;
+        }
+        FakeNodeBehaviour::SendCompactBeforeVerAck => {
+            framed.send(version).await?;
+            framed
+                .send(BitcoinMessage::SendCmpct(SendCompactMessage {
+                    announce: false,
+                    version: 2,
+                }))
+                .await?;
+            framed.send(BitcoinMessage::VerAck).await?;
+        }
+        FakeNodeBehaviour::Normal => {
+            framed.send(version).await?;
+            framed.send(BitcoinMessage::VerAck).await?;
+        }
+    }
+
+    while let Some(message) = framed.next().await {
+        let message = message?;
+        if let BitcoinMessage::Ping(ping) = &message {
+            framed.send(BitcoinMessage::Pong(*ping)).await?;
+        }
+        received.lock().unwrap().push(message);
+    }
+    Ok(())
+}
+
+fn encode(network: Network, message: BitcoinMessage) -> io::Result<BytesMut> {
+    let mut buffer = BytesMut::new();
+    BitcoinCodec::new(network).encode(message, &mut buffer)?;
+    Ok(buffer)
+}
+
+// A configuration builder for the regtest network, to tweak before building
+pub fn regtest_builder() -> BitcoinConfigurationBuilder {
+    BitcoinConfiguration::builder().network(Network::Regtest)
+}
+
+// The default configuration on the regtest network
+pub fn regtest_config() -> BitcoinConfiguration {
+    regtest_builder().build()
+}
+
+/// Discover a fixed list of regtest addresses, announced as full nodes.
+#[derive(Debug, Clone)]
+pub struct StaticDiscovery(pub Vec<SocketAddr>);
+
+impl PeerDiscovery for StaticDiscovery {
+    type Info = BitcoinConnectionInfo;
+    async fn discover_peers(&self) -> Pin<Box<dyn Stream<Item = Self::Info> + Send + Sync>> {
+        let peers: Vec<_> = self
+            .0
+            .iter()
+            .map(|address| {
+                BitcoinConnectionInfo::new(*address, Network::Regtest)
+                    .with_services(ServiceFlags::NETWORK)
+            })
+            .collect();
+        Box::pin(stream::iter(peers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_connection_info::CompactBlocksFeature,
+        messages::{PingMessage, ServiceFlags},
+        BitcoinConnectionProtocol, BitcoinHandshakeError, Established, HandshakeState,
+    };
+
+    async fn handshake(node: FakeNode) -> Result<Established, BitcoinHandshakeError> {
+        let node = node.listen().await?;
+        let config = regtest_builder()
+            .message_timeout(Duration::from_millis(500))
+            .build();
+        BitcoinConnectionProtocol::new(node.connection_info(), Arc::new(config))
+            .connect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn establish_with_normal_node() -> Result<(), Box<dyn std::error::Error>> {
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let established =
+            BitcoinConnectionProtocol::new(node.connection_info(), Arc::new(regtest_config()))
+                .connect()
+                .await?;
+
+        // the pong proves the node read everything sent before the ping
+        let (mut reader, mut writer) = established.split();
+        writer
+            .send(BitcoinMessage::Ping(PingMessage { nonce: 7 }))
+            .await?;
+        assert_eq!(
+            reader.next().await.transpose()?,
+            Some(BitcoinMessage::Pong(PingMessage { nonce: 7 }))
+        );
+        let received = node.received();
+        assert!(matches!(received[0], BitcoinMessage::Version(_)));
+        assert_eq!(
+            received[1..],
+            [
+                // both sides announce the protocol version of addrv2
+                BitcoinMessage::SendAddrV2,
+                BitcoinMessage::VerAck,
+                BitcoinMessage::Ping(PingMessage { nonce: 7 })
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_v1() -> Result<(), Box<dyn std::error::Error>> {
+        // the fake node speaks v1 only and drops the v2 public key as a bad header
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let config = regtest_builder().v2_transport(true).build();
+        BitcoinConnectionProtocol::new(node.connection_info(), Arc::new(config))
+            .connect()
+            .await?;
+
+        let received = node.received();
+        let BitcoinMessage::Version(version) = &received[0] else {
+            panic!("expected a version, received {:?}", received[0]);
+        };
+        assert!(version.services().has(ServiceFlags::P2P_V2));
+        Ok(())
+    }
+
+    // A SOCKS5 proxy without authentication that takes `delay` to open every connection to `upstream`
+    async fn slow_proxy(upstream: SocketAddr, delay: Duration) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // the greeting offering no authentication, then a request for an ipv4 target
+                    let mut greeting = [0u8; 3];
+                    client.read_exact(&mut greeting).await?;
+                    client.write_all(&[5, 0]).await?;
+                    let mut request = [0u8; 10];
+                    client.read_exact(&mut request).await?;
+                    tokio::time::sleep(delay).await;
+                    let mut upstream = TcpStream::connect(upstream).await?;
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    Ok::<_, io::Error>(())
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_v1_over_slow_dial() -> Result<(), Box<dyn std::error::Error>> {
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let proxy = slow_proxy(node.local_addr(), Duration::from_millis(300)).await?;
+        // both dials fit the connect timeout, not their sum
+        let config = regtest_builder()
+            .v2_transport(true)
+            .proxy(proxy)
+            .proxy_randomize_credentials(false)
+            .connect_timeout(Duration::from_millis(500))
+            .build();
+        BitcoinConnectionProtocol::new(node.connection_info(), Arc::new(config))
+            .connect()
+            .await?;
+
+        assert!(matches!(node.received()[0], BitcoinMessage::Version(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_send_compact_before_verack() {
+        let established = handshake(FakeNode::new(
+            Network::Regtest,
+            FakeNodeBehaviour::SendCompactBeforeVerAck,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            established.connection_info().features.compact_blocks,
+            Some(CompactBlocksFeature {
+                high_bandwidth: false,
+                version: 2
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_node_times_out() {
+        let result = handshake(FakeNode::new(
+            Network::Regtest,
+            FakeNodeBehaviour::Slow(Duration::from_secs(5)),
+        ))
+        .await;
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::Timeout(HandshakeState::AwaitVersion))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_bad_checksum() {
+        let result = handshake(FakeNode::new(
+            Network::Regtest,
+            FakeNodeBehaviour::BadChecksum,
+        ))
+        .await;
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::InvalidResponse(reason)) if reason.contains("checksum")
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_wrong_magic() {
+        let result = handshake(FakeNode::new(
+            Network::Regtest,
+            FakeNodeBehaviour::WrongMagic,
+        ))
+        .await;
+        // the header is rejected before the payload is read
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::InvalidResponse(reason)) if reason.contains("header")
+        ));
+    }
+
+    #[tokio::test]
+    async fn fail_when_disconnected_mid_handshake() {
+        let result = handshake(FakeNode::new(
+            Network::Regtest,
+            FakeNodeBehaviour::DisconnectMidHandshake,
+        ))
+        .await;
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::InvalidResponse(reason)) if reason.contains("eof")
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_obsolete_version() {
+        let result = handshake(
+            FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+                .with_version(VersionMessage::new("/old:0.1/", 0).with_version(60000)),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::ObsoleteVersion { version: 60000, .. })
+        ));
+    }
+}
@@ -1,10 +1,11 @@
 use bytes::BytesMut;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio_util::codec::Decoder;
 use tracing::{debug, error};
 
 use super::{
-    connection_protocol::AdvanceStateResult, send_version::SendVersion,
+    connection_protocol::AdvanceStateResult, send_version::SendVersion, transport::Transport,
     CHANNEL_NOT_INITIALIZED_ERROR,
 };
 
@@ -12,92 +13,93 @@ use crate::{
     bitcoin::{
         bitcoin_connection_info::BitcoinConnectionInfo,
         handshake::connection_protocol::BitcoinHandshakeError,
-        messages::{
-            commands::Command, sha2_checksum, HeaderCodec, HeaderMessage, VersionCodec,
-            VersionMessage,
-        },
+        messages::{sha2_checksum, BitcoinMessage, DecodeError, HeaderCodec, HeaderMessage},
+        network::Network,
+        BitcoinConfiguration,
     },
     HEADER_LENGTH,
 };
 
 #[derive(Debug)]
-pub(super) struct AwaitVersion {
-    pub(super) channel: Option<TcpStream>,
+pub(super) struct AwaitVersion<S> {
+    pub(super) channel: Option<S>,
     pub(super) connection_info: BitcoinConnectionInfo,
+    pub(super) config: Arc<BitcoinConfiguration>,
 }
 
-impl AwaitVersion {
+impl<S: Transport> AwaitVersion<S> {
     // Initialize the state with the stream and the operation to await the version
-    fn new(channel: TcpStream, connection_info: BitcoinConnectionInfo) -> Self {
+    pub(super) fn new(
+        channel: S,
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+    ) -> Self {
         AwaitVersion {
             channel: Some(channel),
             connection_info,
+            config,
         }
     }
 
     pub(crate) async fn execute(&mut self) -> AdvanceStateResult {
         if let Some(mut channel) = self.channel.take() {
-            let header = match read_header(&mut channel).await {
-                Ok(header) => header,
-                Err(e) => {
-                    // return the channel back
-                    self.channel = Some(channel);
-                    return Err(e);
-                }
-            };
-
-            // header verification
-            if header.command != Command::Version {
-                let error_str = format!(
-                    "Received invalid header command {:?}, expected version command",
-                    header.command
-                );
-                error!(error_str);
-                return Err(BitcoinHandshakeError::InvalidResponse(error_str));
-            }
-
-            // read the version
-            let version_result = read_version(&mut channel, header).await;
+            let message_result = read_message(&mut channel, self.connection_info.network).await;
             // return the channel back on both cases Err and Ok
             self.channel = Some(channel);
 
             // If Ok - assign the version
-            version_result.and_then(|version| {
-                debug!("version accepted. details: {:?}", version);
-                self.connection_info.version = Some(version);
-                Ok(())
-            })
+            match message_result? {
+                BitcoinMessage::Version(version) => {
+                    debug!("version accepted. details: {:?}", version);
+                    self.connection_info.clock_offset = Some(
+                        version
+                            .timestamp()
+                            .saturating_sub(chrono::Utc::now().timestamp()),
+                    );
+                    self.connection_info.version = Some(version);
+                    Ok(())
+                }
+                message => {
+                    let error_str = format!(
+                        "Received invalid header command {:?}, expected version command",
+                        message.command()
+                    );
+                    error!(error_str);
+                    Err(BitcoinHandshakeError::InvalidResponse(error_str))
+                }
+            }
         } else {
             panic!("{}", CHANNEL_NOT_INITIALIZED_ERROR);
         }
     }
 }
 
-async fn read_header(channel: &mut TcpStream) -> Result<HeaderMessage, BitcoinHandshakeError> {
-    let mut codec = HeaderCodec {};
+async fn read_header(
+    channel: &mut (impl AsyncRead + Unpin),
+    network: Network,
+) -> Result<HeaderMessage, BitcoinHandshakeError> {
+    let mut codec = HeaderCodec::new(network);
     let mut buffer = BytesMut::with_capacity(HEADER_LENGTH);
     buffer.resize(HEADER_LENGTH, 0);
     channel.read_exact(&mut buffer).await?;
     let header = codec
         .decode(&mut buffer)
-        .map_err(|e| {
-            BitcoinHandshakeError::ProtocolError(format!("Failed receive header. error: {:?}", e))
-        })
+        .map_err(|e| decode_error("header", e))
         .and_then(|opt| {
             opt.ok_or(BitcoinHandshakeError::ProtocolError(
                 "Cannot receive header".to_owned(),
             ))
         })?;
 
-    return Ok(header);
+    Ok(header)
 }
 
-async fn read_version(
-    channel: &mut TcpStream,
-    header: HeaderMessage,
-) -> Result<VersionMessage, BitcoinHandshakeError> {
+// Read the payload described by the header and verify its checksum
+async fn read_payload(
+    channel: &mut (impl AsyncRead + Unpin),
+    header: &HeaderMessage,
+) -> Result<BytesMut, BitcoinHandshakeError> {
     let buffer_size: usize = header.payload_length.try_into().unwrap();
-    let mut codec = VersionCodec {};
     let mut buffer = BytesMut::with_capacity(buffer_size);
     buffer.resize(buffer_size, 0);
     channel.read_exact(&mut buffer).await?;
@@ -109,24 +111,37 @@ async fn read_version(
                                             format!("Invalid checksum. The received header checksum: {:?} not equal to the computed actual payload checksum: {:?}",header.checksum,computed_checksum),
                                         ));
     }
+    Ok(buffer)
+}
 
-    codec
-        .decode(&mut buffer)
-        .map_err(|e| {
-            BitcoinHandshakeError::ProtocolError(format!("Failed receive version. error: {:?}", e))
-        })
-        .and_then(|opt| {
-            opt.ok_or(BitcoinHandshakeError::ProtocolError(
-                "Cannot receive version".to_owned(),
-            ))
-        })
+// Read exactly one message from the channel, nothing beyond it is consumed
+pub(super) async fn read_message(
+    channel: &mut (impl AsyncRead + Unpin),
+    network: Network,
+) -> Result<BitcoinMessage, BitcoinHandshakeError> {
+    let header = read_header(channel, network).await?;
+    let payload = read_payload(channel, &header).await?;
+    BitcoinMessage::decode_payload(header.command, payload)
+        .map_err(|e| decode_error(&header.command.to_string(), e))
+}
+
+// A message breaking a decoding limit keeps its dedicated error, any other failure is a protocol error
+fn decode_error(what: &str, error: std::io::Error) -> BitcoinHandshakeError {
+    match DecodeError::from_io(&error) {
+        Some(e) => BitcoinHandshakeError::InvalidMessage(e.clone()),
+        None => BitcoinHandshakeError::ProtocolError(format!(
+            "Failed receive {}. error: {:?}",
+            what, error
+        )),
+    }
 }
 
-impl From<SendVersion> for AwaitVersion {
-    fn from(value: SendVersion) -> Self {
+impl<S: Transport> From<SendVersion<S>> for AwaitVersion<S> {
+    fn from(value: SendVersion<S>) -> Self {
         AwaitVersion::new(
             value.channel.expect(CHANNEL_NOT_INITIALIZED_ERROR),
             value.connection_info,
+            value.config,
         )
     }
 }
@@ -1,28 +1,27 @@
-use futures::StreamExt;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
-use tracing::{debug, error, warn};
+use tracing::{debug, error};
 
 use super::{
-    connection_protocol::AdvanceStateResult, send_version_ack::SendVerAck,
-    CHANNEL_NOT_INITIALIZED_ERROR,
+    await_version::read_message, connection_protocol::AdvanceStateResult,
+    send_version_ack::SendVerAck, transport::Transport, CHANNEL_NOT_INITIALIZED_ERROR,
 };
 use crate::bitcoin::{
     bitcoin_connection_info::BitcoinConnectionInfo,
-    handshake::connection_protocol::BitcoinHandshakeError,
-    messages::{commands::Command, HeaderCodec},
+    handshake::connection_protocol::BitcoinHandshakeError, messages::BitcoinMessage,
 };
 
-const MAX_READ_HEADER_ATTEMPTS: i8 = 3;
+// The feature negotiation messages a peer may send between version and verack.
+// Each one is expected at most once (sendcmpct may announce several versions), so this bounds a misbehaving peer.
+const MAX_FEATURE_MESSAGES: usize = 8;
+
 #[derive(Debug)]
-pub(super) struct AwaitVerAck {
-    pub(super) channel: Option<TcpStream>,
+pub(super) struct AwaitVerAck<S> {
+    pub(super) channel: Option<S>,
     pub(super) connection_info: BitcoinConnectionInfo,
 }
 
-impl AwaitVerAck {
+impl<S: Transport> AwaitVerAck<S> {
     // Initialize the state with the stream and the operation to await the version ack
-    fn new(stream: TcpStream, connection_info: BitcoinConnectionInfo) -> Self {
+    fn new(stream: S, connection_info: BitcoinConnectionInfo) -> Self {
         AwaitVerAck {
             channel: Some(stream),
             connection_info,
@@ -30,53 +29,133 @@ impl AwaitVerAck {
     }
 
     pub(super) async fn execute(&mut self) -> AdvanceStateResult {
-        if let Some(channel) = self.channel.take() {
-            let mut framed = Framed::new(channel, HeaderCodec);
-            for _ in 1..=MAX_READ_HEADER_ATTEMPTS {
-                let result = framed.next().await;
-                match result {
-                    Some(Ok(header_message)) => {
-                        // return the channel back
-                        self.channel = Some(framed.into_inner());
-                        debug!(
-                            "Receive verack successfully from {:?}",
-                            self.connection_info
-                        );
-                        if header_message.command != Command::VerAck {
-                            return Err(BitcoinHandshakeError::ProtocolError(format!("Invalid header. header must be of verack command. the current header: {:?}",header_message)));
-                        }
-                        return Ok(());
-                    }
-                    Some(Err(e)) => {
-                        // return the channel back
-                        self.channel = Some(framed.into_inner());
-                        error!(
-                            "failed to receive verack message from {:?}, reason: {:?}",
-                            self.connection_info, e
-                        );
-                        return Err(BitcoinHandshakeError::ProtocolError(e.to_string()));
-                    }
-                    None => {
-                        warn!(
-                            "did not receive verack from {:?}, continue reading",
-                            self.connection_info
-                        );
-                        continue;
-                    }
-                }
+        if let Some(mut channel) = self.channel.take() {
+            let result = self.receive_verack(&mut channel).await;
+            // return the channel back on both cases Err and Ok
+            self.channel = Some(channel);
+            if let Err(e) = &result {
+                error!(
+                    "failed to receive verack message from {:?}, reason: {:?}",
+                    self.connection_info, e
+                );
             }
-            return Err(BitcoinHandshakeError::ProtocolError(format!("Failed to read the verack header and stop after pass the max retries of: {} times", MAX_READ_HEADER_ATTEMPTS)));
+            result
         } else {
             panic!("{}", CHANNEL_NOT_INITIALIZED_ERROR);
         }
     }
+
+    // Read messages until the verack arrives, recording the features the peer negotiates on the way
+    async fn receive_verack(&mut self, channel: &mut S) -> AdvanceStateResult {
+        for _ in 0..=MAX_FEATURE_MESSAGES {
+            let message = read_message(channel, self.connection_info.network).await?;
+            let command = message.command();
+            let features = &mut self.connection_info.features;
+            match message {
+                BitcoinMessage::VerAck => {
+                    debug!(
+                        "Receive verack successfully from {:?}",
+                        self.connection_info
+                    );
+                    return Ok(());
+                }
+                BitcoinMessage::SendCmpct(message) => features.record_send_compact(message),
+                BitcoinMessage::FeeFilter(message) => features.record_fee_filter(message),
+                BitcoinMessage::WtxidRelay => features.wtxid_relay = true,
+                BitcoinMessage::SendAddrV2 => features.addr_v2 = true,
+                _ => {
+                    return Err(BitcoinHandshakeError::ProtocolError(format!(
+                        "Invalid message. message must be verack or a feature negotiation command. the current command: {}",
+                        command
+                    )))
+                }
+            }
+            debug!(
+                "Receive {} before verack from {:?}",
+                command, self.connection_info.public_address
+            );
+        }
+        Err(BitcoinHandshakeError::ProtocolError(format!(
+            "Failed to receive verack, the peer sent more than {} feature negotiation messages",
+            MAX_FEATURE_MESSAGES
+        )))
+    }
 }
 
-impl From<SendVerAck> for AwaitVerAck {
-    fn from(value: SendVerAck) -> Self {
+impl<S: Transport> From<SendVerAck<S>> for AwaitVerAck<S> {
+    fn from(value: SendVerAck<S>) -> Self {
         AwaitVerAck::new(
             value.channel.expect(CHANNEL_NOT_INITIALIZED_ERROR),
             value.connection_info,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::codec::Encoder;
+
+    use super::*;
+    use crate::bitcoin::{
+        messages::{BitcoinCodec, FeeFilterMessage, SendCompactMessage},
+        network::Network,
+    };
+
+    #[tokio::test]
+    async fn record_feature_messages_before_verack() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut remote = TcpStream::connect(listener.local_addr()?).await?;
+        let (channel, remote_address) = listener.accept().await?;
+
+        let mut buffer = BytesMut::new();
+        for message in [
+            BitcoinMessage::WtxidRelay,
+            BitcoinMessage::SendAddrV2,
+            BitcoinMessage::SendCmpct(SendCompactMessage {
+                announce: false,
+                version: 2,
+            }),
+            BitcoinMessage::FeeFilter(FeeFilterMessage { fee_rate: 1000 }),
+            BitcoinMessage::VerAck,
+        ] {
+            BitcoinCodec::new(Network::Mainnet).encode(message, &mut buffer)?;
+        }
+        remote.write_all(&buffer).await?;
+
+        let mut await_verack = AwaitVerAck::new(
+            channel,
+            BitcoinConnectionInfo::new(remote_address, Network::Mainnet),
+        );
+        await_verack.execute().await?;
+
+        let features = await_verack.connection_info.features;
+        assert!(features.wtxid_relay);
+        assert!(features.addr_v2);
+        assert_eq!(features.compact_blocks.map(|c| c.version), Some(2));
+        assert_eq!(features.fee_filter, Some(1000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_unexpected_message_before_verack() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut remote = TcpStream::connect(listener.local_addr()?).await?;
+        let (channel, remote_address) = listener.accept().await?;
+
+        let mut buffer = BytesMut::new();
+        BitcoinCodec::new(Network::Mainnet).encode(BitcoinMessage::GetAddr, &mut buffer)?;
+        remote.write_all(&buffer).await?;
+
+        let mut await_verack = AwaitVerAck::new(
+            channel,
+            BitcoinConnectionInfo::new(remote_address, Network::Mainnet),
+        );
+        assert!(await_verack.execute().await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+};
+
+/// Supply the height of our best chain, announced as the start height of our version message.
+pub trait ChainHeight: Debug + Send + Sync {
+    fn height(&self) -> i32;
+}
+
+// A height that never changes, 0 for a client that does not keep the chain
+impl ChainHeight for i32 {
+    fn height(&self) -> i32 {
+        *self
+    }
+}
+
+// A height kept up to date by the synchronization of the chain while peers are connected
+impl ChainHeight for AtomicI32 {
+    fn height(&self) -> i32 {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: ChainHeight + ?Sized> ChainHeight for Arc<T> {
+    fn height(&self) -> i32 {
+        (**self).height()
+    }
+}
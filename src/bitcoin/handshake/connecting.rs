@@ -1,40 +1,59 @@
-use tokio::net::TcpStream;
+use std::{fmt, sync::Arc};
 
 use super::{
-    connection_protocol::{AdvanceStateResult, BitcoinHandshakeError},
-    disconnected::Disconnected,
+    connection_protocol::{AdvanceStateResult, BitcoinHandshakeError, HandshakeState},
+    disconnected::{Disconnected, OpenTransport},
+    transport::Transport,
 };
-use crate::bitcoin::bitcoin_connection_info::BitcoinConnectionInfo;
+use crate::bitcoin::{bitcoin_connection_info::BitcoinConnectionInfo, BitcoinConfiguration};
 
-#[derive(Debug)]
-pub(super) struct Connecting {
-    pub(super) channel: Option<TcpStream>,
+pub(super) struct Connecting<S> {
+    pub(super) channel: Option<S>,
     pub(super) connection_info: BitcoinConnectionInfo,
+    pub(super) config: Arc<BitcoinConfiguration>,
+    open: Option<OpenTransport<S>>,
 }
 
-impl Connecting {
-    async fn connect(
-        connection_info: BitcoinConnectionInfo,
-    ) -> Result<TcpStream, BitcoinHandshakeError> {
-        match TcpStream::connect(connection_info.public_address).await {
-            Ok(stream) => Ok(stream),
+impl<S: Transport> Connecting<S> {
+    pub(super) async fn execute(&mut self) -> AdvanceStateResult {
+        let open = self
+            .open
+            .take()
+            .expect("the transport is opened once, by the first execution");
+        match open.await {
+            Ok(stream) => {
+                self.channel = Some(stream);
+                Ok(())
+            }
             Err(e) => match e.kind() {
-                tokio::io::ErrorKind::TimedOut => Err(BitcoinHandshakeError::Timeout),
-                _ => Err(BitcoinHandshakeError::ConnectionFailed(e.to_string())),
+                tokio::io::ErrorKind::TimedOut => {
+                    Err(BitcoinHandshakeError::Timeout(HandshakeState::Connecting))
+                }
+                _ => Err(BitcoinHandshakeError::ConnectionFailed(format!(
+                    "Failed connecting to {}, reason: {}",
+                    self.connection_info.public_address, e
+                ))),
             },
         }
     }
+}
 
-    pub(super) async fn execute(&mut self) -> AdvanceStateResult {
-        self.channel = Some(Self::connect(self.connection_info.clone()).await?);
-        Ok(())
+impl<S: fmt::Debug> fmt::Debug for Connecting<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connecting")
+            .field("channel", &self.channel)
+            .field("connection_info", &self.connection_info)
+            .finish_non_exhaustive()
     }
 }
-impl From<Disconnected> for Connecting {
-    fn from(value: Disconnected) -> Self {
+
+impl<S> From<Disconnected<S>> for Connecting<S> {
+    fn from(value: Disconnected<S>) -> Self {
         Connecting {
             channel: None,
-            connection_info: value.connection_info.clone(),
+            connection_info: value.connection_info,
+            config: value.config,
+            open: Some(value.open),
         }
     }
 }
@@ -1,25 +1,37 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use futures::FutureExt;
+use strum::Display;
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, time::Instant};
+use tracing::debug;
 
-use crate::bitcoin::bitcoin_connection_info::BitcoinConnectionInfo;
+use crate::bitcoin::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    messages::{DecodeError, ServiceFlags, VersionMessage},
+    socks5::Socks5Proxy,
+    v2_transport::{connect_v2, PeerStream},
+    BitcoinConfiguration,
+};
 
 use super::{
     await_version::AwaitVersion, await_version_ack::AwaitVerAck, connecting::Connecting,
-    disconnected::Disconnected, established::Established, send_version::SendVersion,
-    send_version_ack::SendVerAck,
+    disconnected::Disconnected, established::Established, report::HandshakeReport,
+    send_version::SendVersion, send_version_ack::SendVerAck, transport::Transport,
+    version_policy::LocalNonce, CHANNEL_NOT_INITIALIZED_ERROR,
 };
 
-#[allow(private_interfaces)]
+#[allow(private_interfaces, clippy::large_enum_variant)]
 #[derive(Debug)]
-pub enum BitcoinConnectionStates {
-    Disconnected(Disconnected),
-    Connecting(Connecting),
-    SendVersion(SendVersion),
-    AwaitVersion(AwaitVersion),
-    SendVerAck(SendVerAck),
-    AwaitVerAck(AwaitVerAck),
-    // when the handshake established successfully, let the consumer take and use the inner opened tcp stream channel
-    Established(Established),
+pub enum BitcoinConnectionStates<S> {
+    Disconnected(Disconnected<S>),
+    Connecting(Connecting<S>),
+    SendVersion(SendVersion<S>),
+    AwaitVersion(AwaitVersion<S>),
+    SendVerAck(SendVerAck<S>),
+    AwaitVerAck(AwaitVerAck<S>),
+    // when the handshake established successfully, let the consumer take and use the established connection
+    Established(Established<S>),
     Failed(BitcoinHandshakeError),
 }
 
@@ -31,59 +43,290 @@ pub enum BitcoinHandshakeError {
     #[error("Invalid response received: {0}")]
     InvalidResponse(String),
 
-    #[error("Connection timed out")]
-    Timeout,
+    #[error("Handshake timed out in state {0}")]
+    Timeout(HandshakeState),
 
     #[error("Protocol error: {0}")]
     ProtocolError(String),
+
+    #[error("Peer protocol version {version} is below the minimum {minimum}")]
+    ObsoleteVersion { version: u32, minimum: u32 },
+
+    #[error("Peer offers services {offered}, required {required}")]
+    MissingServices {
+        offered: ServiceFlags,
+        required: ServiceFlags,
+    },
+
+    #[error("Peer clock is {offset} seconds away from ours")]
+    InvalidTimestamp { offset: i64 },
+
+    #[error("Connected to ourselves")]
+    SelfConnection,
+
+    #[error("Invalid message: {0}")]
+    InvalidMessage(#[from] DecodeError),
 }
 
 impl From<std::io::Error> for BitcoinHandshakeError {
     fn from(value: std::io::Error) -> Self {
-        match value.kind() {
-            tokio::io::ErrorKind::TimedOut => BitcoinHandshakeError::Timeout,
-            tokio::io::ErrorKind::ConnectionRefused => {
-                BitcoinHandshakeError::ConnectionFailed(value.to_string())
-            }
-            _ => BitcoinHandshakeError::ConnectionFailed(value.to_string()),
-        }
+        BitcoinHandshakeError::ConnectionFailed(value.to_string())
     }
 }
 
+// The handshake state that was in progress, reported by timeouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum HandshakeState {
+    Connecting,
+    SendVersion,
+    AwaitVersion,
+    SendVerAck,
+    AwaitVerAck,
+}
+
 pub(super) type AdvanceStateResult = Result<(), BitcoinHandshakeError>;
 
-// implement the connection protocol of bitcoin client (the handshake)
+// The side of the handshake the local peer plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    // We dialed the remote peer: Disconnected -> Connecting -> SendVersion -> AwaitVersion -> SendVerAck -> AwaitVerAck
+    Outbound,
+    // The remote peer dialed us: AwaitVersion -> SendVersion -> SendVerAck -> AwaitVerAck
+    Inbound,
+}
+
+// implement the connection protocol of bitcoin client (the handshake), over a tcp connection, plaintext or encrypted, unless another transport is given
 #[derive(Debug)]
-pub struct BitcoinConnectionProtocol {
-    state: BitcoinConnectionStates,
+pub struct BitcoinConnectionProtocol<S = PeerStream> {
+    state: BitcoinConnectionStates<S>,
     connection_info: BitcoinConnectionInfo,
+    // The parameters of the local peer, shared by all the states
+    config: Arc<BitcoinConfiguration>,
+    direction: ConnectionDirection,
+    // The bound of the connecting state, longer when opening may dial twice
+    connect_timeout: Duration,
+    // The whole handshake must complete before this instant, set on the first advance
+    deadline: Option<Instant>,
+    // The nonce of our version message, registered for the whole handshake to detect self connections
+    local_nonce: LocalNonce,
+    // When the current state started, and how long the completed states took
+    state_started: Instant,
+    latencies: Vec<(HandshakeState, Duration)>,
 }
 
-impl BitcoinConnectionProtocol {
-    pub fn new(connection_info: BitcoinConnectionInfo) -> Self {
+impl BitcoinConnectionProtocol<PeerStream> {
+    // Create the initiator side of the handshake, the tcp connection is opened by the protocol
+    // through the configured proxy, if any, and encrypted when the v2 transport is enabled
+    pub fn new(connection_info: BitcoinConnectionInfo, config: Arc<BitcoinConfiguration>) -> Self {
+        let open = open_peer_stream(connection_info.clone(), config.clone());
+        let mut protocol =
+            BitcoinConnectionProtocol::new_with_transport(connection_info, config, open);
+        if protocol.config.v2_transport {
+            // each dial and the v2 attempt are bounded on their own, see `open_peer_stream`
+            protocol.connect_timeout =
+                protocol.config.connect_timeout * 2 + v2_attempt_timeout(&protocol.config);
+        }
+        protocol
+    }
+}
+
+// Try the v2 transport first when enabled. A peer that does not speak it closes the connection or
+// stays silent, the handshake then continues in plaintext over a new connection.
+// Every dial has the whole connect timeout, a slow proxy is not paid twice out of the same budget.
+async fn open_peer_stream(
+    connection_info: BitcoinConnectionInfo,
+    config: Arc<BitcoinConfiguration>,
+) -> std::io::Result<PeerStream> {
+    let proxy = config.socks5_proxy();
+    let channel = dial_within(
+        config.connect_timeout,
+        connection_info.clone(),
+        proxy.clone(),
+    )
+    .await?;
+    if !config.v2_transport {
+        return Ok(PeerStream::V1(channel));
+    }
+    let v2 = tokio::time::timeout(
+        v2_attempt_timeout(&config),
+        connect_v2(channel, connection_info.network),
+    )
+    .await;
+    match v2 {
+        Ok(Ok(stream)) => return Ok(PeerStream::V2(Box::new(stream))),
+        Ok(Err(e)) => debug!(
+            "v2 transport refused by {}, falling back to v1, reason: {}",
+            connection_info.public_address, e
+        ),
+        Err(_) => debug!(
+            "v2 transport not answered by {}, falling back to v1",
+            connection_info.public_address
+        ),
+    }
+    let channel = dial_within(config.connect_timeout, connection_info, proxy).await?;
+    Ok(PeerStream::V1(channel))
+}
+
+// How long a peer may take to answer our v2 public key before we redial it in v1
+fn v2_attempt_timeout(config: &BitcoinConfiguration) -> Duration {
+    config.connect_timeout / 2
+}
+
+// A dial that is not done in time fails as timed out, reported as a timeout of the connecting state
+async fn dial_within(
+    timeout: Duration,
+    connection_info: BitcoinConnectionInfo,
+    proxy: Option<Socks5Proxy>,
+) -> std::io::Result<TcpStream> {
+    tokio::time::timeout(timeout, dial(connection_info, proxy))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+async fn dial(
+    connection_info: BitcoinConnectionInfo,
+    proxy: Option<Socks5Proxy>,
+) -> std::io::Result<TcpStream> {
+    let address = connection_info.public_address;
+    match (proxy, connection_info.host) {
+        (Some(proxy), Some(host)) => proxy.connect(&host, address.port()).await,
+        (Some(proxy), None) => {
+            proxy
+                .connect(&address.ip().to_string(), address.port())
+                .await
+        }
+        (None, Some(host)) if host.ends_with(".onion") => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("{} is reachable through a proxy only", host),
+        )),
+        (None, Some(host)) => TcpStream::connect((host, address.port())).await,
+        (None, None) => TcpStream::connect(address).await,
+    }
+}
+
+impl<S: Transport> BitcoinConnectionProtocol<S> {
+    // Create the initiator side of the handshake over the transport opened by `open`.
+    // Opening is bounded by the connect timeout, like the tcp connection of `new`.
+    pub fn new_with_transport(
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+        open: impl Future<Output = std::io::Result<S>> + Send + 'static,
+    ) -> Self {
         BitcoinConnectionProtocol {
             connection_info: connection_info.clone(),
-            state: BitcoinConnectionStates::Disconnected(Disconnected { connection_info }),
+            state: BitcoinConnectionStates::Disconnected(Disconnected {
+                connection_info,
+                config: config.clone(),
+                open: open.boxed(),
+            }),
+            local_nonce: config.local_nonces.register(),
+            connect_timeout: config.connect_timeout,
+            config,
+            direction: ConnectionDirection::Outbound,
+            deadline: None,
+            state_started: Instant::now(),
+            latencies: Vec::new(),
         }
     }
 
+    // Create the responder side of the handshake over an already accepted connection
+    pub fn new_inbound(
+        channel: S,
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+    ) -> Self {
+        BitcoinConnectionProtocol {
+            connection_info: connection_info.clone(),
+            state: BitcoinConnectionStates::AwaitVersion(AwaitVersion::new(
+                channel,
+                connection_info,
+                config.clone(),
+            )),
+            local_nonce: config.local_nonces.register(),
+            connect_timeout: config.connect_timeout,
+            config,
+            direction: ConnectionDirection::Inbound,
+            deadline: None,
+            state_started: Instant::now(),
+            latencies: Vec::new(),
+        }
+    }
+
+    pub fn direction(&self) -> ConnectionDirection {
+        self.direction
+    }
+
     #[tracing::instrument(level = "debug")]
-    pub(crate) async fn advance(&mut self) -> Result<Option<TcpStream>, BitcoinHandshakeError> {
+    pub(crate) async fn advance(
+        &mut self,
+    ) -> Result<Option<Established<S>>, BitcoinHandshakeError> {
+        // a handler that does not set the next state leaves the protocol failed
         let state = std::mem::replace(
             &mut self.state,
-            BitcoinConnectionStates::Disconnected(Disconnected {
-                connection_info: self.connection_info.clone(),
-            }),
+            BitcoinConnectionStates::Failed(BitcoinHandshakeError::ProtocolError(
+                "The handshake was interrupted".to_owned(),
+            )),
         );
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + self.config.handshake_timeout);
+        self.state_started = Instant::now();
+        let connect_timeout = self.connect_timeout;
+        let message_timeout = self.config.message_timeout;
         let result = match state {
             BitcoinConnectionStates::Disconnected(d) => self.handle_disconnect_state(d),
-            BitcoinConnectionStates::Connecting(c) => self.handle_connecting_state(c).await,
-            BitcoinConnectionStates::SendVersion(s) => self.handle_send_version(s).await,
-            BitcoinConnectionStates::AwaitVersion(a) => self.handle_await_version(a).await,
-            BitcoinConnectionStates::SendVerAck(s) => self.handle_send_version_ack(s).await,
-            BitcoinConnectionStates::AwaitVerAck(a) => self.handle_await_version_ack(a).await,
+            BitcoinConnectionStates::Connecting(c) => {
+                let handle = self.handle_connecting_state(c);
+                within(
+                    HandshakeState::Connecting,
+                    connect_timeout,
+                    deadline,
+                    handle,
+                )
+                .await
+            }
+            BitcoinConnectionStates::SendVersion(s) => {
+                let handle = self.handle_send_version(s);
+                within(
+                    HandshakeState::SendVersion,
+                    message_timeout,
+                    deadline,
+                    handle,
+                )
+                .await
+            }
+            BitcoinConnectionStates::AwaitVersion(a) => {
+                let handle = self.handle_await_version(a);
+                within(
+                    HandshakeState::AwaitVersion,
+                    message_timeout,
+                    deadline,
+                    handle,
+                )
+                .await
+            }
+            BitcoinConnectionStates::SendVerAck(s) => {
+                let handle = self.handle_send_version_ack(s);
+                within(
+                    HandshakeState::SendVerAck,
+                    message_timeout,
+                    deadline,
+                    handle,
+                )
+                .await
+            }
+            BitcoinConnectionStates::AwaitVerAck(a) => {
+                let handle = self.handle_await_version_ack(a);
+                within(
+                    HandshakeState::AwaitVerAck,
+                    message_timeout,
+                    deadline,
+                    handle,
+                )
+                .await
+            }
             // Connection is established, nothing more to do
-            BitcoinConnectionStates::Established(e) => return Ok(Some(e.channel)),
+            BitcoinConnectionStates::Established(e) => return Ok(Some(e)),
             BitcoinConnectionStates::Failed(e) => return Err(e),
         };
         if let Err(e) = result {
@@ -94,103 +337,509 @@ impl BitcoinConnectionProtocol {
     }
 
     // Handle the "Disconnected" state by moving to the connecting state
-    fn handle_disconnect_state(&mut self, disconnected: Disconnected) -> AdvanceStateResult {
+    fn handle_disconnect_state(&mut self, disconnected: Disconnected<S>) -> AdvanceStateResult {
         self.state = BitcoinConnectionStates::Connecting(disconnected.into());
         Ok(())
     }
 
     // Handle the "Connecting" state by waiting to establish a TCP connection. and advance to the next state
-    async fn handle_connecting_state(&mut self, mut connecting: Connecting) -> AdvanceStateResult {
-        match connecting.execute().await {
-            Ok(_) => {
-                self.state = BitcoinConnectionStates::SendVersion(connecting.into());
-                Ok(())
-            }
-            Err(e) => Err(BitcoinHandshakeError::ConnectionFailed(format!(
-                "Failed connecting to {}, reason: {}",
-                self.connection_info.public_address.to_string(),
-                e
-            ))),
-        }
+    async fn handle_connecting_state(
+        &mut self,
+        mut connecting: Connecting<S>,
+    ) -> AdvanceStateResult {
+        connecting.execute().await?;
+        self.complete_state(HandshakeState::Connecting);
+        self.state = BitcoinConnectionStates::SendVersion(connecting.into());
+        Ok(())
     }
 
-    async fn handle_send_version(&mut self, mut send_version: SendVersion) -> AdvanceStateResult {
-        match send_version.execute().await {
+    async fn handle_send_version(
+        &mut self,
+        mut send_version: SendVersion<S>,
+    ) -> AdvanceStateResult {
+        match send_version.execute(self.local_nonce.value()).await {
             Ok(_) => {
-                self.state = BitcoinConnectionStates::AwaitVersion(send_version.into());
+                self.complete_state(HandshakeState::SendVersion);
+                self.state = match self.direction {
+                    // the initiator waits for the remote version
+                    ConnectionDirection::Outbound => {
+                        BitcoinConnectionStates::AwaitVersion(send_version.into())
+                    }
+                    // the responder already has the remote version, acknowledge it
+                    ConnectionDirection::Inbound => {
+                        BitcoinConnectionStates::SendVerAck(send_version.into())
+                    }
+                };
                 Ok(())
             }
             Err(e) => Err(BitcoinHandshakeError::ProtocolError(format!(
                 "Failed sending version to {}, reason: {}",
-                self.connection_info.public_address.to_string(),
-                e
+                self.connection_info.public_address, e
             ))),
         }
     }
 
     async fn handle_await_version(
         &mut self,
-        mut await_version: AwaitVersion,
+        mut await_version: AwaitVersion<S>,
     ) -> AdvanceStateResult {
         match await_version.execute().await {
             Ok(_) => {
-                self.state = BitcoinConnectionStates::SendVerAck(await_version.into());
+                if let Some(version) = &await_version.connection_info.version {
+                    self.validate_version(version)?;
+                }
+                self.complete_state(HandshakeState::AwaitVersion);
+                self.state = match self.direction {
+                    // the initiator already sent its version, acknowledge the remote one
+                    ConnectionDirection::Outbound => {
+                        BitcoinConnectionStates::SendVerAck(await_version.into())
+                    }
+                    // the responder answers with its own version first
+                    ConnectionDirection::Inbound => {
+                        BitcoinConnectionStates::SendVersion(await_version.into())
+                    }
+                };
                 Ok(())
             }
+            Err(e @ BitcoinHandshakeError::InvalidMessage(_)) => Err(e),
             Err(e) => Err(BitcoinHandshakeError::InvalidResponse(format!(
                 "Failed receiving version from {}, reason: {}",
-                self.connection_info.public_address.to_string(),
-                e
+                self.connection_info.public_address, e
             ))),
         }
     }
 
+    // A version echoing the nonce of one of our handshakes means we dialed ourselves, whatever the policy says
+    fn validate_version(&self, version: &VersionMessage) -> AdvanceStateResult {
+        if self.config.local_nonces.contains(version.nonce()) {
+            return Err(BitcoinHandshakeError::SelfConnection);
+        }
+        self.config.version_policy.validate(version, self.direction)
+    }
+
     async fn handle_send_version_ack(
         &mut self,
-        mut send_version_ack: SendVerAck,
+        mut send_version_ack: SendVerAck<S>,
     ) -> AdvanceStateResult {
         match send_version_ack.execute().await {
             Ok(_) => {
+                self.complete_state(HandshakeState::SendVerAck);
                 self.state = BitcoinConnectionStates::AwaitVerAck(send_version_ack.into());
                 Ok(())
             }
             Err(e) => Err(BitcoinHandshakeError::ProtocolError(format!(
                 "Failed to send version ack to {}, reason: {}",
-                self.connection_info.public_address.to_string(),
-                e
+                self.connection_info.public_address, e
             ))),
         }
     }
 
     async fn handle_await_version_ack(
         &mut self,
-        mut await_version_ack: AwaitVerAck,
+        mut await_version_ack: AwaitVerAck<S>,
     ) -> AdvanceStateResult {
         match await_version_ack.execute().await {
             Ok(_) => {
-                self.state = BitcoinConnectionStates::Established(await_version_ack.into());
+                self.complete_state(HandshakeState::AwaitVerAck);
+                self.state =
+                    BitcoinConnectionStates::Established(self.establish(await_version_ack));
                 Ok(())
             }
+            Err(e @ BitcoinHandshakeError::InvalidMessage(_)) => Err(e),
             Err(e) => Err(BitcoinHandshakeError::InvalidResponse(format!(
                 "Failed to receive verack from {}, reason: {}",
-                self.connection_info.public_address.to_string(),
-                e
+                self.connection_info.public_address, e
             ))),
         }
     }
 
-    pub async fn connect(
-        &mut self,
-    ) -> Result<(TcpStream, BitcoinConnectionInfo), BitcoinHandshakeError> {
-        let mut connection_protocol = BitcoinConnectionProtocol::new(self.connection_info.clone());
+    fn complete_state(&mut self, state: HandshakeState) {
+        self.latencies.push((state, self.state_started.elapsed()));
+    }
+
+    // Hand the channel over to the established connection along with the report of the handshake
+    fn establish(&self, await_version_ack: AwaitVerAck<S>) -> Established<S> {
+        let connection_info = await_version_ack.connection_info;
+        let version = connection_info
+            .version
+            .as_ref()
+            .expect("the version is received before the verack");
+        let report = HandshakeReport::new(
+            &connection_info,
+            version,
+            self.direction,
+            self.latencies.clone(),
+        );
+        // only the peers we chose may move our clock, inbound peers could be many and colluding
+        if self.direction == ConnectionDirection::Outbound {
+            // a node counts once whatever its port, hidden services share the unspecified
+            // address and are told apart by their host
+            let peer = match &connection_info.host {
+                Some(host) => host.clone(),
+                None => connection_info.public_address.ip().to_string(),
+            };
+            self.config.network_time.add_sample(
+                peer,
+                report.clock_offset,
+                self.config.max_time_adjustment,
+            );
+        }
+        Established::new(
+            await_version_ack
+                .channel
+                .expect(CHANNEL_NOT_INITIALIZED_ERROR),
+            connection_info,
+            report,
+        )
+    }
+
+    // Drive the handshake until the connection is established or failed, regardless of the direction
+    pub async fn connect(mut self) -> Result<Established<S>, BitcoinHandshakeError> {
         loop {
-            _ = match connection_protocol.state {
-                BitcoinConnectionStates::Established(established) => {
-                    return Ok((established.channel, established.connection_info));
-                }
-                BitcoinConnectionStates::Failed(e) => return Err(e),
-                _ => connection_protocol.advance().await,
+            if let Some(established) = self.advance().await? {
+                return Ok(established);
+            }
+        }
+    }
+}
+
+// Run the handler of a state until its own timeout or the deadline of the whole handshake, whichever comes first
+async fn within(
+    state: HandshakeState,
+    timeout: Duration,
+    deadline: Instant,
+    handle: impl Future<Output = AdvanceStateResult>,
+) -> AdvanceStateResult {
+    let deadline = deadline.min(Instant::now() + timeout);
+    tokio::time::timeout_at(deadline, handle)
+        .await
+        .unwrap_or(Err(BitcoinHandshakeError::Timeout(state)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+        net::TcpListener,
+    };
+    use tokio_util::codec::Encoder;
+
+    use super::*;
+    use crate::bitcoin::{
+        fake_node::regtest_builder,
+        messages::{BitcoinCodec, BitcoinMessage, VersionMessage},
+        network::Network,
+    };
+
+    fn config(message_timeout: Duration, handshake_timeout: Duration) -> Arc<BitcoinConfiguration> {
+        Arc::new(
+            regtest_builder()
+                .message_timeout(message_timeout)
+                .handshake_timeout(handshake_timeout)
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out_awaiting_version() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let protocol = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            config(Duration::from_millis(200), Duration::from_secs(60)),
+        );
+        // accept the connection but never answer
+        let (result, accepted) = tokio::join!(protocol.connect(), listener.accept());
+        let _silent = accepted?;
+
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::Timeout(HandshakeState::AwaitVersion))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_refused_connection_once() -> Result<(), Box<dyn std::error::Error>> {
+        // nothing listens on the address once the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let result = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(address, Network::Regtest),
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+        )
+        .connect()
+        .await;
+
+        let Err(BitcoinHandshakeError::ConnectionFailed(reason)) = result else {
+            panic!("connected to a closed port");
+        };
+        assert!(reason.starts_with(&format!("Failed connecting to {}, reason: ", address)));
+        assert_eq!(reason.matches("Failed connecting").count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn total_deadline_bounds_the_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let protocol = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            config(Duration::from_secs(60), Duration::from_millis(300)),
+        );
+        let remote = async {
+            // answer with a version, but never acknowledge ours
+            let (mut channel, _) = listener.accept().await?;
+            let mut buffer = BytesMut::new();
+            BitcoinCodec::new(Network::Regtest).encode(
+                BitcoinMessage::Version(VersionMessage::new("silent peer", 0)),
+                &mut buffer,
+            )?;
+            channel.write_all(&buffer).await?;
+            Ok::<_, Box<dyn std::error::Error>>(channel)
+        };
+        let started = Instant::now();
+        let (result, remote) = tokio::join!(protocol.connect(), remote);
+        let _silent = remote?;
+
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::Timeout(HandshakeState::AwaitVerAck))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_over_in_memory_transport() -> Result<(), Box<dyn std::error::Error>> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let info = BitcoinConnectionInfo::new("127.0.0.1:18444".parse()?, Network::Regtest);
+        // each side has its own configuration, a shared one would detect a self connection
+        let outbound = BitcoinConnectionProtocol::new_with_transport(
+            info.clone(),
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+            async move { Ok(client) },
+        );
+        let inbound = BitcoinConnectionProtocol::new_inbound(
+            server,
+            info,
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+        );
+
+        let (outbound, inbound) = tokio::join!(outbound.connect(), inbound.connect());
+        let (mut outbound, inbound) = (outbound?, inbound?);
+        assert_eq!(outbound.report().direction, ConnectionDirection::Outbound);
+        assert_eq!(inbound.report().direction, ConnectionDirection::Inbound);
+
+        outbound.send(BitcoinMessage::GetAddr).await?;
+        let (mut reader, _writer) = inbound.split();
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(BitcoinMessage::GetAddr))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_over_v2_transport() -> Result<(), Box<dyn std::error::Error>> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let info = BitcoinConnectionInfo::new("127.0.0.1:18444".parse()?, Network::Regtest);
+        let (client, server) = tokio::join!(
+            connect_v2(client, Network::Regtest),
+            crate::bitcoin::v2_transport::accept_v2(server, Network::Regtest)
+        );
+        let (client, server) = (client?, server?);
+        let outbound = BitcoinConnectionProtocol::new_with_transport(
+            info.clone(),
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+            async move { Ok(client) },
+        );
+        let inbound = BitcoinConnectionProtocol::new_inbound(
+            server,
+            info,
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+        );
+
+        let (outbound, inbound) = tokio::join!(outbound.connect(), inbound.connect());
+        let (mut outbound, mut inbound) = (outbound?, inbound?);
+        // short ids and full commands both go through the encrypted packets
+        outbound.send(BitcoinMessage::GetAddr).await?;
+        outbound
+            .send(BitcoinMessage::Ping(
+                crate::bitcoin::messages::PingMessage { nonce: 3 },
+            ))
+            .await?;
+        assert!(matches!(
+            inbound.next().await,
+            Some(Ok(BitcoinMessage::GetAddr))
+        ));
+        assert!(matches!(
+            inbound.next().await,
+            Some(Ok(BitcoinMessage::Ping(ping))) if ping.nonce == 3
+        ));
+        Ok(())
+    }
+
+    // A transport whose every other write completes later, as a socket with a full send buffer
+    #[derive(Debug)]
+    struct DeferredWrites {
+        inner: DuplexStream,
+        deferred: bool,
+    }
+
+    impl AsyncRead for DeferredWrites {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for DeferredWrites {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.deferred = !self.deferred;
+            if self.deferred {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_messages_of_v2_transport() -> Result<(), Box<dyn std::error::Error>> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client = DeferredWrites {
+            inner: client,
+            deferred: false,
+        };
+        let info = BitcoinConnectionInfo::new("127.0.0.1:18444".parse()?, Network::Regtest);
+        let (client, server) = tokio::join!(
+            connect_v2(client, Network::Regtest),
+            crate::bitcoin::v2_transport::accept_v2(server, Network::Regtest)
+        );
+        let (client, server) = (client?, server?);
+        // the version is only queued by the first write, a handshake without flush would wait for it
+        let outbound = BitcoinConnectionProtocol::new_with_transport(
+            info.clone(),
+            config(Duration::from_millis(500), Duration::from_secs(2)),
+            async move { Ok(client) },
+        );
+        let inbound = BitcoinConnectionProtocol::new_inbound(
+            server,
+            info,
+            config(Duration::from_millis(500), Duration::from_secs(2)),
+        );
+
+        let (outbound, inbound) = tokio::join!(outbound.connect(), inbound.connect());
+        outbound?;
+        inbound?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_version() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let protocol = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            config(Duration::from_secs(5), Duration::from_secs(10)),
+        );
+        let remote = async {
+            // announce a 4 GiB version, the payload never follows
+            let (mut channel, _) = listener.accept().await?;
+            let mut buffer = BytesMut::new();
+            BitcoinCodec::new(Network::Regtest).encode(
+                BitcoinMessage::Version(VersionMessage::new("greedy peer", 0)),
+                &mut buffer,
+            )?;
+            buffer[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+            channel.write_all(&buffer[..24]).await?;
+            Ok::<_, Box<dyn std::error::Error>>(channel)
+        };
+        let (result, remote) = tokio::join!(protocol.connect(), remote);
+        let _greedy = remote?;
+
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::InvalidMessage(
+                DecodeError::MessageTooLarge { .. }
+            ))
+        ));
+        Ok(())
+    }
+
+    // Complete an outbound handshake with a peer whose clock is two minutes ahead of ours
+    async fn handshake_with_peer_ahead(
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        // the peer answers first, the duplex holds our messages meanwhile
+        let mut buffer = BytesMut::new();
+        let mut codec = BitcoinCodec::new(Network::Regtest);
+        codec.encode(
+            BitcoinMessage::Version(
+                VersionMessage::new("peer ahead", 0)
+                    .with_timestamp(chrono::Utc::now().timestamp() + 120),
+            ),
+            &mut buffer,
+        )?;
+        codec.encode(BitcoinMessage::VerAck, &mut buffer)?;
+        server.write_all(&buffer).await?;
+
+        BitcoinConnectionProtocol::new_with_transport(connection_info, config, async move {
+            Ok(client)
+        })
+        .connect()
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adjust_network_time_from_outbound_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let config = config(Duration::from_secs(5), Duration::from_secs(10));
+        for index in 1..=5 {
+            // every peer is a hidden service behind the same unspecified address
+            let address = std::net::SocketAddr::from(([0u8; 16], 18444));
+            let connection_info = BitcoinConnectionInfo::new(address, Network::Regtest)
+                .with_host(format!("peer{}.onion", index));
+            handshake_with_peer_ahead(connection_info, config.clone()).await?;
         }
+        assert!((config.network_time.offset() - 120).abs() <= 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_node_once_whatever_its_port() -> Result<(), Box<dyn std::error::Error>> {
+        let config = config(Duration::from_secs(5), Duration::from_secs(10));
+        for port in 18444..18449 {
+            let address = std::net::SocketAddr::from(([10, 0, 0, 1], port));
+            let connection_info = BitcoinConnectionInfo::new(address, Network::Regtest);
+            handshake_with_peer_ahead(connection_info, config.clone()).await?;
+        }
+        // a single sample is too few to move our clock
+        assert_eq!(config.network_time.offset(), 0);
+        Ok(())
     }
 }
@@ -1,6 +1,22 @@
-use crate::bitcoin::bitcoin_connection_info::BitcoinConnectionInfo;
+use std::{fmt, sync::Arc};
 
-#[derive(Debug)]
-pub(super) struct Disconnected {
+use futures::future::BoxFuture;
+
+use crate::bitcoin::{bitcoin_connection_info::BitcoinConnectionInfo, BitcoinConfiguration};
+
+// Open the transport to the remote peer, polled once the handshake starts connecting
+pub(super) type OpenTransport<S> = BoxFuture<'static, std::io::Result<S>>;
+
+pub(super) struct Disconnected<S> {
     pub(super) connection_info: BitcoinConnectionInfo,
+    pub(super) config: Arc<BitcoinConfiguration>,
+    pub(super) open: OpenTransport<S>,
+}
+
+impl<S> fmt::Debug for Disconnected<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Disconnected")
+            .field("connection_info", &self.connection_info)
+            .finish_non_exhaustive()
+    }
 }
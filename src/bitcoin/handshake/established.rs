@@ -1,28 +1,163 @@
-use tokio::net::TcpStream;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use super::{await_version_ack::AwaitVerAck, CHANNEL_NOT_INITIALIZED_ERROR};
-use crate::bitcoin::bitcoin_connection_info::BitcoinConnectionInfo;
+use futures::{SinkExt, Stream};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
+use super::{report::HandshakeReport, transport::Transport};
+use crate::bitcoin::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    messages::{BitcoinCodec, BitcoinMessage},
+    v2_transport::PeerStream,
+};
+
+/// A connection that completed the handshake.
+///
+/// Exchange typed messages with `send` and by polling it as a `Stream`,
+/// or `split` it to read and write concurrently from different tasks.
 #[derive(Debug)]
-pub(super) struct Established {
-    pub(super) channel: TcpStream,
-    pub(super) connection_info: BitcoinConnectionInfo,
+pub struct Established<S = PeerStream> {
+    reader: EstablishedReader<S>,
+    writer: EstablishedWriter<S>,
 }
 
-impl Established {
-    fn new(stream: TcpStream, connection_info: BitcoinConnectionInfo) -> Self {
+impl<S: Transport> Established<S> {
+    pub(super) fn new(
+        stream: S,
+        connection_info: BitcoinConnectionInfo,
+        report: HandshakeReport,
+    ) -> Self {
+        // the handshake reads exactly one message at a time, so no bytes are buffered beyond the verack
+        let (read_half, write_half) = tokio::io::split(stream);
+        let codec = BitcoinCodec::new(connection_info.network);
         Established {
-            channel: stream,
-            connection_info,
+            reader: EstablishedReader {
+                framed: FramedRead::new(read_half, codec),
+                connection_info: connection_info.clone(),
+                report: report.clone(),
+            },
+            writer: EstablishedWriter {
+                framed: FramedWrite::new(write_half, codec),
+                connection_info,
+                report,
+            },
         }
     }
+
+    /// The information negotiated during the handshake.
+    pub fn connection_info(&self) -> &BitcoinConnectionInfo {
+        &self.writer.connection_info
+    }
+
+    /// What was negotiated with the peer during the handshake.
+    pub fn report(&self) -> &HandshakeReport {
+        &self.writer.report
+    }
+
+    pub async fn send(&mut self, message: BitcoinMessage) -> io::Result<()> {
+        self.writer.send(message).await
+    }
+
+    /// Split the connection into halves that can be moved to different tasks.
+    pub fn split(self) -> (EstablishedReader<S>, EstablishedWriter<S>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<S: Transport> Stream for Established<S> {
+    type Item = io::Result<BitcoinMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.reader).poll_next(cx)
+    }
+}
+
+/// The receiving half of an established connection.
+#[derive(Debug)]
+pub struct EstablishedReader<S = PeerStream> {
+    framed: FramedRead<ReadHalf<S>, BitcoinCodec>,
+    connection_info: BitcoinConnectionInfo,
+    report: HandshakeReport,
+}
+
+impl<S> EstablishedReader<S> {
+    pub fn connection_info(&self) -> &BitcoinConnectionInfo {
+        &self.connection_info
+    }
+
+    pub fn report(&self) -> &HandshakeReport {
+        &self.report
+    }
+}
+
+impl<S: Transport> Stream for EstablishedReader<S> {
+    type Item = io::Result<BitcoinMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.framed).poll_next(cx)
+    }
+}
+
+/// The sending half of an established connection.
+#[derive(Debug)]
+pub struct EstablishedWriter<S = PeerStream> {
+    framed: FramedWrite<WriteHalf<S>, BitcoinCodec>,
+    connection_info: BitcoinConnectionInfo,
+    report: HandshakeReport,
+}
+
+impl<S: Transport> EstablishedWriter<S> {
+    pub fn connection_info(&self) -> &BitcoinConnectionInfo {
+        &self.connection_info
+    }
+
+    pub fn report(&self) -> &HandshakeReport {
+        &self.report
+    }
+
+    pub async fn send(&mut self, message: BitcoinMessage) -> io::Result<()> {
+        self.framed.send(message).await
+    }
 }
 
-impl From<AwaitVerAck> for Established {
-    fn from(value: AwaitVerAck) -> Self {
-        Established::new(
-            value.channel.expect(CHANNEL_NOT_INITIALIZED_ERROR),
-            value.connection_info,
-        )
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_listener::BitcoinListener, fake_node::regtest_config, network::Network,
+        BitcoinConnectionProtocol,
+    };
+
+    #[tokio::test]
+    async fn exchange_messages_after_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        // separate configurations, otherwise the shared nonces reject the connection to ourselves
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let outbound = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            Arc::new(regtest_config()),
+        );
+        let (inbound, outbound) = tokio::join!(listener.accept(), outbound.connect());
+        let mut inbound = inbound?;
+        let (mut reader, mut writer) = outbound?.split();
+
+        // read and write concurrently on the split halves
+        let reading = tokio::spawn(async move { reader.next().await });
+        writer.send(BitcoinMessage::SendHeaders).await?;
+        inbound.send(BitcoinMessage::GetAddr).await?;
+
+        assert_eq!(
+            inbound.next().await.transpose()?,
+            Some(BitcoinMessage::SendHeaders)
+        );
+        assert_eq!(reading.await?.transpose()?, Some(BitcoinMessage::GetAddr));
+        Ok(())
     }
 }
@@ -1,11 +1,25 @@
 mod await_version;
 mod await_version_ack;
+mod chain_height;
 mod connecting;
 mod connection_protocol;
 mod disconnected;
 mod established;
+mod report;
 mod send_version;
 mod send_version_ack;
-pub use connection_protocol::BitcoinConnectionProtocol;
+mod transport;
+mod version_policy;
+pub use chain_height::ChainHeight;
+pub use connection_protocol::{
+    BitcoinConnectionProtocol, BitcoinHandshakeError, ConnectionDirection, HandshakeState,
+};
+pub use established::{Established, EstablishedReader, EstablishedWriter};
+pub use report::HandshakeReport;
+pub use transport::Transport;
+pub(crate) use version_policy::LocalNonces;
+pub use version_policy::{
+    StandardVersionPolicy, VersionPolicy, DEFAULT_MAX_CLOCK_OFFSET, DEFAULT_MIN_PROTOCOL_VERSION,
+};
 
-const CHANNEL_NOT_INITIALIZED_ERROR: &str = "channel TcpStream must be initialized";
+const CHANNEL_NOT_INITIALIZED_ERROR: &str = "channel transport must be initialized";
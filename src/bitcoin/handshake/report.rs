@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use super::connection_protocol::{ConnectionDirection, HandshakeState};
+use crate::bitcoin::{
+    bitcoin_connection_info::{BitcoinConnectionInfo, NegotiatedFeatures},
+    messages::{ServiceFlags, VersionMessage, PROTOCOL_VERSION},
+};
+
+/// What was negotiated with a remote peer during a successful handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeReport {
+    pub direction: ConnectionDirection,
+    /// The version both sides speak, the lower of ours and the peer's.
+    pub protocol_version: u32,
+    /// The version announced by the peer.
+    pub remote_version: u32,
+    pub services: ServiceFlags,
+    pub user_agent: String,
+    pub start_height: i32,
+    /// Whether the peer wants transactions announced to it (BIP 37).
+    pub relay: bool,
+    /// The clock of the peer minus ours, in seconds.
+    pub clock_offset: i64,
+    /// The feature messages the peer sent before its verack.
+    pub features: NegotiatedFeatures,
+    /// How long each state of the handshake took, in the order they ran.
+    pub latencies: Vec<(HandshakeState, Duration)>,
+}
+
+impl HandshakeReport {
+    pub(super) fn new(
+        connection_info: &BitcoinConnectionInfo,
+        version: &VersionMessage,
+        direction: ConnectionDirection,
+        latencies: Vec<(HandshakeState, Duration)>,
+    ) -> Self {
+        HandshakeReport {
+            direction,
+            protocol_version: version.version().min(PROTOCOL_VERSION),
+            remote_version: version.version(),
+            services: version.services(),
+            user_agent: version.user_agent().to_owned(),
+            start_height: version.start_height(),
+            relay: version.relay(),
+            clock_offset: connection_info.clock_offset.unwrap_or_default(),
+            features: connection_info.features.clone(),
+            latencies,
+        }
+    }
+
+    /// The time from the start of the handshake until the verack of the peer.
+    pub fn total_latency(&self) -> Duration {
+        self.latencies.iter().map(|(_, latency)| *latency).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_listener::BitcoinListener, fake_node::regtest_builder, network::Network,
+        BitcoinConnectionProtocol,
+    };
+
+    #[test]
+    fn negotiate_the_lower_version() {
+        let connection_info =
+            BitcoinConnectionInfo::new("127.0.0.1:18444".parse().unwrap(), Network::Regtest);
+        let version = VersionMessage::new("old peer", 0).with_version(70001);
+        let report = HandshakeReport::new(
+            &connection_info,
+            &version,
+            ConnectionDirection::Outbound,
+            Vec::new(),
+        );
+        assert_eq!(report.protocol_version, 70001);
+        assert_eq!(report.remote_version, 70001);
+    }
+
+    #[tokio::test]
+    async fn report_both_sides_of_the_handshake() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind(
+            "127.0.0.1:0".parse()?,
+            regtest_builder().user_agent("/listener:0.1/").build(),
+        )
+        .await?;
+        let outbound = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            Arc::new(regtest_builder().user_agent("/dialer:0.1/").build()),
+        );
+        let (inbound, outbound) = tokio::join!(listener.accept(), outbound.connect());
+        let (inbound, outbound) = (inbound?, outbound?);
+
+        let report = outbound.report();
+        assert_eq!(report.direction, ConnectionDirection::Outbound);
+        assert_eq!(report.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(report.user_agent, "/listener:0.1/");
+        assert!(report.services.has(ServiceFlags::NETWORK));
+        assert!(report.clock_offset.abs() <= 1);
+        let states: Vec<_> = report.latencies.iter().map(|(state, _)| *state).collect();
+        assert_eq!(
+            states,
+            [
+                HandshakeState::Connecting,
+                HandshakeState::SendVersion,
+                HandshakeState::AwaitVersion,
+                HandshakeState::SendVerAck,
+                HandshakeState::AwaitVerAck
+            ]
+        );
+
+        let report = inbound.report();
+        assert_eq!(report.direction, ConnectionDirection::Inbound);
+        assert_eq!(report.user_agent, "/dialer:0.1/");
+        let states: Vec<_> = report.latencies.iter().map(|(state, _)| *state).collect();
+        assert_eq!(
+            states,
+            [
+                HandshakeState::AwaitVersion,
+                HandshakeState::SendVersion,
+                HandshakeState::SendVerAck,
+                HandshakeState::AwaitVerAck
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,84 +1,171 @@
 use super::{
-    connecting::Connecting, connection_protocol::BitcoinHandshakeError,
+    await_version::AwaitVersion, connecting::Connecting,
+    connection_protocol::BitcoinHandshakeError, transport::Transport,
     CHANNEL_NOT_INITIALIZED_ERROR,
 };
 use crate::bitcoin::{
     bitcoin_connection_info::BitcoinConnectionInfo,
-    messages::{commands::Command, HeaderCodec, HeaderMessage, VersionCodec, VersionMessage},
+    messages::{BitcoinCodec, BitcoinMessage, ServiceFlags, VersionMessage},
+    network::Network,
     BitcoinConfiguration,
 };
 use bytes::BytesMut;
+use std::sync::Arc;
 
-use clap::Parser;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::Encoder;
-use tracing::error;
 
 #[derive(Debug)]
-pub(super) struct SendVersion {
-    pub(super) channel: Option<TcpStream>,
+pub(super) struct SendVersion<S> {
+    pub(super) channel: Option<S>,
     pub(super) connection_info: BitcoinConnectionInfo,
-    user_agent: String,
+    pub(super) config: Arc<BitcoinConfiguration>,
 }
 
-impl SendVersion {
+impl<S: Transport> SendVersion<S> {
     // Initialize the state with the stream and the operation to send the version
-    fn new(channel: TcpStream, connection_info: BitcoinConnectionInfo, user_agent: String) -> Self {
+    fn new(
+        channel: S,
+        connection_info: BitcoinConnectionInfo,
+        config: Arc<BitcoinConfiguration>,
+    ) -> Self {
         SendVersion {
             channel: Some(channel),
             connection_info,
-            user_agent,
+            config,
         }
     }
 
-    pub(super) async fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // store the payload in temporary buffer, so we can take the payload length
-        let mut payload_buffer = BytesMut::new();
-        let mut payload_codec = VersionCodec {};
-        let payload_message = VersionMessage::new(&self.user_agent, 0);
-        payload_codec
-            .encode(payload_message, &mut payload_buffer)
-            .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()))?;
-
-        // prepare the header
-        let mut header_buffer = BytesMut::new();
-        let mut header_codec = HeaderCodec {};
-        let header_message = HeaderMessage::new(Command::Version, &payload_buffer);
-        header_codec
-            .encode(header_message, &mut header_buffer)
-            .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()))?;
+    pub(super) async fn execute(&mut self, nonce: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let config = &self.config;
+        let mut services = config.services;
+        if config.v2_transport {
+            services |= ServiceFlags::P2P_V2;
+        }
+        // the responder knows the services of the initiator from its version already,
+        // the initiator announces those the discovery or the address manager knew of
+        let remote_services = self
+            .connection_info
+            .version
+            .as_ref()
+            .map_or(self.connection_info.services, |version| version.services());
+        let mut payload_message =
+            VersionMessage::new(&config.user_agent, config.chain_height.height())
+                .with_services(services)
+                .with_receiver(self.connection_info.public_address, remote_services)
+                .with_relay(!config.blocks_only)
+                .with_nonce(nonce);
+        if let Some(address) = config
+            .external_address
+            .or(self.connection_info.local_address)
+        {
+            payload_message = payload_message.with_sender(address);
+        }
 
         if let Some(mut channel) = self.channel.take() {
-            let result = async {
-                channel.write_all(&header_buffer).await?;
-                channel.write_all(&payload_buffer).await?;
-                Ok(())
-            }
+            let result = write_message(
+                &mut channel,
+                self.connection_info.network,
+                BitcoinMessage::Version(payload_message),
+            )
             .await;
 
             // return the channel back
             self.channel = Some(channel);
-            result
+            result.map_err(|e| e.into())
         } else {
             panic!("{}", CHANNEL_NOT_INITIALIZED_ERROR);
         }
     }
 }
 
-impl From<Connecting> for SendVersion {
-    fn from(value: Connecting) -> Self {
-        let config = BitcoinConfiguration::try_parse()
-            .map_err(|e| {
-                error!(
-                    "Failure while parsing command line arguments. error: {:?}",
-                    e
-                )
-            })
-            .expect("Failure while parsing command line arguments.");
+// Encode the message with its header and write it to the channel
+pub(super) async fn write_message(
+    channel: &mut (impl AsyncWrite + Unpin),
+    network: Network,
+    message: BitcoinMessage,
+) -> Result<(), BitcoinHandshakeError> {
+    let mut buffer = BytesMut::new();
+    BitcoinCodec::new(network)
+        .encode(message, &mut buffer)
+        .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()))?;
+    channel.write_all(&buffer).await?;
+    // a buffering transport, e.g. the v2 transport, may keep the message until it is flushed
+    channel.flush().await?;
+    Ok(())
+}
+
+// The initiator sends its version right after the connection is opened
+impl<S: Transport> From<Connecting<S>> for SendVersion<S> {
+    fn from(value: Connecting<S>) -> Self {
         SendVersion::new(
             value.channel.expect(CHANNEL_NOT_INITIALIZED_ERROR),
             value.connection_info,
-            config.user_agent,
+            value.config,
         )
     }
 }
+
+// The responder sends its version after receiving the version of the initiator
+impl<S: Transport> From<AwaitVersion<S>> for SendVersion<S> {
+    fn from(value: AwaitVersion<S>) -> Self {
+        SendVersion::new(
+            value.channel.expect(CHANNEL_NOT_INITIALIZED_ERROR),
+            value.connection_info,
+            value.config,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    use futures::StreamExt;
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+    use crate::bitcoin::fake_node::regtest_builder;
+
+    #[tokio::test]
+    async fn announce_connection_and_configuration() -> Result<(), Box<dyn std::error::Error>> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let remote: SocketAddr = "10.0.0.1:18444".parse()?;
+        let external: SocketAddr = "203.0.113.7:18444".parse()?;
+        let height = Arc::new(AtomicI32::new(0));
+        let config = Arc::new(
+            regtest_builder()
+                .external_address(external)
+                .blocks_only(true)
+                .chain_height(height.clone())
+                .build(),
+        );
+        let mut send_version = SendVersion::new(
+            client,
+            BitcoinConnectionInfo::new(remote, Network::Regtest)
+                .with_services(ServiceFlags::NETWORK | ServiceFlags::WITNESS),
+            config,
+        );
+        // the height is read when the version is sent
+        height.store(840_000, Ordering::Relaxed);
+        send_version.execute(42).await?;
+
+        let mut received = FramedRead::new(server, BitcoinCodec::new(Network::Regtest));
+        let Some(Ok(BitcoinMessage::Version(version))) = received.next().await else {
+            panic!("expected a version");
+        };
+        assert_eq!(version.receiver(), remote);
+        assert_eq!(
+            version.receiver_services(),
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS
+        );
+        assert_eq!(version.sender(), external);
+        assert_eq!(version.start_height(), 840_000);
+        assert_eq!(version.nonce(), 42);
+        assert!(!version.relay());
+        Ok(())
+    }
+}
@@ -1,42 +1,53 @@
-use bytes::BytesMut;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
-use tokio_util::codec::Encoder;
-
 use crate::bitcoin::{
     bitcoin_connection_info::BitcoinConnectionInfo,
     handshake::CHANNEL_NOT_INITIALIZED_ERROR,
-    messages::{commands::Command, HeaderCodec, HeaderMessage},
+    messages::{BitcoinMessage, PROTOCOL_VERSION},
 };
 
 use super::{
     await_version::AwaitVersion,
     connection_protocol::{AdvanceStateResult, BitcoinHandshakeError},
+    send_version::{write_message, SendVersion},
+    transport::Transport,
 };
 
-const VERACK_CHECKSUM: [u8; 4] = [0x5D, 0xF6, 0xE0, 0xE2];
+// BIP 155 peers announce sendaddrv2 to this version and above
+const ADDR_V2_PROTOCOL_VERSION: u32 = 70016;
 
 #[derive(Debug)]
-pub(super) struct SendVerAck {
-    pub(super) channel: Option<TcpStream>,
+pub(super) struct SendVerAck<S> {
+    pub(super) channel: Option<S>,
     pub(super) connection_info: BitcoinConnectionInfo,
 }
 
-impl SendVerAck {
+impl<S: Transport> SendVerAck<S> {
     // The verack message is sent in reply to version. This message consists of only a message header with the command string "verack".
+    // Peers supporting BIP 155 are told first that we accept addrv2, which must happen before the verack.
+    // Both sides must know it, the session runs the lower of both versions as in Bitcoin Core.
     pub(super) async fn execute(&mut self) -> AdvanceStateResult {
-        // prepare the header
-        let mut header_buffer = BytesMut::new();
-        let mut header_codec = HeaderCodec {};
-        let header_message = HeaderMessage::new_without_payload(Command::VerAck, VERACK_CHECKSUM);
-        header_codec
-            .encode(header_message, &mut header_buffer)
-            .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()))?;
-
         if let Some(mut channel) = self.channel.take() {
-            let result = channel
-                .write_all(&header_buffer)
-                .await
-                .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()));
+            let mut messages = Vec::new();
+            if self
+                .connection_info
+                .version
+                .as_ref()
+                .is_some_and(|version| {
+                    version.version().min(PROTOCOL_VERSION) >= ADDR_V2_PROTOCOL_VERSION
+                })
+            {
+                messages.push(BitcoinMessage::SendAddrV2);
+            }
+            messages.push(BitcoinMessage::VerAck);
+
+            let mut result = Ok(());
+            for message in messages {
+                result = write_message(&mut channel, self.connection_info.network, message)
+                    .await
+                    .map_err(|e| BitcoinHandshakeError::ProtocolError(e.to_string()));
+                if result.is_err() {
+                    break;
+                }
+            }
 
             // return the channel back
             self.channel = Some(channel);
@@ -47,11 +58,69 @@ impl SendVerAck {
     }
 }
 
-impl From<AwaitVersion> for SendVerAck {
-    fn from(value: AwaitVersion) -> Self {
+// The responder acknowledges the remote version after sending its own
+impl<S> From<SendVersion<S>> for SendVerAck<S> {
+    fn from(value: SendVersion<S>) -> Self {
+        SendVerAck {
+            channel: value.channel,
+            connection_info: value.connection_info,
+        }
+    }
+}
+
+// The initiator acknowledges the remote version once received
+impl<S> From<AwaitVersion<S>> for SendVerAck<S> {
+    fn from(value: AwaitVersion<S>) -> Self {
         SendVerAck {
             channel: value.channel,
             connection_info: value.connection_info,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+    use crate::bitcoin::{
+        messages::{BitcoinCodec, VersionMessage},
+        network::Network,
+    };
+
+    // The messages received by the remote side when acknowledging a peer of the given version
+    async fn acknowledge(version: u32) -> Result<Vec<BitcoinMessage>, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let remote = TcpStream::connect(listener.local_addr()?).await?;
+        let (channel, remote_address) = listener.accept().await?;
+
+        let mut connection_info = BitcoinConnectionInfo::new(remote_address, Network::Regtest);
+        connection_info.version = Some(VersionMessage::new("peer", 0).with_version(version));
+        let mut send_verack = SendVerAck {
+            channel: Some(channel),
+            connection_info,
+        };
+        send_verack.execute().await?;
+        drop(send_verack);
+
+        let received = FramedRead::new(remote, BitcoinCodec::new(Network::Regtest))
+            .collect::<Vec<_>>()
+            .await;
+        Ok(received.into_iter().collect::<Result<_, _>>()?)
+    }
+
+    #[tokio::test]
+    async fn announce_addr_v2_to_bip155_peers() -> Result<(), Box<dyn std::error::Error>> {
+        // the version we announce negotiates addrv2, with peers of the same version or newer
+        for version in [PROTOCOL_VERSION, PROTOCOL_VERSION + 1] {
+            assert_eq!(
+                acknowledge(version).await?,
+                vec![BitcoinMessage::SendAddrV2, BitcoinMessage::VerAck]
+            );
+        }
+        assert_eq!(acknowledge(70015).await?, vec![BitcoinMessage::VerAck]);
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+use std::fmt::Debug;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A byte stream the handshake can run over.
+///
+/// Implemented for every stream that fits, e.g. `TcpStream`, `UnixStream`, `tokio::io::DuplexStream`
+/// or a stream wrapped by a proxy or an encryption layer.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Debug + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + Debug + 'static {}
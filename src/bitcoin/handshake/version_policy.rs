@@ -0,0 +1,197 @@
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::connection_protocol::{BitcoinHandshakeError, ConnectionDirection};
+use crate::bitcoin::messages::{ServiceFlags, VersionMessage};
+
+// BIP 37 added the relay field, older peers are not supported by default
+pub const DEFAULT_MIN_PROTOCOL_VERSION: u32 = 70001;
+
+// A peer clock that is a day away from ours is broken, not merely skewed
+pub const DEFAULT_MAX_CLOCK_OFFSET: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Decide whether the version of a remote peer is acceptable.
+///
+/// Called once the version message of the peer is received, rejecting it fails the handshake with the returned error.
+pub trait VersionPolicy: Debug + Send + Sync {
+    fn validate(
+        &self,
+        version: &VersionMessage,
+        direction: ConnectionDirection,
+    ) -> Result<(), BitcoinHandshakeError>;
+}
+
+/// The policy used unless another one is configured.
+#[derive(Debug, Clone)]
+pub struct StandardVersionPolicy {
+    pub min_protocol_version: u32,
+    // Service bits a peer we dial must offer, inbound peers are not required to serve us
+    pub required_services: ServiceFlags,
+    pub max_clock_offset: Duration,
+}
+
+impl Default for StandardVersionPolicy {
+    fn default() -> Self {
+        StandardVersionPolicy {
+            min_protocol_version: DEFAULT_MIN_PROTOCOL_VERSION,
+            required_services: ServiceFlags::NONE,
+            max_clock_offset: DEFAULT_MAX_CLOCK_OFFSET,
+        }
+    }
+}
+
+impl VersionPolicy for StandardVersionPolicy {
+    fn validate(
+        &self,
+        version: &VersionMessage,
+        direction: ConnectionDirection,
+    ) -> Result<(), BitcoinHandshakeError> {
+        if version.version() < self.min_protocol_version {
+            return Err(BitcoinHandshakeError::ObsoleteVersion {
+                version: version.version(),
+                minimum: self.min_protocol_version,
+            });
+        }
+        if direction == ConnectionDirection::Outbound
+            && !version.services().has(self.required_services)
+        {
+            return Err(BitcoinHandshakeError::MissingServices {
+                offered: version.services(),
+                required: self.required_services,
+            });
+        }
+        let offset = version
+            .timestamp()
+            .saturating_sub(chrono::Utc::now().timestamp());
+        if offset.unsigned_abs() > self.max_clock_offset.as_secs() {
+            return Err(BitcoinHandshakeError::InvalidTimestamp { offset });
+        }
+        Ok(())
+    }
+}
+
+// The nonces of the version messages we sent and whose handshake is still in progress.
+// Receiving one of them back means we connected to ourselves.
+// Clones share the same nonces, so every connection made from the same configuration is checked against the others.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LocalNonces(Arc<Mutex<HashSet<u64>>>);
+
+impl LocalNonces {
+    // Pick a new nonce, it is forgotten once the returned guard is dropped
+    pub(crate) fn register(&self) -> LocalNonce {
+        let mut nonces = self.0.lock().expect("local nonces lock poisoned");
+        let mut nonce = rand::random();
+        while !nonces.insert(nonce) {
+            nonce = rand::random();
+        }
+        LocalNonce {
+            nonce,
+            nonces: self.clone(),
+        }
+    }
+
+    pub(crate) fn contains(&self, nonce: u64) -> bool {
+        self.0
+            .lock()
+            .expect("local nonces lock poisoned")
+            .contains(&nonce)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LocalNonce {
+    nonce: u64,
+    nonces: LocalNonces,
+}
+
+impl LocalNonce {
+    pub(crate) fn value(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl Drop for LocalNonce {
+    fn drop(&mut self) {
+        if let Ok(mut nonces) = self.nonces.0.lock() {
+            nonces.remove(&self.nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version() -> VersionMessage {
+        VersionMessage::new("peer", 0)
+    }
+
+    #[test]
+    fn accept_current_peer() {
+        let policy = StandardVersionPolicy::default();
+        assert!(policy
+            .validate(&version(), ConnectionDirection::Outbound)
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_obsolete_version() {
+        let policy = StandardVersionPolicy::default();
+        let result = policy.validate(&version().with_version(60002), ConnectionDirection::Inbound);
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::ObsoleteVersion {
+                version: 60002,
+                minimum: DEFAULT_MIN_PROTOCOL_VERSION
+            })
+        ));
+    }
+
+    #[test]
+    fn require_services_from_outbound_peers_only() {
+        let policy = StandardVersionPolicy {
+            required_services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            ..Default::default()
+        };
+        let version = version().with_services(ServiceFlags::NETWORK);
+        assert!(matches!(
+            policy.validate(&version, ConnectionDirection::Outbound),
+            Err(BitcoinHandshakeError::MissingServices { .. })
+        ));
+        assert!(policy
+            .validate(&version, ConnectionDirection::Inbound)
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_absurd_timestamp() {
+        let policy = StandardVersionPolicy::default();
+        for timestamp in [
+            0,
+            i64::MAX,
+            chrono::Utc::now().timestamp() + 2 * 24 * 60 * 60,
+        ] {
+            assert!(matches!(
+                policy.validate(
+                    &version().with_timestamp(timestamp),
+                    ConnectionDirection::Outbound
+                ),
+                Err(BitcoinHandshakeError::InvalidTimestamp { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn forget_nonce_when_dropped() {
+        let nonces = LocalNonces::default();
+        let nonce = nonces.register();
+        let value = nonce.value();
+        assert!(nonces.clone().contains(value));
+        drop(nonce);
+        assert!(!nonces.contains(value));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::debug;
+
+use super::{
+    bitcoin_connection_info::BitcoinConnectionInfo,
+    handshake::{Established, EstablishedReader, EstablishedWriter, HandshakeReport, Transport},
+    messages::{BitcoinMessage, PingMessage},
+};
+
+// Messages not consumed yet by the owner of the connection.
+// Once full the keepalive task waits, so a stalled consumer eventually fails the pings of the peer.
+const INCOMING_BUFFER: usize = 64;
+
+/// An established connection kept alive by a background task.
+///
+/// The task pings the peer every interval and fails the connection when the pong does not arrive in time,
+/// answers the pings of the peer and measures the round trip time.
+/// Every other message is yielded by polling it as a `Stream`.
+#[derive(Debug)]
+pub struct KeepAliveConnection {
+    incoming: mpsc::Receiver<io::Result<BitcoinMessage>>,
+    outgoing: mpsc::Sender<BitcoinMessage>,
+    round_trip_time: watch::Receiver<Option<Duration>>,
+    connection_info: BitcoinConnectionInfo,
+    report: HandshakeReport,
+}
+
+impl KeepAliveConnection {
+    pub fn new<S: Transport>(
+        established: Established<S>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        let connection_info = established.connection_info().clone();
+        let report = established.report().clone();
+        let (incoming_sender, incoming) = mpsc::channel(INCOMING_BUFFER);
+        let (outgoing, outgoing_receiver) = mpsc::channel(INCOMING_BUFFER);
+        let (round_trip_sender, round_trip_time) = watch::channel(None);
+        let (reader, writer) = established.split();
+        tokio::spawn(keep_alive(
+            reader,
+            writer,
+            KeepAliveChannels {
+                incoming: incoming_sender,
+                outgoing: outgoing_receiver,
+                round_trip_time: round_trip_sender,
+            },
+            interval,
+            timeout,
+        ));
+        KeepAliveConnection {
+            incoming,
+            outgoing,
+            round_trip_time,
+            connection_info,
+            report,
+        }
+    }
+
+    pub fn connection_info(&self) -> &BitcoinConnectionInfo {
+        &self.connection_info
+    }
+
+    pub fn report(&self) -> &HandshakeReport {
+        &self.report
+    }
+
+    /// The round trip time of the last answered ping.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        *self.round_trip_time.borrow()
+    }
+
+    // Watch the round trip time, it stays readable after the connection is dropped
+    pub(crate) fn watch_round_trip_time(&self) -> watch::Receiver<Option<Duration>> {
+        self.round_trip_time.clone()
+    }
+
+    pub async fn send(&self, message: BitcoinMessage) -> io::Result<()> {
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "The connection is closed"))
+    }
+}
+
+impl Stream for KeepAliveConnection {
+    type Item = io::Result<BitcoinMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx)
+    }
+}
+
+// The ends of the channels owned by the keepalive task
+struct KeepAliveChannels {
+    incoming: mpsc::Sender<io::Result<BitcoinMessage>>,
+    outgoing: mpsc::Receiver<BitcoinMessage>,
+    round_trip_time: watch::Sender<Option<Duration>>,
+}
+
+// Own the connection until it fails or the KeepAliveConnection is dropped
+async fn keep_alive<S: Transport>(
+    mut reader: EstablishedReader<S>,
+    mut writer: EstablishedWriter<S>,
+    mut channels: KeepAliveChannels,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let address = reader.connection_info().public_address;
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the nonce of the ping in flight and when it was sent
+    let mut pending: Option<(u64, Instant)> = None;
+
+    let result: io::Result<()> = async {
+        loop {
+            let pong_deadline = pending.map(|(_, sent)| sent + timeout);
+            tokio::select! {
+                message = reader.next() => match message {
+                    Some(Ok(BitcoinMessage::Ping(ping))) => {
+                        writer.send(BitcoinMessage::Pong(ping)).await?
+                    }
+                    Some(Ok(BitcoinMessage::Pong(pong))) => match pending {
+                        Some((nonce, sent)) if nonce == pong.nonce => {
+                            let round_trip_time = sent.elapsed();
+                            debug!("pong from {} after {:?}", address, round_trip_time);
+                            channels.round_trip_time.send_replace(Some(round_trip_time));
+                            pending = None;
+                        }
+                        // a late or unsolicited pong, ignore it
+                        _ => {}
+                    },
+                    Some(Ok(message)) => {
+                        if channels.incoming.send(Ok(message)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "The peer closed the connection",
+                        ))
+                    }
+                },
+                message = channels.outgoing.recv() => match message {
+                    Some(message) => writer.send(message).await?,
+                    // the KeepAliveConnection was dropped
+                    None => return Ok(()),
+                },
+                _ = ticker.tick(), if pending.is_none() => {
+                    let nonce = rand::random();
+                    writer.send(BitcoinMessage::Ping(PingMessage { nonce })).await?;
+                    pending = Some((nonce, Instant::now()));
+                }
+                _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("No pong received within {:?}", timeout),
+                    ));
+                }
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        debug!("keepalive of {} stopped, reason: {}", address, e);
+        // the owner may be gone already
+        _ = channels.incoming.send(Err(e)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_listener::BitcoinListener, fake_node::regtest_config, network::Network,
+        BitcoinConnectionProtocol,
+    };
+
+    // a pair of connections that completed the handshake with each other
+    async fn connected_pair() -> Result<(Established, Established), Box<dyn std::error::Error>> {
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let outbound = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(listener.local_addr()?, Network::Regtest),
+            Arc::new(regtest_config()),
+        );
+        let (inbound, outbound) = tokio::join!(listener.accept(), outbound.connect());
+        Ok((outbound?, inbound?))
+    }
+
+    #[tokio::test]
+    async fn measure_round_trip_time_on_both_sides() -> Result<(), Box<dyn std::error::Error>> {
+        let (local, remote) = connected_pair().await?;
+        let local =
+            KeepAliveConnection::new(local, Duration::from_millis(20), Duration::from_secs(5));
+        let remote =
+            KeepAliveConnection::new(remote, Duration::from_millis(20), Duration::from_secs(5));
+
+        let mut round_trip_time = local.watch_round_trip_time();
+        time::timeout(
+            Duration::from_secs(5),
+            round_trip_time.wait_for(Option::is_some),
+        )
+        .await??;
+        let mut round_trip_time = remote.watch_round_trip_time();
+        time::timeout(
+            Duration::from_secs(5),
+            round_trip_time.wait_for(Option::is_some),
+        )
+        .await??;
+        assert!(local.round_trip_time().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answer_pings_and_forward_other_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (local, mut remote) = connected_pair().await?;
+        let mut local =
+            KeepAliveConnection::new(local, Duration::from_secs(60), Duration::from_secs(5));
+
+        // the first ping of the keepalive task is sent right away
+        assert!(matches!(
+            remote.next().await.transpose()?,
+            Some(BitcoinMessage::Ping(_))
+        ));
+        remote
+            .send(BitcoinMessage::Ping(PingMessage { nonce: 7 }))
+            .await?;
+        remote.send(BitcoinMessage::SendHeaders).await?;
+        assert_eq!(
+            remote.next().await.transpose()?,
+            Some(BitcoinMessage::Pong(PingMessage { nonce: 7 }))
+        );
+        // the ping was answered by the task, only the other message reaches the owner
+        assert_eq!(
+            local.next().await.transpose()?,
+            Some(BitcoinMessage::SendHeaders)
+        );
+
+        local.send(BitcoinMessage::GetAddr).await?;
+        assert_eq!(
+            remote.next().await.transpose()?,
+            Some(BitcoinMessage::GetAddr)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_when_pong_is_late() -> Result<(), Box<dyn std::error::Error>> {
+        // the remote side never answers the pings
+        let (local, _remote) = connected_pair().await?;
+        let mut local =
+            KeepAliveConnection::new(local, Duration::from_millis(10), Duration::from_millis(100));
+
+        let error = local
+            .next()
+            .await
+            .expect("the failure is reported")
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(local.next().await.is_none());
+        Ok(())
+    }
+}
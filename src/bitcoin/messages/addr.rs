@@ -0,0 +1,69 @@
+use bytes::{BufMut, BytesMut};
+use std::io::{Error, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::MAX_ADDR_COUNT,
+    payload::{decode_with, put_compact_size},
+    service_flags::ServiceFlags,
+    types::BitcoinIpAddr,
+};
+
+/// A network address as advertised by the addr message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedAddress {
+    // the last time the address was seen, in seconds since the epoch
+    pub timestamp: u32,
+    pub services: ServiceFlags,
+    pub ip: BitcoinIpAddr,
+    pub port: u16,
+}
+
+/// Represents the payload of the addr message.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AddrMessage {
+    pub addresses: Vec<TimestampedAddress>,
+}
+
+pub(crate) struct AddrCodec;
+
+impl Encoder<AddrMessage> for AddrCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: AddrMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_compact_size(dst, msg.addresses.len() as u64);
+        for address in msg.addresses {
+            dst.put_u32_le(address.timestamp);
+            dst.put_u64_le(address.services.bits());
+            dst.put_slice(&address.ip.encode());
+            // unlike the rest of the protocol, the port is in network byte order
+            dst.put_u16(address.port);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for AddrCodec {
+    type Item = AddrMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let count = reader.count("addresses", MAX_ADDR_COUNT)?;
+            let mut addresses = Vec::new();
+            for _ in 0..count {
+                let timestamp = reader.u32_le()?;
+                let services = ServiceFlags::from_bits(reader.u64_le()?);
+                let ip = BitcoinIpAddr::try_from_bytes(reader.bytes(16)?)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                addresses.push(TimestampedAddress {
+                    timestamp,
+                    services,
+                    ip,
+                    port: reader.u16_be()?,
+                });
+            }
+            Ok(AddrMessage { addresses })
+        })
+    }
+}
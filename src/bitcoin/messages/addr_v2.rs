@@ -0,0 +1,360 @@
+use bytes::{BufMut, BytesMut};
+use data_encoding::BASE32_NOPAD;
+use sha3::{Digest, Sha3_256};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    addr::TimestampedAddress,
+    limits::MAX_ADDR_COUNT,
+    payload::{decode_with, put_compact_size, put_var_bytes},
+    service_flags::ServiceFlags,
+    types::BitcoinIpAddr,
+};
+
+// BIP 155 bounds the address of every network, even the ones not known yet
+const MAX_ADDRESS_LENGTH: usize = 512;
+
+// The version byte and the checksum prefix of Tor v3 onion addresses
+const TOR_V3_VERSION: u8 = 3;
+const TOR_V3_CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+
+/// The address of a peer on any of the networks of BIP 155.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NetworkAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// The ed25519 public key of the onion service.
+    TorV3([u8; 32]),
+    /// The sha256 of the destination.
+    I2p([u8; 32]),
+    /// An IPv6 address in fc00::/8.
+    Cjdns(Ipv6Addr),
+    /// A network this implementation does not know, kept so it can be relayed.
+    Unknown {
+        network_id: u8,
+        address: Vec<u8>,
+    },
+}
+
+impl NetworkAddress {
+    /// The BIP 155 network id.
+    pub fn network_id(&self) -> u8 {
+        match self {
+            NetworkAddress::Ipv4(_) => 1,
+            NetworkAddress::Ipv6(_) => 2,
+            NetworkAddress::TorV3(_) => 4,
+            NetworkAddress::I2p(_) => 5,
+            NetworkAddress::Cjdns(_) => 6,
+            NetworkAddress::Unknown { network_id, .. } => *network_id,
+        }
+    }
+
+    /// The address reachable without a proxy, if any.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            NetworkAddress::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            NetworkAddress::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            NetworkAddress::Ipv4(ip) => ip.octets().to_vec(),
+            NetworkAddress::Ipv6(ip) | NetworkAddress::Cjdns(ip) => ip.octets().to_vec(),
+            NetworkAddress::TorV3(key) | NetworkAddress::I2p(key) => key.to_vec(),
+            NetworkAddress::Unknown { address, .. } => address.clone(),
+        }
+    }
+
+    // Validate the length of the address against its network, as required by BIP 155
+    fn from_bytes(network_id: u8, bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Invalid address of {} bytes for network {}",
+                    bytes.len(),
+                    network_id
+                ),
+            )
+        };
+        let address = match network_id {
+            1 => NetworkAddress::Ipv4(<[u8; 4]>::try_from(bytes).map_err(|_| invalid())?.into()),
+            2 => NetworkAddress::Ipv6(<[u8; 16]>::try_from(bytes).map_err(|_| invalid())?.into()),
+            // Tor v2 is deprecated, its 10 bytes are checked and kept like any other network
+            3 if bytes.len() != 10 => return Err(invalid()),
+            4 => NetworkAddress::TorV3(bytes.try_into().map_err(|_| invalid())?),
+            5 => NetworkAddress::I2p(bytes.try_into().map_err(|_| invalid())?),
+            6 => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(bytes).map_err(|_| invalid())?);
+                if ip.octets()[0] != 0xfc {
+                    return Err(invalid());
+                }
+                NetworkAddress::Cjdns(ip)
+            }
+            _ => NetworkAddress::Unknown {
+                network_id,
+                address: bytes.to_vec(),
+            },
+        };
+        Ok(address)
+    }
+}
+
+impl From<IpAddr> for NetworkAddress {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => NetworkAddress::Ipv4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => NetworkAddress::Ipv4(ip),
+                None => NetworkAddress::Ipv6(ip),
+            },
+        }
+    }
+}
+
+impl From<BitcoinIpAddr> for NetworkAddress {
+    fn from(ip: BitcoinIpAddr) -> Self {
+        IpAddr::from(ip).into()
+    }
+}
+
+fn tor_v3_checksum(key: &[u8; 32]) -> [u8; 2] {
+    let hash = Sha3_256::new()
+        .chain_update(TOR_V3_CHECKSUM_PREFIX)
+        .chain_update(key)
+        .chain_update([TOR_V3_VERSION])
+        .finalize();
+    [hash[0], hash[1]]
+}
+
+// The lowercase base32 used by Tor and I2P
+fn base32(bytes: &[u8]) -> String {
+    BASE32_NOPAD.encode(bytes).to_ascii_lowercase()
+}
+
+fn from_base32(value: &str) -> Option<Vec<u8>> {
+    BASE32_NOPAD
+        .decode(value.to_ascii_uppercase().as_bytes())
+        .ok()
+}
+
+impl fmt::Display for NetworkAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkAddress::Ipv4(ip) => write!(f, "{}", ip),
+            NetworkAddress::Ipv6(ip) | NetworkAddress::Cjdns(ip) => write!(f, "{}", ip),
+            NetworkAddress::TorV3(key) => {
+                let mut bytes = key.to_vec();
+                bytes.extend_from_slice(&tor_v3_checksum(key));
+                bytes.push(TOR_V3_VERSION);
+                write!(f, "{}.onion", base32(&bytes))
+            }
+            NetworkAddress::I2p(hash) => write!(f, "{}.b32.i2p", base32(hash)),
+            NetworkAddress::Unknown {
+                network_id,
+                address,
+            } => {
+                write!(f, "network-{}:", network_id)?;
+                address
+                    .iter()
+                    .try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+// Parse an IP address, a Tor v3 `.onion` or an I2P `.b32.i2p` host name
+impl FromStr for NetworkAddress {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network address: {}", value);
+        if let Some(encoded) = value.strip_suffix(".onion") {
+            let bytes = from_base32(encoded).ok_or_else(invalid)?;
+            let (key, suffix) = bytes.split_at_checked(32).ok_or_else(invalid)?;
+            let key: [u8; 32] = key.try_into().map_err(|_| invalid())?;
+            if suffix.len() != 3
+                || suffix[2] != TOR_V3_VERSION
+                || suffix[..2] != tor_v3_checksum(&key)
+            {
+                return Err(invalid());
+            }
+            return Ok(NetworkAddress::TorV3(key));
+        }
+        if let Some(encoded) = value.strip_suffix(".b32.i2p") {
+            let hash = from_base32(encoded).ok_or_else(invalid)?;
+            return Ok(NetworkAddress::I2p(hash.try_into().map_err(|_| invalid())?));
+        }
+        value
+            .parse::<IpAddr>()
+            .map(NetworkAddress::from)
+            .map_err(|_| invalid())
+    }
+}
+
+/// A network address as advertised by the addrv2 message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampedNetworkAddress {
+    // the last time the address was seen, in seconds since the epoch
+    pub timestamp: u32,
+    pub services: ServiceFlags,
+    pub address: NetworkAddress,
+    pub port: u16,
+}
+
+impl From<TimestampedAddress> for TimestampedNetworkAddress {
+    fn from(value: TimestampedAddress) -> Self {
+        TimestampedNetworkAddress {
+            timestamp: value.timestamp,
+            services: value.services,
+            address: value.ip.into(),
+            port: value.port,
+        }
+    }
+}
+
+/// Represents the payload of the addrv2 message (BIP 155).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AddrV2Message {
+    pub addresses: Vec<TimestampedNetworkAddress>,
+}
+
+pub(crate) struct AddrV2Codec;
+
+impl Encoder<AddrV2Message> for AddrV2Codec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: AddrV2Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_compact_size(dst, msg.addresses.len() as u64);
+        for address in msg.addresses {
+            dst.put_u32_le(address.timestamp);
+            // unlike addr, the services are a CompactSize
+            put_compact_size(dst, address.services.bits());
+            dst.put_u8(address.address.network_id());
+            put_var_bytes(dst, &address.address.bytes());
+            dst.put_u16(address.port);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for AddrV2Codec {
+    type Item = AddrV2Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let count = reader.count("addresses", MAX_ADDR_COUNT)?;
+            let mut addresses = Vec::new();
+            for _ in 0..count {
+                let timestamp = reader.u32_le()?;
+                let services = ServiceFlags::from_bits(reader.compact_size()?);
+                let network_id = reader.u8()?;
+                let length = reader.compact_size()?;
+                if length > MAX_ADDRESS_LENGTH as u64 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Address of {} bytes exceeds the maximum", length),
+                    ));
+                }
+                let address =
+                    NetworkAddress::from_bytes(network_id, reader.bytes(length as usize)?)?;
+                addresses.push(TimestampedNetworkAddress {
+                    timestamp,
+                    services,
+                    address,
+                    port: reader.u16_be()?,
+                });
+            }
+            Ok(AddrV2Message { addresses })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // from the test vectors of Bitcoin Core
+    const ONION: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+    const I2P: &str = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+
+    #[test]
+    fn parse_and_display_host_names() {
+        for name in [ONION, I2P, "10.0.0.1", "2001:db8::1"] {
+            let address: NetworkAddress = name.parse().unwrap();
+            assert_eq!(address.to_string(), name);
+        }
+        assert!(matches!(ONION.parse(), Ok(NetworkAddress::TorV3(_))));
+        assert!(matches!(I2P.parse(), Ok(NetworkAddress::I2p(_))));
+        // a single changed character breaks the checksum
+        let corrupted = ONION.replacen('p', "q", 1);
+        assert!(corrupted.parse::<NetworkAddress>().is_err());
+    }
+
+    #[test]
+    fn decode_payload_of_bip155() {
+        // an IPv4 entry followed by an entry of a future network
+        let payload = [
+            0x02, // count
+            0x00, 0x00, 0x00, 0x00, // timestamp
+            0x01, // services
+            0x01, 0x04, 0x01, 0x02, 0x03, 0x04, // IPv4 1.2.3.4
+            0x20, 0x8d, // port 8333
+            0x00, 0x00, 0x00, 0x00, // timestamp
+            0xfd, 0x09, 0x04, // services, NODE_NETWORK | NODE_WITNESS | NODE_NETWORK_LIMITED
+            0x63, 0x02, 0xab, 0xcd, // unknown network 99
+            0x00, 0x00, // port
+        ];
+        let message = AddrV2Codec
+            .decode(&mut BytesMut::from(&payload[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message.addresses[0].address,
+            NetworkAddress::Ipv4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert_eq!(message.addresses[0].port, 8333);
+        assert_eq!(
+            message.addresses[1].services,
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED
+        );
+        assert_eq!(
+            message.addresses[1].address,
+            NetworkAddress::Unknown {
+                network_id: 99,
+                address: vec![0xab, 0xcd]
+            }
+        );
+
+        let mut encoded = BytesMut::new();
+        AddrV2Codec.encode(message, &mut encoded).unwrap();
+        assert_eq!(encoded.as_ref(), &payload[..]);
+    }
+
+    #[test]
+    fn reject_address_of_wrong_length() {
+        // an IPv4 address of 5 bytes
+        let payload = [0x01, 0, 0, 0, 0, 0x01, 0x01, 0x05, 1, 2, 3, 4, 5, 0, 0];
+        let error = AddrV2Codec
+            .decode(&mut BytesMut::from(&payload[..]))
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // CJDNS addresses are in fc00::/8
+        let mut payload = vec![0x01, 0, 0, 0, 0, 0x01, 0x06, 0x10];
+        payload.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        payload.extend_from_slice(&[0, 0]);
+        assert!(AddrV2Codec
+            .decode(&mut BytesMut::from(&payload[..]))
+            .is_err());
+    }
+}
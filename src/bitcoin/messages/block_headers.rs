@@ -0,0 +1,79 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::MAX_HEADERS_COUNT,
+    payload::{decode_with, put_compact_size, PayloadReader},
+};
+
+/// The 80 bytes header of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub(crate) fn encode(&self, dst: &mut BytesMut) {
+        dst.put_i32_le(self.version);
+        dst.put_slice(&self.prev_block);
+        dst.put_slice(&self.merkle_root);
+        dst.put_u32_le(self.timestamp);
+        dst.put_u32_le(self.bits);
+        dst.put_u32_le(self.nonce);
+    }
+
+    pub(crate) fn read(reader: &mut PayloadReader) -> std::io::Result<Self> {
+        Ok(BlockHeader {
+            version: reader.i32_le()?,
+            prev_block: reader.hash()?,
+            merkle_root: reader.hash()?,
+            timestamp: reader.u32_le()?,
+            bits: reader.u32_le()?,
+            nonce: reader.u32_le()?,
+        })
+    }
+}
+
+/// Represents the payload of the headers message.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HeadersMessage {
+    pub headers: Vec<BlockHeader>,
+}
+
+pub(crate) struct HeadersCodec;
+
+impl Encoder<HeadersMessage> for HeadersCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: HeadersMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_compact_size(dst, msg.headers.len() as u64);
+        for header in msg.headers {
+            header.encode(dst);
+            // every header is followed by a transaction count, which is always zero
+            put_compact_size(dst, 0);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for HeadersCodec {
+    type Item = HeadersMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let count = reader.count("headers", MAX_HEADERS_COUNT)?;
+            let mut headers = Vec::new();
+            for _ in 0..count {
+                headers.push(BlockHeader::read(reader)?);
+                reader.compact_size()?;
+            }
+            Ok(HeadersMessage { headers })
+        })
+    }
+}
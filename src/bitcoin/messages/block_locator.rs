@@ -0,0 +1,51 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::MAX_LOCATOR_COUNT,
+    payload::{decode_with, put_hashes},
+};
+
+/// Represents the payload of the getblocks and getheaders messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLocatorMessage {
+    pub version: u32,
+    // block hashes from the tip backwards, the peer replies from the first one it knows
+    pub locator_hashes: Vec<[u8; 32]>,
+    // the last block to send, all zeros to get as many as allowed
+    pub hash_stop: [u8; 32],
+}
+
+pub(crate) struct BlockLocatorCodec;
+
+impl Encoder<BlockLocatorMessage> for BlockLocatorCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: BlockLocatorMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u32_le(msg.version);
+        put_hashes(dst, &msg.locator_hashes);
+        dst.put_slice(&msg.hash_stop);
+        Ok(())
+    }
+}
+
+impl Decoder for BlockLocatorCodec {
+    type Item = BlockLocatorMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let version = reader.u32_le()?;
+            let count = reader.count("locator hashes", MAX_LOCATOR_COUNT)?;
+            let mut locator_hashes = Vec::new();
+            for _ in 0..count {
+                locator_hashes.push(reader.hash()?);
+            }
+            Ok(BlockLocatorMessage {
+                version,
+                locator_hashes,
+                hash_stop: reader.hash()?,
+            })
+        })
+    }
+}
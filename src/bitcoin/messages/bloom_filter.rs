@@ -0,0 +1,88 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::{DecodeError, MAX_BLOOM_FILTER_SIZE, MAX_BLOOM_HASH_FUNCTIONS, MAX_FILTER_ADD_SIZE},
+    payload::{decode_with, put_var_bytes},
+};
+
+/// Represents the payload of the filterload message (BIP 37).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterLoadMessage {
+    pub filter: Vec<u8>,
+    pub hash_functions: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+/// Represents the payload of the filteradd message (BIP 37).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterAddMessage {
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct FilterLoadCodec;
+
+impl Encoder<FilterLoadMessage> for FilterLoadCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: FilterLoadMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_var_bytes(dst, &msg.filter);
+        dst.put_u32_le(msg.hash_functions);
+        dst.put_u32_le(msg.tweak);
+        dst.put_u8(msg.flags);
+        Ok(())
+    }
+}
+
+impl Decoder for FilterLoadCodec {
+    type Item = FilterLoadMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let size = reader.count("filter bytes", MAX_BLOOM_FILTER_SIZE)?;
+            let filter = reader.bytes(size as usize)?.to_vec();
+            let hash_functions = reader.u32_le()?;
+            if hash_functions > MAX_BLOOM_HASH_FUNCTIONS {
+                return Err(DecodeError::TooManyItems {
+                    items: "hash functions",
+                    count: hash_functions as u64,
+                    max: MAX_BLOOM_HASH_FUNCTIONS as usize,
+                }
+                .into());
+            }
+            Ok(FilterLoadMessage {
+                filter,
+                hash_functions,
+                tweak: reader.u32_le()?,
+                flags: reader.u8()?,
+            })
+        })
+    }
+}
+
+pub(crate) struct FilterAddCodec;
+
+impl Encoder<FilterAddMessage> for FilterAddCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: FilterAddMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_var_bytes(dst, &msg.data);
+        Ok(())
+    }
+}
+
+impl Decoder for FilterAddCodec {
+    type Item = FilterAddMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let size = reader.count("filteradd bytes", MAX_FILTER_ADD_SIZE)?;
+            Ok(FilterAddMessage {
+                data: reader.bytes(size as usize)?.to_vec(),
+            })
+        })
+    }
+}
@@ -0,0 +1,52 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::payload::decode_with;
+
+/// Represents a BIP 133 feefilter message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeFilterMessage {
+    // transactions below this fee rate (satoshis per kilobyte) should not be announced to the peer
+    pub fee_rate: i64,
+}
+
+pub(crate) struct FeeFilterCodec;
+
+impl Encoder<FeeFilterMessage> for FeeFilterCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: FeeFilterMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_i64_le(msg.fee_rate);
+        Ok(())
+    }
+}
+
+impl Decoder for FeeFilterCodec {
+    type Item = FeeFilterMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            Ok(FeeFilterMessage {
+                fee_rate: reader.i64_le()?,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_fee_filter() {
+        let expected_message = FeeFilterMessage { fee_rate: 1000 };
+        let mut codec = FeeFilterCodec;
+        let mut bytes = BytesMut::new();
+        codec.encode(expected_message, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 8);
+
+        let decoded_message = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(expected_message, decoded_message);
+    }
+}
@@ -0,0 +1,67 @@
+use bytes::{BufMut, BytesMut};
+use std::io::{Error, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::MAX_BLOCK_TRANSACTIONS,
+    payload::{decode_with, put_compact_size},
+};
+
+/// Represents the payload of the getblocktxn message (BIP 152).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetBlockTxnMessage {
+    pub block_hash: [u8; 32],
+    // absolute transaction indexes in ascending order, differentially encoded on the wire
+    pub indexes: Vec<u64>,
+}
+
+pub(crate) struct GetBlockTxnCodec;
+
+impl Encoder<GetBlockTxnMessage> for GetBlockTxnCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: GetBlockTxnMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_slice(&msg.block_hash);
+        put_compact_size(dst, msg.indexes.len() as u64);
+        let mut next_index = 0;
+        for index in msg.indexes {
+            if index < next_index {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "getblocktxn indexes must be unique and ascending",
+                ));
+            }
+            put_compact_size(dst, index - next_index);
+            next_index = index + 1;
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for GetBlockTxnCodec {
+    type Item = GetBlockTxnMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let block_hash = reader.hash()?;
+            let count = reader.count("transaction indexes", MAX_BLOCK_TRANSACTIONS)?;
+            let mut indexes = Vec::new();
+            let mut next_index: u64 = 0;
+            for _ in 0..count {
+                let index = next_index
+                    .checked_add(reader.compact_size()?)
+                    .ok_or(Error::new(
+                        ErrorKind::InvalidData,
+                        "getblocktxn index overflow",
+                    ))?;
+                indexes.push(index);
+                next_index = index + 1;
+            }
+            Ok(GetBlockTxnMessage {
+                block_hash,
+                indexes,
+            })
+        })
+    }
+}
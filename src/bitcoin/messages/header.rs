@@ -1,14 +1,15 @@
 use bytes::{Buf, BufMut, BytesMut};
 use std::io::{self, Error, ErrorKind};
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{error, warn};
+use tracing::warn;
 
-use crate::HEADER_LENGTH;
+use crate::{bitcoin::network::Network, HEADER_LENGTH};
 
-use super::{commands::Command, sha2_checksum};
-
-const MAINNET_MAGIC: u32 = 0xD9B4BEF9;
-const TESTNET_MAGIC: u32 = 0x0709110B;
+use super::{
+    commands::Command,
+    limits::{DecodeError, MAX_MESSAGE_SIZE},
+    sha2_checksum,
+};
 
 // message header for all messages type
 #[derive(Debug)]
@@ -20,42 +21,29 @@ pub(crate) struct HeaderMessage {
 }
 
 impl HeaderMessage {
-    pub fn new(command: Command, payload_buffer: &BytesMut) -> Self {
+    pub fn new(network: Network, command: Command, payload_buffer: &BytesMut) -> Self {
         let checksum = sha2_checksum(payload_buffer);
         let payload_length = payload_buffer.len() as u32;
         HeaderMessage {
             command,
             payload_length,
-            magic: Self::get_magic(),
+            magic: network.magic(),
             checksum,
         }
     }
+}
 
-    pub fn new_without_payload(command: Command, checksum: [u8; 4]) -> Self {
-        match command {
-            Command::VerAck => {}
-            _ => error!(
-                "command {} must initialize with payload, use the other new() fn",
-                command
-            ),
-        }
-        HeaderMessage {
-            command,
-            payload_length: 0,
-            magic: Self::get_magic(),
-            checksum,
-        }
-    }
+// Encode and decode headers of a single network, headers with the magic of any other network are rejected
+pub(crate) struct HeaderCodec {
+    network: Network,
+}
 
-    fn get_magic() -> u32 {
-        MAINNET_MAGIC
-        // todo!("read from configuration, decide if mainnet or testnet");
-        // TESTNET_MAGIC
+impl HeaderCodec {
+    pub fn new(network: Network) -> Self {
+        HeaderCodec { network }
     }
 }
 
-pub(crate) struct HeaderCodec;
-
 impl Decoder for HeaderCodec {
     type Item = HeaderMessage;
     type Error = io::Error;
@@ -78,8 +66,24 @@ impl Decoder for HeaderCodec {
         let mut checksum = [0u8; 4];
         src.copy_to_slice(&mut checksum);
 
-        if magic != MAINNET_MAGIC && magic != TESTNET_MAGIC {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid magic number"));
+        if magic != self.network.magic() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                match Network::from_magic(magic) {
+                    Some(other) => format!(
+                        "Invalid magic number, the peer is on {} instead of {}",
+                        other, self.network
+                    ),
+                    None => format!("Invalid magic number: {:#010x}", magic),
+                },
+            ));
+        }
+        if payload_length as usize > MAX_MESSAGE_SIZE {
+            return Err(DecodeError::MessageTooLarge {
+                size: payload_length as usize,
+                max: MAX_MESSAGE_SIZE,
+            }
+            .into());
         }
         Ok(Some(HeaderMessage {
             magic,
@@ -99,7 +103,7 @@ impl Encoder<HeaderMessage> for HeaderCodec {
     type Error = io::Error;
 
     fn encode(&mut self, item: HeaderMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        if item.magic != MAINNET_MAGIC && item.magic != TESTNET_MAGIC {
+        if item.magic != self.network.magic() {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
                 "Invalid magic number for encoding",
@@ -123,16 +127,16 @@ mod tests {
 
     #[test]
     fn decode_valid_header() {
-        let mut codec = HeaderCodec;
+        let mut codec = HeaderCodec::new(Network::Mainnet);
         let mut buf = BytesMut::new();
-        buf.extend_from_slice(&MAINNET_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&Network::Mainnet.magic().to_le_bytes());
         buf.extend_from_slice(b"version\x00\x00\x00\x00\x00");
         buf.extend_from_slice(&100u32.to_le_bytes());
         buf.extend_from_slice(&[0xAB; 4]);
 
         match codec.decode(&mut buf) {
             Ok(Some(header)) => {
-                assert_eq!(header.magic, MAINNET_MAGIC);
+                assert_eq!(header.magic, Network::Mainnet.magic());
             }
             Ok(None) => panic!("Failed to decode a valid header, buffer to short"),
             Err(e) => panic!("Failed to decode a valid header {:?}", e),
@@ -141,7 +145,7 @@ mod tests {
 
     #[test]
     fn decode_invalid_magic() {
-        let mut codec = HeaderCodec;
+        let mut codec = HeaderCodec::new(Network::Mainnet);
         let mut buf = BytesMut::new();
         buf.extend_from_slice(&0x12345678u32.to_le_bytes());
         buf.extend_from_slice(b"version\x00\x00\x00\x00\x00");
@@ -155,4 +159,21 @@ mod tests {
             _ => panic!("Expected an error due to invalid magic number"),
         }
     }
+
+    #[test]
+    fn decode_magic_of_other_network() {
+        let mut codec = HeaderCodec::new(Network::Regtest);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&Network::Mainnet.magic().to_le_bytes());
+        buf.extend_from_slice(b"verack\x00\x00\x00\x00\x00\x00");
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&[0xAB; 4]);
+
+        match codec.decode(&mut buf) {
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::InvalidData);
+            }
+            _ => panic!("Expected an error due to magic number of another network"),
+        }
+    }
 }
@@ -0,0 +1,100 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    limits::MAX_INV_COUNT,
+    payload::{decode_with, put_compact_size},
+};
+
+/// The type of object an inventory vector refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InventoryType {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CompactBlock,
+    WitnessTx,
+    WitnessBlock,
+    WitnessFilteredBlock,
+    Unknown(u32),
+}
+
+impl From<u32> for InventoryType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => InventoryType::Error,
+            1 => InventoryType::Tx,
+            2 => InventoryType::Block,
+            3 => InventoryType::FilteredBlock,
+            4 => InventoryType::CompactBlock,
+            0x40000001 => InventoryType::WitnessTx,
+            0x40000002 => InventoryType::WitnessBlock,
+            0x40000003 => InventoryType::WitnessFilteredBlock,
+            other => InventoryType::Unknown(other),
+        }
+    }
+}
+
+impl From<InventoryType> for u32 {
+    fn from(value: InventoryType) -> Self {
+        match value {
+            InventoryType::Error => 0,
+            InventoryType::Tx => 1,
+            InventoryType::Block => 2,
+            InventoryType::FilteredBlock => 3,
+            InventoryType::CompactBlock => 4,
+            InventoryType::WitnessTx => 0x40000001,
+            InventoryType::WitnessBlock => 0x40000002,
+            InventoryType::WitnessFilteredBlock => 0x40000003,
+            InventoryType::Unknown(other) => other,
+        }
+    }
+}
+
+/// A single inventory vector entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inventory {
+    pub inventory_type: InventoryType,
+    pub hash: [u8; 32],
+}
+
+/// Represents the payload of the inv, getdata and notfound messages.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InventoryMessage {
+    pub inventory: Vec<Inventory>,
+}
+
+pub(crate) struct InventoryCodec;
+
+impl Encoder<InventoryMessage> for InventoryCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: InventoryMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_compact_size(dst, msg.inventory.len() as u64);
+        for inventory in msg.inventory {
+            dst.put_u32_le(inventory.inventory_type.into());
+            dst.put_slice(&inventory.hash);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for InventoryCodec {
+    type Item = InventoryMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let count = reader.count("inventory entries", MAX_INV_COUNT)?;
+            let mut inventory = Vec::new();
+            for _ in 0..count {
+                inventory.push(Inventory {
+                    inventory_type: reader.u32_le()?.into(),
+                    hash: reader.hash()?,
+                });
+            }
+            Ok(InventoryMessage { inventory })
+        })
+    }
+}
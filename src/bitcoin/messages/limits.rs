@@ -0,0 +1,71 @@
+use std::io::{self, ErrorKind};
+
+use thiserror::Error;
+
+// The limits enforced while decoding, so a peer cannot make us allocate or loop beyond them
+
+/// The largest payload accepted, checked from the header before the payload is read.
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+/// The longest user agent accepted in a version message.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// The most addresses in an addr or addrv2 message.
+pub const MAX_ADDR_COUNT: usize = 1000;
+
+/// The most entries in an inv, getdata or notfound message.
+pub const MAX_INV_COUNT: usize = 50_000;
+
+/// The most block headers in a headers message.
+pub const MAX_HEADERS_COUNT: usize = 2000;
+
+/// The most hashes in the locator of a getblocks or getheaders message.
+pub const MAX_LOCATOR_COUNT: usize = 101;
+
+/// The most transactions a block can hold, its weight limit over the weight of the smallest transaction.
+/// Bounds the indexes of a getblocktxn message and the hashes of a merkleblock message.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 4_000_000 / 240;
+
+/// The largest filter of a filterload message, in bytes (BIP 37).
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// The most hash functions of a filterload message (BIP 37).
+pub const MAX_BLOOM_HASH_FUNCTIONS: u32 = 50;
+
+/// The largest data of a filteradd message, the largest element a script can push (BIP 37).
+pub const MAX_FILTER_ADD_SIZE: usize = 520;
+
+/// A message rejected while decoding because it breaks one of the limits or encoding rules.
+///
+/// Decoders report it inside an `io::Error` of kind `InvalidData`, retrieve it with `DecodeError::from_io`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DecodeError {
+    #[error("Payload of {size} bytes exceeds the maximum of {max}")]
+    MessageTooLarge { size: usize, max: usize },
+
+    #[error("User agent of {length} bytes exceeds the maximum of {max}")]
+    UserAgentTooLong { length: u64, max: usize },
+
+    #[error("{count} {items} exceed the maximum of {max}")]
+    TooManyItems {
+        items: &'static str,
+        count: u64,
+        max: usize,
+    },
+
+    #[error("CompactSize {0} is not encoded in its shortest form")]
+    NonCanonicalCompactSize(u64),
+}
+
+impl DecodeError {
+    // The decode error carried by an io error, if any
+    pub fn from_io(error: &io::Error) -> Option<&DecodeError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(value: DecodeError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, value)
+    }
+}
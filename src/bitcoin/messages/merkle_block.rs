@@ -0,0 +1,54 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    block_headers::BlockHeader,
+    limits::MAX_BLOCK_TRANSACTIONS,
+    payload::{decode_with, put_hashes, put_var_bytes},
+};
+
+/// Represents the payload of the merkleblock message (BIP 37).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlockMessage {
+    pub header: BlockHeader,
+    pub total_transactions: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<u8>,
+}
+
+pub(crate) struct MerkleBlockCodec;
+
+impl Encoder<MerkleBlockMessage> for MerkleBlockCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: MerkleBlockMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        msg.header.encode(dst);
+        dst.put_u32_le(msg.total_transactions);
+        put_hashes(dst, &msg.hashes);
+        put_var_bytes(dst, &msg.flags);
+        Ok(())
+    }
+}
+
+impl Decoder for MerkleBlockCodec {
+    type Item = MerkleBlockMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            let header = BlockHeader::read(reader)?;
+            let total_transactions = reader.u32_le()?;
+            let count = reader.count("transaction hashes", MAX_BLOCK_TRANSACTIONS)?;
+            let mut hashes = Vec::new();
+            for _ in 0..count {
+                hashes.push(reader.hash()?);
+            }
+            Ok(MerkleBlockMessage {
+                header,
+                total_transactions,
+                hashes,
+                flags: reader.var_bytes()?.to_vec(),
+            })
+        })
+    }
+}
@@ -0,0 +1,600 @@
+use bytes::{Bytes, BytesMut};
+use std::io::{self, Error, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{bitcoin::network::Network, HEADER_LENGTH};
+
+use super::{
+    addr::{AddrCodec, AddrMessage},
+    addr_v2::{AddrV2Codec, AddrV2Message},
+    block_headers::{HeadersCodec, HeadersMessage},
+    block_locator::{BlockLocatorCodec, BlockLocatorMessage},
+    bloom_filter::{FilterAddCodec, FilterAddMessage, FilterLoadCodec, FilterLoadMessage},
+    commands::Command,
+    fee_filter::{FeeFilterCodec, FeeFilterMessage},
+    get_block_txn::{GetBlockTxnCodec, GetBlockTxnMessage},
+    header::{HeaderCodec, HeaderMessage},
+    inventory::{InventoryCodec, InventoryMessage},
+    limits::{DecodeError, MAX_MESSAGE_SIZE},
+    merkle_block::{MerkleBlockCodec, MerkleBlockMessage},
+    ping::{PingCodec, PingMessage},
+    reject::{RejectCodec, RejectMessage},
+    send_compact::{SendCompactCodec, SendCompactMessage},
+    sha2_checksum,
+    version::{VersionCodec, VersionMessage},
+};
+
+/// A complete Bitcoin P2P message, one variant per `Command`.
+///
+/// Messages that carry transactions or blocks keep their raw serialized payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BitcoinMessage {
+    Version(VersionMessage),
+    VerAck,
+    Addr(AddrMessage),
+    Inv(InventoryMessage),
+    GetData(InventoryMessage),
+    NotFound(InventoryMessage),
+    GetBlocks(BlockLocatorMessage),
+    GetHeaders(BlockLocatorMessage),
+    Tx(Bytes),
+    Block(Bytes),
+    Headers(HeadersMessage),
+    GetAddr,
+    MemPool,
+    CheckOrder(Bytes),
+    SubmitOrder(Bytes),
+    Reply(Bytes),
+    Ping(PingMessage),
+    Pong(PingMessage),
+    Reject(RejectMessage),
+    FilterLoad(FilterLoadMessage),
+    FilterAdd(FilterAddMessage),
+    FilterClear,
+    MerkleBlock(MerkleBlockMessage),
+    Alert(Bytes),
+    SendHeaders,
+    FeeFilter(FeeFilterMessage),
+    SendCmpct(SendCompactMessage),
+    CmpctBlock(Bytes),
+    GetBlockTxn(GetBlockTxnMessage),
+    BlockTxn(Bytes),
+    WtxidRelay,
+    SendAddrV2,
+    AddrV2(AddrV2Message),
+}
+
+impl BitcoinMessage {
+    pub fn command(&self) -> Command {
+        match self {
+            BitcoinMessage::Version(_) => Command::Version,
+            BitcoinMessage::VerAck => Command::VerAck,
+            BitcoinMessage::Addr(_) => Command::Addr,
+            BitcoinMessage::Inv(_) => Command::Inv,
+            BitcoinMessage::GetData(_) => Command::GetData,
+            BitcoinMessage::NotFound(_) => Command::NotFound,
+            BitcoinMessage::GetBlocks(_) => Command::GetBlocks,
+            BitcoinMessage::GetHeaders(_) => Command::GetHeaders,
+            BitcoinMessage::Tx(_) => Command::Tx,
+            BitcoinMessage::Block(_) => Command::Block,
+            BitcoinMessage::Headers(_) => Command::Headers,
+            BitcoinMessage::GetAddr => Command::GetAddr,
+            BitcoinMessage::MemPool => Command::MemPool,
+            BitcoinMessage::CheckOrder(_) => Command::CheckOrder,
+            BitcoinMessage::SubmitOrder(_) => Command::SubmitOrder,
+            BitcoinMessage::Reply(_) => Command::Reply,
+            BitcoinMessage::Ping(_) => Command::Ping,
+            BitcoinMessage::Pong(_) => Command::Pong,
+            BitcoinMessage::Reject(_) => Command::Reject,
+            BitcoinMessage::FilterLoad(_) => Command::FilterLoad,
+            BitcoinMessage::FilterAdd(_) => Command::FilterAdd,
+            BitcoinMessage::FilterClear => Command::FilterClear,
+            BitcoinMessage::MerkleBlock(_) => Command::MerkleBlock,
+            BitcoinMessage::Alert(_) => Command::Alert,
+            BitcoinMessage::SendHeaders => Command::SendHeaders,
+            BitcoinMessage::FeeFilter(_) => Command::FeeFilter,
+            BitcoinMessage::SendCmpct(_) => Command::SendCmpct,
+            BitcoinMessage::CmpctBlock(_) => Command::CmpctBlock,
+            BitcoinMessage::GetBlockTxn(_) => Command::GetBlockTxn,
+            BitcoinMessage::BlockTxn(_) => Command::BlockTxn,
+            BitcoinMessage::WtxidRelay => Command::WtxidRelay,
+            BitcoinMessage::SendAddrV2 => Command::SendAddrV2,
+            BitcoinMessage::AddrV2(_) => Command::AddrV2,
+        }
+    }
+
+    /// Serialize the payload of the message, without the header.
+    pub(crate) fn encode_payload(self, dst: &mut BytesMut) -> io::Result<()> {
+        match self {
+            BitcoinMessage::Version(msg) => VersionCodec.encode(msg, dst),
+            BitcoinMessage::Addr(msg) => AddrCodec.encode(msg, dst),
+            BitcoinMessage::AddrV2(msg) => AddrV2Codec.encode(msg, dst),
+            BitcoinMessage::Inv(msg)
+            | BitcoinMessage::GetData(msg)
+            | BitcoinMessage::NotFound(msg) => InventoryCodec.encode(msg, dst),
+            BitcoinMessage::GetBlocks(msg) | BitcoinMessage::GetHeaders(msg) => {
+                BlockLocatorCodec.encode(msg, dst)
+            }
+            BitcoinMessage::Headers(msg) => HeadersCodec.encode(msg, dst),
+            BitcoinMessage::Ping(msg) | BitcoinMessage::Pong(msg) => PingCodec.encode(msg, dst),
+            BitcoinMessage::Reject(msg) => RejectCodec.encode(msg, dst),
+            BitcoinMessage::FilterLoad(msg) => FilterLoadCodec.encode(msg, dst),
+            BitcoinMessage::FilterAdd(msg) => FilterAddCodec.encode(msg, dst),
+            BitcoinMessage::MerkleBlock(msg) => MerkleBlockCodec.encode(msg, dst),
+            BitcoinMessage::FeeFilter(msg) => FeeFilterCodec.encode(msg, dst),
+            BitcoinMessage::SendCmpct(msg) => SendCompactCodec.encode(msg, dst),
+            BitcoinMessage::GetBlockTxn(msg) => GetBlockTxnCodec.encode(msg, dst),
+            BitcoinMessage::Tx(raw)
+            | BitcoinMessage::Block(raw)
+            | BitcoinMessage::CheckOrder(raw)
+            | BitcoinMessage::SubmitOrder(raw)
+            | BitcoinMessage::Reply(raw)
+            | BitcoinMessage::Alert(raw)
+            | BitcoinMessage::CmpctBlock(raw)
+            | BitcoinMessage::BlockTxn(raw) => {
+                dst.extend_from_slice(&raw);
+                Ok(())
+            }
+            BitcoinMessage::VerAck
+            | BitcoinMessage::GetAddr
+            | BitcoinMessage::MemPool
+            | BitcoinMessage::FilterClear
+            | BitcoinMessage::SendHeaders
+            | BitcoinMessage::WtxidRelay
+            | BitcoinMessage::SendAddrV2 => Ok(()),
+        }
+    }
+
+    /// Decode a complete payload, already verified against the header checksum, for the given command.
+    pub(crate) fn decode_payload(command: Command, mut payload: BytesMut) -> io::Result<Self> {
+        let payload = &mut payload;
+        let message = match command {
+            Command::Version => BitcoinMessage::Version(decode_complete(VersionCodec, payload)?),
+            Command::VerAck => BitcoinMessage::VerAck,
+            Command::Addr => BitcoinMessage::Addr(decode_complete(AddrCodec, payload)?),
+            Command::Inv => BitcoinMessage::Inv(decode_complete(InventoryCodec, payload)?),
+            Command::GetData => BitcoinMessage::GetData(decode_complete(InventoryCodec, payload)?),
+            Command::NotFound => {
+                BitcoinMessage::NotFound(decode_complete(InventoryCodec, payload)?)
+            }
+            Command::GetBlocks => {
+                BitcoinMessage::GetBlocks(decode_complete(BlockLocatorCodec, payload)?)
+            }
+            Command::GetHeaders => {
+                BitcoinMessage::GetHeaders(decode_complete(BlockLocatorCodec, payload)?)
+            }
+            Command::Tx => BitcoinMessage::Tx(payload.split().freeze()),
+            Command::Block => BitcoinMessage::Block(payload.split().freeze()),
+            Command::Headers => BitcoinMessage::Headers(decode_complete(HeadersCodec, payload)?),
+            Command::GetAddr => BitcoinMessage::GetAddr,
+            Command::MemPool => BitcoinMessage::MemPool,
+            Command::CheckOrder => BitcoinMessage::CheckOrder(payload.split().freeze()),
+            Command::SubmitOrder => BitcoinMessage::SubmitOrder(payload.split().freeze()),
+            Command::Reply => BitcoinMessage::Reply(payload.split().freeze()),
+            Command::Ping => BitcoinMessage::Ping(decode_complete(PingCodec, payload)?),
+            Command::Pong => BitcoinMessage::Pong(decode_complete(PingCodec, payload)?),
+            Command::Reject => BitcoinMessage::Reject(decode_complete(RejectCodec, payload)?),
+            Command::FilterLoad => {
+                BitcoinMessage::FilterLoad(decode_complete(FilterLoadCodec, payload)?)
+            }
+            Command::FilterAdd => {
+                BitcoinMessage::FilterAdd(decode_complete(FilterAddCodec, payload)?)
+            }
+            Command::FilterClear => BitcoinMessage::FilterClear,
+            Command::MerkleBlock => {
+                BitcoinMessage::MerkleBlock(decode_complete(MerkleBlockCodec, payload)?)
+            }
+            Command::Alert => BitcoinMessage::Alert(payload.split().freeze()),
+            Command::SendHeaders => BitcoinMessage::SendHeaders,
+            Command::FeeFilter => {
+                BitcoinMessage::FeeFilter(decode_complete(FeeFilterCodec, payload)?)
+            }
+            Command::SendCmpct => {
+                BitcoinMessage::SendCmpct(decode_complete(SendCompactCodec, payload)?)
+            }
+            Command::CmpctBlock => BitcoinMessage::CmpctBlock(payload.split().freeze()),
+            Command::GetBlockTxn => {
+                BitcoinMessage::GetBlockTxn(decode_complete(GetBlockTxnCodec, payload)?)
+            }
+            Command::BlockTxn => BitcoinMessage::BlockTxn(payload.split().freeze()),
+            Command::WtxidRelay => BitcoinMessage::WtxidRelay,
+            Command::SendAddrV2 => BitcoinMessage::SendAddrV2,
+            Command::AddrV2 => BitcoinMessage::AddrV2(decode_complete(AddrV2Codec, payload)?),
+        };
+        Ok(message)
+    }
+}
+
+// The payload is complete, so a decoder waiting for more bytes means the payload is truncated
+fn decode_complete<C: Decoder<Error = io::Error>>(
+    mut codec: C,
+    payload: &mut BytesMut,
+) -> io::Result<C::Item> {
+    codec.decode(payload)?.ok_or(Error::new(
+        ErrorKind::InvalidData,
+        "Payload is shorter than the message requires",
+    ))
+}
+
+/// Frames complete messages: the header followed by its payload.
+///
+/// The checksum of every payload is verified before it is decoded,
+/// and messages that do not start with the magic of the network are rejected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BitcoinCodec {
+    network: Network,
+}
+
+impl BitcoinCodec {
+    pub fn new(network: Network) -> Self {
+        BitcoinCodec { network }
+    }
+}
+
+impl Decoder for BitcoinCodec {
+    type Item = BitcoinMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+        // peek the payload length so nothing is consumed before the whole message arrived
+        let payload_length = u32::from_le_bytes([src[16], src[17], src[18], src[19]]) as usize;
+        // checked before reserving room for the payload
+        if payload_length > MAX_MESSAGE_SIZE {
+            return Err(DecodeError::MessageTooLarge {
+                size: payload_length,
+                max: MAX_MESSAGE_SIZE,
+            }
+            .into());
+        }
+        if src.len() < HEADER_LENGTH + payload_length {
+            src.reserve(HEADER_LENGTH + payload_length - src.len());
+            return Ok(None);
+        }
+
+        let header = HeaderCodec::new(self.network)
+            .decode(src)?
+            .ok_or(Error::new(ErrorKind::InvalidData, "Cannot decode header"))?;
+        let payload = src.split_to(payload_length);
+
+        let computed_checksum = sha2_checksum(&payload);
+        if computed_checksum != header.checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Invalid checksum for {}. The received header checksum: {:?} not equal to the computed actual payload checksum: {:?}",
+                    header.command, header.checksum, computed_checksum
+                ),
+            ));
+        }
+        BitcoinMessage::decode_payload(header.command, payload).map(Some)
+    }
+}
+
+impl Encoder<BitcoinMessage> for BitcoinCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: BitcoinMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // store the payload in temporary buffer, so we can take the payload length and checksum
+        let command = item.command();
+        let mut payload_buffer = BytesMut::new();
+        item.encode_payload(&mut payload_buffer)?;
+
+        HeaderCodec::new(self.network).encode(
+            HeaderMessage::new(self.network, command, &payload_buffer),
+            dst,
+        )?;
+        dst.extend_from_slice(&payload_buffer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::bitcoin::messages::{
+        addr::TimestampedAddress,
+        addr_v2::{NetworkAddress, TimestampedNetworkAddress},
+        block_headers::BlockHeader,
+        inventory::{Inventory, InventoryType},
+        limits::{
+            MAX_ADDR_COUNT, MAX_BLOCK_TRANSACTIONS, MAX_BLOOM_FILTER_SIZE,
+            MAX_BLOOM_HASH_FUNCTIONS, MAX_FILTER_ADD_SIZE, MAX_HEADERS_COUNT, MAX_INV_COUNT,
+            MAX_LOCATOR_COUNT,
+        },
+        payload::put_compact_size,
+        service_flags::ServiceFlags,
+    };
+
+    fn block_header() -> BlockHeader {
+        BlockHeader {
+            version: 2,
+            prev_block: [1; 32],
+            merkle_root: [2; 32],
+            timestamp: 1_700_000_000,
+            bits: 0x1d00ffff,
+            nonce: 42,
+        }
+    }
+
+    // one sample message for every command
+    fn sample_message(command: Command) -> BitcoinMessage {
+        let raw = Bytes::from_static(&[1, 2, 3, 4]);
+        let inventory = InventoryMessage {
+            inventory: vec![
+                Inventory {
+                    inventory_type: InventoryType::WitnessTx,
+                    hash: [3; 32],
+                },
+                Inventory {
+                    inventory_type: InventoryType::Unknown(77),
+                    hash: [4; 32],
+                },
+            ],
+        };
+        let locator = BlockLocatorMessage {
+            version: 70015,
+            locator_hashes: vec![[5; 32], [6; 32]],
+            hash_stop: [0; 32],
+        };
+        match command {
+            Command::Version => BitcoinMessage::Version(VersionMessage::new("test", 10)),
+            Command::VerAck => BitcoinMessage::VerAck,
+            Command::Addr => BitcoinMessage::Addr(AddrMessage {
+                addresses: vec![TimestampedAddress {
+                    timestamp: 1_700_000_000,
+                    services: ServiceFlags::NETWORK,
+                    ip: Ipv4Addr::new(10, 0, 0, 1).into(),
+                    port: 8333,
+                }],
+            }),
+            Command::Inv => BitcoinMessage::Inv(inventory),
+            Command::GetData => BitcoinMessage::GetData(inventory),
+            Command::NotFound => BitcoinMessage::NotFound(inventory),
+            Command::GetBlocks => BitcoinMessage::GetBlocks(locator),
+            Command::GetHeaders => BitcoinMessage::GetHeaders(locator),
+            Command::Tx => BitcoinMessage::Tx(raw),
+            Command::Block => BitcoinMessage::Block(raw),
+            Command::Headers => BitcoinMessage::Headers(HeadersMessage {
+                headers: vec![block_header(), block_header()],
+            }),
+            Command::GetAddr => BitcoinMessage::GetAddr,
+            Command::MemPool => BitcoinMessage::MemPool,
+            Command::CheckOrder => BitcoinMessage::CheckOrder(raw),
+            Command::SubmitOrder => BitcoinMessage::SubmitOrder(raw),
+            Command::Reply => BitcoinMessage::Reply(raw),
+            Command::Ping => BitcoinMessage::Ping(PingMessage { nonce: 7 }),
+            Command::Pong => BitcoinMessage::Pong(PingMessage { nonce: 7 }),
+            Command::Reject => BitcoinMessage::Reject(RejectMessage {
+                message: "tx".to_owned(),
+                code: 0x10,
+                reason: "bad-txns".to_owned(),
+                data: vec![8; 32],
+            }),
+            Command::FilterLoad => BitcoinMessage::FilterLoad(FilterLoadMessage {
+                filter: vec![0xAA; 10],
+                hash_functions: 5,
+                tweak: 9,
+                flags: 1,
+            }),
+            Command::FilterAdd => BitcoinMessage::FilterAdd(FilterAddMessage {
+                data: vec![0xBB; 20],
+            }),
+            Command::FilterClear => BitcoinMessage::FilterClear,
+            Command::MerkleBlock => BitcoinMessage::MerkleBlock(MerkleBlockMessage {
+                header: block_header(),
+                total_transactions: 3,
+                hashes: vec![[9; 32]],
+                flags: vec![0x1d],
+            }),
+            Command::Alert => BitcoinMessage::Alert(raw),
+            Command::SendHeaders => BitcoinMessage::SendHeaders,
+            Command::FeeFilter => BitcoinMessage::FeeFilter(FeeFilterMessage { fee_rate: 1000 }),
+            Command::SendCmpct => BitcoinMessage::SendCmpct(SendCompactMessage {
+                announce: true,
+                version: 2,
+            }),
+            Command::CmpctBlock => BitcoinMessage::CmpctBlock(raw),
+            Command::GetBlockTxn => BitcoinMessage::GetBlockTxn(GetBlockTxnMessage {
+                block_hash: [10; 32],
+                indexes: vec![0, 1, 5, 100],
+            }),
+            Command::BlockTxn => BitcoinMessage::BlockTxn(raw),
+            Command::WtxidRelay => BitcoinMessage::WtxidRelay,
+            Command::SendAddrV2 => BitcoinMessage::SendAddrV2,
+            Command::AddrV2 => BitcoinMessage::AddrV2(AddrV2Message {
+                addresses: [
+                    "10.0.0.1",
+                    "2001:db8::1",
+                    "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+                    "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p",
+                ]
+                .into_iter()
+                .map(|address| address.parse().unwrap())
+                .chain([NetworkAddress::Cjdns("fc00::1".parse().unwrap())])
+                .map(|address| TimestampedNetworkAddress {
+                    timestamp: 1_700_000_000,
+                    services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                    address,
+                    port: 8333,
+                })
+                .collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn encode_decode_all_commands() {
+        for command in Command::iter() {
+            let expected_message = sample_message(command);
+            let mut bytes = BytesMut::new();
+            BitcoinCodec::default()
+                .encode(expected_message.clone(), &mut bytes)
+                .unwrap();
+            let decoded_message = BitcoinCodec::default()
+                .decode(&mut bytes)
+                .unwrap_or_else(|e| panic!("Decoding failed for {:?}: {}", expected_message, e))
+                .unwrap();
+            assert_eq!(expected_message, decoded_message);
+            assert!(
+                bytes.is_empty(),
+                "leftover bytes after {:?}",
+                decoded_message
+            );
+        }
+    }
+
+    #[test]
+    fn wait_for_complete_message() {
+        let mut bytes = BytesMut::new();
+        BitcoinCodec::default()
+            .encode(BitcoinMessage::Ping(PingMessage { nonce: 1 }), &mut bytes)
+            .unwrap();
+        let mut partial = bytes.split_to(bytes.len() - 1);
+        assert!(BitcoinCodec::default()
+            .decode(&mut partial)
+            .unwrap()
+            .is_none());
+        // the header must not be consumed while waiting
+        partial.unsplit(bytes);
+        assert!(BitcoinCodec::default()
+            .decode(&mut partial)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn reject_invalid_checksum() {
+        let mut bytes = BytesMut::new();
+        BitcoinCodec::default()
+            .encode(BitcoinMessage::Ping(PingMessage { nonce: 1 }), &mut bytes)
+            .unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        let error = BitcoinCodec::default().decode(&mut bytes).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_truncated_payload() {
+        // a ping with a valid checksum but only half of the nonce
+        let payload = BytesMut::from(&[1u8, 2, 3, 4][..]);
+        let mut bytes = BytesMut::new();
+        HeaderCodec::new(Network::Mainnet)
+            .encode(
+                HeaderMessage::new(Network::Mainnet, Command::Ping, &payload),
+                &mut bytes,
+            )
+            .unwrap();
+        bytes.extend_from_slice(&payload);
+        let error = BitcoinCodec::default().decode(&mut bytes).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_message_of_other_network() {
+        let mut bytes = BytesMut::new();
+        BitcoinCodec::new(Network::Testnet4)
+            .encode(BitcoinMessage::VerAck, &mut bytes)
+            .unwrap();
+        let error = BitcoinCodec::new(Network::Regtest)
+            .decode(&mut bytes)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_oversized_payload_from_header() {
+        // only the header arrived, the payload is refused before room is reserved for it
+        let mut bytes = BytesMut::new();
+        HeaderCodec::new(Network::Mainnet)
+            .encode(
+                HeaderMessage::new(Network::Mainnet, Command::Block, &BytesMut::new()),
+                &mut bytes,
+            )
+            .unwrap();
+        bytes[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        let error = BitcoinCodec::default().decode(&mut bytes).unwrap_err();
+        assert_eq!(
+            DecodeError::from_io(&error),
+            Some(&DecodeError::MessageTooLarge {
+                size: MAX_MESSAGE_SIZE + 1,
+                max: MAX_MESSAGE_SIZE
+            })
+        );
+        assert!(bytes.capacity() < MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn reject_vectors_above_their_limit() {
+        let cases: [(Command, &[u8], usize); 11] = [
+            (Command::Addr, &[], MAX_ADDR_COUNT),
+            (Command::AddrV2, &[], MAX_ADDR_COUNT),
+            (Command::Inv, &[], MAX_INV_COUNT),
+            (Command::GetData, &[], MAX_INV_COUNT),
+            (Command::NotFound, &[], MAX_INV_COUNT),
+            (Command::Headers, &[], MAX_HEADERS_COUNT),
+            // the locator follows the protocol version
+            (
+                Command::GetHeaders,
+                &[0x7f, 0x11, 0x01, 0x00],
+                MAX_LOCATOR_COUNT,
+            ),
+            // the indexes follow the block hash
+            (Command::GetBlockTxn, &[0; 32], MAX_BLOCK_TRANSACTIONS),
+            // the hashes follow the header and the number of transactions
+            (Command::MerkleBlock, &[0; 84], MAX_BLOCK_TRANSACTIONS),
+            (Command::FilterLoad, &[], MAX_BLOOM_FILTER_SIZE),
+            (Command::FilterAdd, &[], MAX_FILTER_ADD_SIZE),
+        ];
+        for (command, prefix, max) in cases {
+            // the count alone is enough, none of the items is read
+            let mut payload = BytesMut::from(prefix);
+            put_compact_size(&mut payload, max as u64 + 1);
+            let error = BitcoinMessage::decode_payload(command, payload).unwrap_err();
+            assert!(
+                matches!(
+                    DecodeError::from_io(&error),
+                    Some(DecodeError::TooManyItems { count, .. }) if *count == max as u64 + 1
+                ),
+                "{} accepted {} items",
+                command,
+                max + 1
+            );
+        }
+    }
+
+    #[test]
+    fn reject_filter_with_too_many_hash_functions() {
+        let filter = |hash_functions| {
+            let mut payload = BytesMut::new();
+            FilterLoadCodec
+                .encode(
+                    FilterLoadMessage {
+                        filter: vec![0xff; MAX_BLOOM_FILTER_SIZE],
+                        hash_functions,
+                        tweak: 0,
+                        flags: 0,
+                    },
+                    &mut payload,
+                )
+                .unwrap();
+            BitcoinMessage::decode_payload(Command::FilterLoad, payload)
+        };
+        assert!(filter(MAX_BLOOM_HASH_FUNCTIONS).is_ok());
+        let error = filter(MAX_BLOOM_HASH_FUNCTIONS + 1).unwrap_err();
+        assert_eq!(
+            DecodeError::from_io(&error),
+            Some(&DecodeError::TooManyItems {
+                items: "hash functions",
+                count: MAX_BLOOM_HASH_FUNCTIONS as u64 + 1,
+                max: MAX_BLOOM_HASH_FUNCTIONS as usize
+            })
+        );
+    }
+}
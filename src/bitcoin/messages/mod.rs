@@ -1,14 +1,47 @@
 use bitcoin_hashes::sha256d;
 use bitcoin_hashes::Hash;
 
+mod addr;
+mod addr_v2;
+mod block_headers;
+mod block_locator;
+mod bloom_filter;
+mod fee_filter;
+mod get_block_txn;
 mod header;
+mod inventory;
+mod limits;
+mod merkle_block;
+mod message;
+mod payload;
+mod ping;
+mod reject;
+mod send_compact;
+mod service_flags;
 mod verack;
 mod version;
 
+pub use addr::{AddrMessage, TimestampedAddress};
+pub use addr_v2::{AddrV2Message, NetworkAddress, TimestampedNetworkAddress};
+pub use block_headers::{BlockHeader, HeadersMessage};
+pub use block_locator::BlockLocatorMessage;
+pub use bloom_filter::{FilterAddMessage, FilterLoadMessage};
+pub use fee_filter::FeeFilterMessage;
+pub use get_block_txn::GetBlockTxnMessage;
 pub(crate) use header::{HeaderCodec, HeaderMessage};
+pub use inventory::{Inventory, InventoryMessage, InventoryType};
+pub use limits::{
+    DecodeError, MAX_ADDR_COUNT, MAX_HEADERS_COUNT, MAX_INV_COUNT, MAX_LOCATOR_COUNT,
+    MAX_MESSAGE_SIZE, MAX_USER_AGENT_LENGTH,
+};
+pub use merkle_block::MerkleBlockMessage;
+pub use message::{BitcoinCodec, BitcoinMessage};
+pub use ping::PingMessage;
+pub use reject::RejectMessage;
+pub use send_compact::SendCompactMessage;
+pub use service_flags::ServiceFlags;
 // pub(crate) use verack::VerackMessage;
-pub(crate) use version::VersionCodec;
-pub(crate) use version::VersionMessage;
+pub use version::{VersionMessage, PROTOCOL_VERSION};
 
 pub fn sha2_checksum(data: &[u8]) -> [u8; 4] {
     let checksum = sha256d::Hash::hash(data);
@@ -20,9 +53,9 @@ pub mod commands {
     use strum::EnumIter;
     use strum::EnumString;
 
-    #[derive(Debug, PartialEq, EnumString, Display, EnumIter)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display, EnumIter)]
     #[strum(serialize_all = "lowercase")]
-    pub(crate) enum Command {
+    pub enum Command {
         Version,
         VerAck,
         Addr,
@@ -53,6 +86,9 @@ pub mod commands {
         CmpctBlock,
         GetBlockTxn,
         BlockTxn,
+        WtxidRelay,
+        SendAddrV2,
+        AddrV2,
     }
 
     impl Command {
@@ -109,7 +145,7 @@ pub mod commands {
             for command in Command::iter() {
                 let encoded = command.encode().unwrap();
                 let decoded = Command::decode(encoded)
-                    .expect(&format!("Decoding failed for command: {}", command));
+                    .unwrap_or_else(|_| panic!("Decoding failed for command: {}", command));
                 assert_eq!(command, decoded, "Failed on command: {:?}", command);
             }
         }
@@ -123,13 +159,14 @@ pub mod commands {
 }
 pub mod types {
 
+    use super::limits::DecodeError;
     use std::{
         io,
         net::{IpAddr, Ipv4Addr, Ipv6Addr},
     };
 
-    #[derive(Debug, Clone, Copy, PartialEq)]
-    pub(crate) struct BitcoinIpAddr([u8; 16]);
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct BitcoinIpAddr([u8; 16]);
 
     impl BitcoinIpAddr {
         pub fn encode(&self) -> [u8; 16] {
@@ -174,6 +211,15 @@ pub mod types {
         }
     }
 
+    impl From<IpAddr> for BitcoinIpAddr {
+        fn from(addr: IpAddr) -> Self {
+            match addr {
+                IpAddr::V4(ip) => ip.into(),
+                IpAddr::V6(ip) => ip.into(),
+            }
+        }
+    }
+
     impl From<BitcoinIpAddr> for IpAddr {
         fn from(addr: BitcoinIpAddr) -> Self {
             if addr.0[..10] == [0u8; 10] && addr.0[10] == 0xFF && addr.0[11] == 0xFF {
@@ -189,7 +235,7 @@ pub mod types {
     }
 
     #[derive(Debug, PartialEq, Eq, Clone)]
-    pub(crate) struct CompactSize(u64);
+    pub struct CompactSize(u64);
 
     impl CompactSize {
         /// Creates a `CompactSize` instance based on the length of the input.
@@ -209,8 +255,8 @@ pub mod types {
         /// let my_bytes: Vec<u8> = vec![0, 1, 2, 3, 4, 5];
         /// let compact_size_from_bytes = CompactSize::from_length(&my_bytes);
         ///
-        /// assert_eq!(compact_size_from_str, CompactSize(13)); // Length of "Hello, world!"
-        /// assert_eq!(compact_size_from_bytes, CompactSize(6)); // Length of the byte vector
+        /// assert_eq!(compact_size_from_str.value(), 13); // Length of "Hello, world!"
+        /// assert_eq!(compact_size_from_bytes.value(), 6); // Length of the byte vector
         /// ```
         ///
         /// This approach abstracts away the details of how the length is obtained,
@@ -219,18 +265,22 @@ pub mod types {
         /// # Parameters
         ///
         /// - `input`: An instance of any type `T` that can be referenced as a byte slice,
-        /// including but not limited to `String`, `&str`, and `Vec<u8>`.
+        ///   including but not limited to `String`, `&str`, and `Vec<u8>`.
         ///
         /// # Returns
         ///
         /// Returns a `CompactSize` instance representing the length of the input data.
-        pub(crate) fn from_length<T: AsRef<[u8]>>(input: T) -> Self {
+        pub fn from_length<T: AsRef<[u8]>>(input: T) -> Self {
             CompactSize(input.as_ref().len() as u64)
         }
 
-        /// Returns the numeric value represented by this `CompactSize` as `usize`.
-        pub fn value_as_usize(&self) -> usize {
-            self.0 as usize
+        pub(crate) fn new(value: u64) -> Self {
+            CompactSize(value)
+        }
+
+        /// Returns the numeric value represented by this `CompactSize`.
+        pub fn value(&self) -> u64 {
+            self.0
         }
 
         /// Decodes a `CompactSize` from a byte slice, returning the `CompactSize` and the number of bytes read.
@@ -257,6 +307,9 @@ pub mod types {
                         ))
                     } else {
                         let value = u16::from_le_bytes([buf[1], buf[2]]) as u64;
+                        if value < 253 {
+                            return Err(DecodeError::NonCanonicalCompactSize(value).into());
+                        }
                         Ok((CompactSize(value), 3))
                     }
                 }
@@ -269,6 +322,9 @@ pub mod types {
                         ))
                     } else {
                         let value = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]) as u64;
+                        if value <= 0xffff {
+                            return Err(DecodeError::NonCanonicalCompactSize(value).into());
+                        }
                         Ok((CompactSize(value), 5))
                     }
                 }
@@ -283,6 +339,9 @@ pub mod types {
                         let value = u64::from_le_bytes([
                             buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7], buf[8],
                         ]);
+                        if value <= 0xffffffff {
+                            return Err(DecodeError::NonCanonicalCompactSize(value).into());
+                        }
                         Ok((CompactSize(value), 9))
                     }
                 }
@@ -0,0 +1,194 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::{self, Error, ErrorKind};
+
+use super::{limits::DecodeError, types::CompactSize};
+
+// Cursor over a payload that fails with `UnexpectedEof` instead of panicking when the payload is too short
+pub(crate) struct PayloadReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        PayloadReader { buf, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.position
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "Payload too short, require {} bytes, remaining {}",
+                    len,
+                    self.remaining()
+                ),
+            ));
+        }
+        let bytes = &self.buf[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16_be(&mut self) -> io::Result<u16> {
+        self.bytes(2).map(|mut b| b.get_u16())
+    }
+
+    pub fn u32_le(&mut self) -> io::Result<u32> {
+        self.bytes(4).map(|mut b| b.get_u32_le())
+    }
+
+    pub fn i32_le(&mut self) -> io::Result<i32> {
+        self.bytes(4).map(|mut b| b.get_i32_le())
+    }
+
+    pub fn u64_le(&mut self) -> io::Result<u64> {
+        self.bytes(8).map(|mut b| b.get_u64_le())
+    }
+
+    pub fn i64_le(&mut self) -> io::Result<i64> {
+        self.bytes(8).map(|mut b| b.get_i64_le())
+    }
+
+    pub fn hash(&mut self) -> io::Result<[u8; 32]> {
+        self.array::<32>()
+    }
+
+    pub fn compact_size(&mut self) -> io::Result<u64> {
+        let (size, bytes_used) = CompactSize::decode(&self.buf[self.position..])?;
+        self.position += bytes_used;
+        Ok(size.value())
+    }
+
+    // The number of items of a vector, rejected above `max` before any of them is read
+    pub fn count(&mut self, items: &'static str, max: usize) -> io::Result<u64> {
+        let count = self.compact_size()?;
+        if count > max as u64 {
+            return Err(DecodeError::TooManyItems { items, count, max }.into());
+        }
+        Ok(count)
+    }
+
+    // CompactSize length followed by that many bytes
+    pub fn var_bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.compact_size()?;
+        self.bytes(len as usize)
+    }
+
+    pub fn var_string(&mut self) -> io::Result<String> {
+        let bytes = self.var_bytes()?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF-8 string"))
+    }
+
+    // The remaining bytes of the payload
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.position..];
+        self.position = self.buf.len();
+        rest
+    }
+}
+
+// Run `read` over the source buffer, consume the bytes it read on success,
+// and report a too short buffer as `Ok(None)` the same way the other decoders wait for more bytes
+pub(crate) fn decode_with<T>(
+    src: &mut BytesMut,
+    read: impl FnOnce(&mut PayloadReader) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    let mut reader = PayloadReader::new(src.as_ref());
+    match read(&mut reader) {
+        Ok(item) => {
+            let consumed = reader.position;
+            src.advance(consumed);
+            Ok(Some(item))
+        }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn put_compact_size(dst: &mut BytesMut, value: u64) {
+    dst.extend_from_slice(&Vec::<u8>::from(CompactSize::new(value)));
+}
+
+pub(crate) fn put_var_bytes(dst: &mut BytesMut, bytes: &[u8]) {
+    put_compact_size(dst, bytes.len() as u64);
+    dst.extend_from_slice(bytes);
+}
+
+pub(crate) fn put_var_string(dst: &mut BytesMut, value: &str) {
+    put_var_bytes(dst, value.as_bytes());
+}
+
+pub(crate) fn put_hashes(dst: &mut BytesMut, hashes: &[[u8; 32]]) {
+    put_compact_size(dst, hashes.len() as u64);
+    for hash in hashes {
+        dst.put_slice(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_payload_waits_for_more_bytes() {
+        let mut src = BytesMut::from(&[1u8, 2, 3][..]);
+        let result = decode_with(&mut src, |reader| reader.u32_le()).unwrap();
+        assert!(result.is_none());
+        // nothing consumed
+        assert_eq!(src.len(), 3);
+    }
+
+    #[test]
+    fn var_bytes_round_trip() {
+        let mut dst = BytesMut::new();
+        put_var_bytes(&mut dst, &[7u8; 300]);
+        let decoded = decode_with(&mut dst, |reader| Ok(reader.var_bytes()?.to_vec()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded, vec![7u8; 300]);
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn reject_non_canonical_compact_size() {
+        let encodings: [&[u8]; 3] = [
+            &[0xfd, 0xfc, 0x00],
+            &[0xfe, 0xff, 0xff, 0x00, 0x00],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00],
+        ];
+        for encoding in encodings {
+            let mut src = BytesMut::from(encoding);
+            let error = decode_with(&mut src, |reader| reader.compact_size()).unwrap_err();
+            assert!(matches!(
+                DecodeError::from_io(&error),
+                Some(DecodeError::NonCanonicalCompactSize(_))
+            ));
+        }
+        // the shortest forms are accepted
+        let mut src = BytesMut::from(&[0xfd, 0xfd, 0x00][..]);
+        assert_eq!(
+            decode_with(&mut src, |reader| reader.compact_size()).unwrap(),
+            Some(253)
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::payload::decode_with;
+
+/// Represents the payload of the ping and pong messages (BIP 31).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingMessage {
+    // the pong echoes the nonce of the ping it answers
+    pub nonce: u64,
+}
+
+pub(crate) struct PingCodec;
+
+impl Encoder<PingMessage> for PingCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: PingMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u64_le(msg.nonce);
+        Ok(())
+    }
+}
+
+impl Decoder for PingCodec {
+    type Item = PingMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            Ok(PingMessage {
+                nonce: reader.u64_le()?,
+            })
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::payload::{decode_with, put_var_string};
+
+/// Represents the payload of the reject message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectMessage {
+    // the command of the rejected message
+    pub message: String,
+    pub code: u8,
+    pub reason: String,
+    // extra data, usually the hash of the rejected block or transaction
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct RejectCodec;
+
+impl Encoder<RejectMessage> for RejectCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: RejectMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        put_var_string(dst, &msg.message);
+        dst.put_u8(msg.code);
+        put_var_string(dst, &msg.reason);
+        dst.put_slice(&msg.data);
+        Ok(())
+    }
+}
+
+impl Decoder for RejectCodec {
+    type Item = RejectMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            Ok(RejectMessage {
+                message: reader.var_string()?,
+                code: reader.u8()?,
+                reason: reader.var_string()?,
+                data: reader.rest().to_vec(),
+            })
+        })
+    }
+}
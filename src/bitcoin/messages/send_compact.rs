@@ -0,0 +1,58 @@
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::payload::decode_with;
+
+/// Represents a BIP 152 sendcmpct message, announcing compact blocks relay support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendCompactMessage {
+    // true when the peer asks to receive new blocks as cmpctblock without inv announcements
+    pub announce: bool,
+    pub version: u64,
+}
+
+pub(crate) struct SendCompactCodec;
+
+impl Encoder<SendCompactMessage> for SendCompactCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: SendCompactMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u8(msg.announce as u8);
+        dst.put_u64_le(msg.version);
+        Ok(())
+    }
+}
+
+impl Decoder for SendCompactCodec {
+    type Item = SendCompactMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_with(src, |reader| {
+            Ok(SendCompactMessage {
+                announce: reader.bool()?,
+                version: reader.u64_le()?,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_send_compact() {
+        let expected_message = SendCompactMessage {
+            announce: true,
+            version: 2,
+        };
+        let mut codec = SendCompactCodec;
+        let mut bytes = BytesMut::new();
+        codec.encode(expected_message, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 9);
+
+        let decoded_message = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(expected_message, decoded_message);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fmt,
+    ops::{BitOr, BitOrAssign},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The services a node offers, as announced in its version message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    pub const NONE: ServiceFlags = ServiceFlags(0);
+    /// Serves the full block chain.
+    pub const NETWORK: ServiceFlags = ServiceFlags(1);
+    /// Filters transactions with bloom filters (BIP 37).
+    pub const BLOOM: ServiceFlags = ServiceFlags(1 << 2);
+    /// Serves blocks and transactions with their witness (BIP 144).
+    pub const WITNESS: ServiceFlags = ServiceFlags(1 << 3);
+    /// Serves compact block filters (BIP 157).
+    pub const COMPACT_FILTERS: ServiceFlags = ServiceFlags(1 << 6);
+    /// Serves the last 288 blocks only (BIP 159).
+    pub const NETWORK_LIMITED: ServiceFlags = ServiceFlags(1 << 10);
+    /// Accepts the encrypted v2 transport (BIP 324).
+    pub const P2P_V2: ServiceFlags = ServiceFlags(1 << 11);
+
+    // The named flags, in the order they are displayed
+    const NAMES: [(ServiceFlags, &'static str); 6] = [
+        (ServiceFlags::NETWORK, "NETWORK"),
+        (ServiceFlags::BLOOM, "BLOOM"),
+        (ServiceFlags::WITNESS, "WITNESS"),
+        (ServiceFlags::COMPACT_FILTERS, "COMPACT_FILTERS"),
+        (ServiceFlags::NETWORK_LIMITED, "NETWORK_LIMITED"),
+        (ServiceFlags::P2P_V2, "P2P_V2"),
+    ];
+
+    pub fn from_bits(bits: u64) -> Self {
+        ServiceFlags(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether every flag of `flags` is offered.
+    pub fn has(&self, flags: ServiceFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// The flags of `flags` that are not offered.
+    pub fn missing(&self, flags: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(flags.0 & !self.0)
+    }
+}
+
+impl BitOr for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        ServiceFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+// The names of the flags separated by `|`, the bits without a name in hex, e.g. `NETWORK|WITNESS|0x1000000`
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == ServiceFlags::NONE {
+            return write!(f, "NONE");
+        }
+        let mut names = Vec::new();
+        let mut unknown = self.0;
+        for (flag, name) in ServiceFlags::NAMES {
+            if self.has(flag) {
+                names.push(name.to_owned());
+                unknown &= !flag.0;
+            }
+        }
+        if unknown != 0 {
+            names.push(format!("{:#x}", unknown));
+        }
+        write!(f, "{}", names.join("|"))
+    }
+}
+
+// Parse what `Display` writes, names are not case sensitive and may be separated by commas as well
+impl FromStr for ServiceFlags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = ServiceFlags::NONE;
+        for part in s.split(['|', ',']).map(str::trim) {
+            if part.eq_ignore_ascii_case("NONE") {
+                continue;
+            }
+            if let Some(hex) = part.strip_prefix("0x") {
+                let bits = u64::from_str_radix(hex, 16)
+                    .map_err(|_| format!("invalid service bits: {}", part))?;
+                flags |= ServiceFlags(bits);
+                continue;
+            }
+            let (flag, _) = ServiceFlags::NAMES
+                .iter()
+                .find(|(_, name)| name.eq_ignore_ascii_case(part))
+                .ok_or_else(|| format!("unknown service: {}", part))?;
+            flags |= *flag;
+        }
+        Ok(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn has_every_requested_flag() {
+        let services = ServiceFlags::from_bits(0b1001);
+        assert!(services.has(ServiceFlags::NETWORK));
+        assert!(services.has(ServiceFlags::NETWORK | ServiceFlags::WITNESS));
+        assert!(services.has(ServiceFlags::NONE));
+        assert!(!ServiceFlags::NETWORK.has(ServiceFlags::WITNESS));
+        assert_eq!(
+            ServiceFlags::NETWORK.missing(ServiceFlags::NETWORK | ServiceFlags::P2P_V2),
+            ServiceFlags::P2P_V2
+        );
+    }
+
+    #[test]
+    fn display_and_parse_names() {
+        let services =
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::from_bits(1 << 24);
+        assert_eq!(services.to_string(), "NETWORK|WITNESS|0x1000000");
+        assert_eq!(services.to_string().parse::<ServiceFlags>(), Ok(services));
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+        assert_eq!("none".parse::<ServiceFlags>(), Ok(ServiceFlags::NONE));
+        assert_eq!(
+            "network, network_limited".parse::<ServiceFlags>(),
+            Ok(ServiceFlags::NETWORK | ServiceFlags::NETWORK_LIMITED)
+        );
+        assert!("NETWORK|FAST".parse::<ServiceFlags>().is_err());
+    }
+}
@@ -1,23 +1,40 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 
 use std::{
     io::{self, ErrorKind},
-    net::Ipv6Addr,
+    net::{Ipv6Addr, SocketAddr},
 };
 use tokio_util::codec::{Decoder, Encoder};
 
-use super::types::{BitcoinIpAddr, CompactSize};
+use super::{
+    limits::{DecodeError, MAX_USER_AGENT_LENGTH},
+    payload::{decode_with, put_var_string, PayloadReader},
+    service_flags::ServiceFlags,
+    types::{BitcoinIpAddr, CompactSize},
+};
+
+/// The protocol version we announce, the first with addrv2 negotiation (BIP 155).
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+// The protocol version that added the sender address, the nonce and the user agent
+const SENDER_FIELDS_VERSION: u32 = 106;
+
+// The protocol version that added the start height
+const START_HEIGHT_VERSION: u32 = 209;
+
+// The protocol version that added the relay flag (BIP 37)
+const RELAY_VERSION: u32 = 70001;
 
 /// Represents a Bitcoin version message.
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct VersionMessage {
+pub struct VersionMessage {
     version: u32,
-    services: u64,
+    services: ServiceFlags,
     timestamp: i64,
-    addr_recv_services: u64,
+    addr_recv_services: ServiceFlags,
     addr_recv_ip: BitcoinIpAddr,
     addr_recv_port: u16,
-    addr_trans_services: u64,
+    addr_trans_services: ServiceFlags,
     addr_trans_ip: BitcoinIpAddr,
     addr_trans_port: u16,
     nonce: u64,
@@ -32,13 +49,13 @@ impl VersionMessage {
     pub fn new(user_agent: &str, start_height: i32) -> Self {
         let user_agent = user_agent.into();
         VersionMessage {
-            version: 70015, // The protocol version
-            services: 1,    // NODE_NETWORK
+            version: PROTOCOL_VERSION,
+            services: ServiceFlags::NETWORK,
             timestamp: chrono::Utc::now().timestamp(),
-            addr_recv_services: 1, // NODE_NETWORK
+            addr_recv_services: ServiceFlags::NETWORK,
             addr_recv_ip: Ipv6Addr::UNSPECIFIED.into(),
             addr_recv_port: 0,
-            addr_trans_services: 1, // NODE_NETWORK
+            addr_trans_services: ServiceFlags::NETWORK,
             addr_trans_ip: Ipv6Addr::UNSPECIFIED.into(),
             addr_trans_port: 0,
             nonce: rand::random(),
@@ -48,6 +65,96 @@ impl VersionMessage {
             relay: false,
         }
     }
+
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    // The services we offer, announced by the sending address as well
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self.addr_trans_services = services;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    // The address of the peer as we see it, with the services we know it offers
+    pub fn with_receiver(mut self, address: SocketAddr, services: ServiceFlags) -> Self {
+        self.addr_recv_ip = address.ip().into();
+        self.addr_recv_port = address.port();
+        self.addr_recv_services = services;
+        self
+    }
+
+    // Our address as the peer can reach it, unspecified when we do not want to reveal it
+    pub fn with_sender(mut self, address: SocketAddr) -> Self {
+        self.addr_trans_ip = address.ip().into();
+        self.addr_trans_port = address.port();
+        self
+    }
+
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// The protocol version of the sender.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The service bits the sender offers.
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    /// The clock of the sender, in seconds since the unix epoch.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// The random value used to detect connections to ourselves.
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// The address of the receiver as seen by the sender.
+    pub fn receiver(&self) -> SocketAddr {
+        SocketAddr::new(self.addr_recv_ip.into(), self.addr_recv_port)
+    }
+
+    /// The services of the receiver as known by the sender.
+    pub fn receiver_services(&self) -> ServiceFlags {
+        self.addr_recv_services
+    }
+
+    /// The address the sender announces for itself.
+    pub fn sender(&self) -> SocketAddr {
+        SocketAddr::new(self.addr_trans_ip.into(), self.addr_trans_port)
+    }
+
+    pub fn start_height(&self) -> i32 {
+        self.start_height
+    }
+
+    /// Whether the sender wants transactions announced to it (BIP 37), false for blocks-only peers.
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
 }
 
 pub(crate) struct VersionCodec;
@@ -57,101 +164,108 @@ impl Encoder<VersionMessage> for VersionCodec {
 
     fn encode(&mut self, msg: VersionMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
         dst.put_u32_le(msg.version);
-        dst.put_u64_le(msg.services);
+        dst.put_u64_le(msg.services.bits());
         dst.put_i64_le(msg.timestamp);
 
-        dst.put_u64_le(msg.addr_recv_services);
-        // Assuming addr_recv_ip is serialized to 16 bytes
+        dst.put_u64_le(msg.addr_recv_services.bits());
         dst.extend_from_slice(&msg.addr_recv_ip.encode());
-        dst.put_u16_le(msg.addr_recv_port);
+        // unlike the rest of the protocol, the ports are in network byte order
+        dst.put_u16(msg.addr_recv_port);
 
-        dst.put_u64_le(msg.addr_trans_services);
-        // Assuming addr_trans_ip is serialized to 16 bytes
+        // the fields a protocol version does not know are left out, as its nodes did
+        if msg.version < SENDER_FIELDS_VERSION {
+            return Ok(());
+        }
+        dst.put_u64_le(msg.addr_trans_services.bits());
         dst.extend_from_slice(&msg.addr_trans_ip.encode());
-        dst.put_u16_le(msg.addr_trans_port);
-
+        dst.put_u16(msg.addr_trans_port);
         dst.put_u64_le(msg.nonce);
+        put_var_string(dst, &msg.user_agent);
 
-        // User agent: encode its length as CompactSize first, then the string itself
-        let user_agent_bytes = msg.user_agent.into_bytes();
-        let user_agent_compact_size = CompactSize::from_length(&user_agent_bytes);
-        dst.extend_from_slice(&Vec::<u8>::from(user_agent_compact_size));
-        dst.extend_from_slice(&user_agent_bytes);
-
-        dst.put_i32_le(msg.start_height);
-        dst.put_u8(msg.relay as u8);
-
+        if msg.version >= START_HEIGHT_VERSION {
+            dst.put_i32_le(msg.start_height);
+        }
+        if msg.version >= RELAY_VERSION {
+            dst.put_u8(msg.relay as u8);
+        }
         Ok(())
     }
 }
 
+// The source holds the complete payload: the fields its protocol version defines are read,
+// whatever follows them is consumed without being interpreted, newer nodes may append fields
 impl Decoder for VersionCodec {
-    type Item = VersionMessage; // Define your inbound message type
+    type Item = VersionMessage;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 86 {
-            // Minimum size without user_agent
-            return Ok(None); // Wait for more bytes
-        }
+        decode_with(src, |reader| {
+            let version = reader.u32_le()?;
+            let services = ServiceFlags::from_bits(reader.u64_le()?);
+            let timestamp = reader.i64_le()?;
+            let addr_recv_services = ServiceFlags::from_bits(reader.u64_le()?);
+            let addr_recv_ip = ip_address(reader, "addr_recv_ip")?;
+            let addr_recv_port = reader.u16_be()?;
 
-        let mut buf = src.as_ref();
-        let version = buf.get_u32_le();
-        let services = buf.get_u64_le();
-        let timestamp = buf.get_i64_le();
-
-        let addr_recv_services = buf.get_u64_le();
-        let addr_recv_ip = BitcoinIpAddr::try_from_bytes(&buf[0..16]).map_err(|_| {
-            io::Error::new(ErrorKind::InvalidData, "Invalid bytes for addr_recv_ip")
-        })?;
-
-        buf.advance(16); // Skip over the bytes we just processed
-        let addr_recv_port = buf.get_u16_le();
-
-        let addr_trans_services = buf.get_u64_le();
-        let addr_trans_ip = BitcoinIpAddr::try_from_bytes(&buf[0..16]).map_err(|_| {
-            io::Error::new(ErrorKind::InvalidData, "Invalid bytes for addr_trans_ip")
-        })?;
-        buf.advance(16); // Skip over the bytes we just processed
-        let addr_trans_port = buf.get_u16_le();
-
-        let nonce = buf.get_u64_le();
-
-        // For user_agent, we need to decode its CompactSize length first
-        let (user_agent_size, bytes_used) = CompactSize::decode(&buf).map_err(|_| {
-            io::Error::new(ErrorKind::InvalidData, "Invalid bytes for user_agent_size")
-        })?;
-        buf.advance(bytes_used); // Advance the buffer past the CompactSize bytes
-
-        if buf.remaining() < user_agent_size.value_as_usize() + 4 + 1 {
-            // Ensure enough bytes for user_agent, start_height, and relay
-            return Ok(None);
-        }
+            let mut message = VersionMessage {
+                version,
+                services,
+                timestamp,
+                addr_recv_services,
+                addr_recv_ip,
+                addr_recv_port,
+                addr_trans_services: ServiceFlags::NONE,
+                addr_trans_ip: Ipv6Addr::UNSPECIFIED.into(),
+                addr_trans_port: 0,
+                nonce: 0,
+                user_agent_bytes: CompactSize::new(0),
+                user_agent: String::new(),
+                start_height: 0,
+                // BIP 37: a peer that does not send the flag wants transactions announced
+                relay: true,
+            };
+            if version >= SENDER_FIELDS_VERSION {
+                message.addr_trans_services = ServiceFlags::from_bits(reader.u64_le()?);
+                message.addr_trans_ip = ip_address(reader, "addr_trans_ip")?;
+                message.addr_trans_port = reader.u16_be()?;
+                message.nonce = reader.u64_le()?;
+                message.user_agent = user_agent(reader)?;
+                message.user_agent_bytes = CompactSize::from_length(&message.user_agent);
+            }
+            if version >= START_HEIGHT_VERSION {
+                message.start_height = reader.i32_le()?;
+            }
+            // the flag is optional even for the versions that know it, as in Bitcoin Core
+            if version >= RELAY_VERSION && reader.remaining() > 0 {
+                message.relay = reader.bool()?;
+            }
+            reader.rest();
+            Ok(message)
+        })
+    }
+}
 
-        let user_agent_bytes = buf.copy_to_bytes(user_agent_size.value_as_usize());
-        let user_agent = String::from_utf8(user_agent_bytes.to_vec())
-            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Invalid UTF-8 for user_agent"))?;
-
-        let start_height = buf.get_i32_le();
-        let relay = buf.get_u8() != 0;
-
-        Ok(Some(VersionMessage {
-            version,
-            services,
-            timestamp,
-            addr_recv_services,
-            addr_recv_ip,
-            addr_recv_port,
-            addr_trans_services,
-            addr_trans_ip,
-            addr_trans_port,
-            nonce,
-            user_agent_bytes: CompactSize::from_length(&user_agent),
-            user_agent,
-            start_height,
-            relay,
-        }))
+fn ip_address(reader: &mut PayloadReader, field: &str) -> io::Result<BitcoinIpAddr> {
+    BitcoinIpAddr::try_from_bytes(reader.bytes(16)?).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid bytes for {}", field),
+        )
+    })
+}
+
+// The length is checked before the user agent is read
+fn user_agent(reader: &mut PayloadReader) -> io::Result<String> {
+    let length = reader.compact_size()?;
+    if length > MAX_USER_AGENT_LENGTH as u64 {
+        return Err(DecodeError::UserAgentTooLong {
+            length,
+            max: MAX_USER_AGENT_LENGTH,
+        }
+        .into());
     }
+    String::from_utf8(reader.bytes(length as usize)?.to_vec())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Invalid UTF-8 for user_agent"))
 }
 
 #[cfg(test)]
@@ -171,4 +285,199 @@ mod tests {
         assert_eq!(expected_message, decoded_message);
         Ok(())
     }
+
+    #[test]
+    fn encode_addresses_in_network_byte_order() {
+        let receiver: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let sender: SocketAddr = "[2001:db8::1]:18444".parse().unwrap();
+        let message = VersionMessage::new("user_agent", 840_000)
+            .with_receiver(receiver, ServiceFlags::WITNESS)
+            .with_sender(sender)
+            .with_relay(true);
+        let mut codec = VersionCodec {};
+        let mut bytes = BytesMut::new();
+        codec.encode(message.clone(), &mut bytes).unwrap();
+        // version, services and timestamp come first, then the services and ip of the receiver
+        assert_eq!(bytes[44..46], 8333u16.to_be_bytes());
+        assert_eq!(bytes[70..72], 18444u16.to_be_bytes());
+
+        let decoded = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(decoded.receiver(), receiver);
+        assert_eq!(decoded.sender(), sender);
+        assert_eq!(decoded.start_height(), 840_000);
+        assert!(decoded.relay());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn reject_long_user_agent() {
+        let mut codec = VersionCodec {};
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(
+                VersionMessage::new(&"a".repeat(MAX_USER_AGENT_LENGTH), 0),
+                &mut bytes,
+            )
+            .unwrap();
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(
+                VersionMessage::new(&"a".repeat(MAX_USER_AGENT_LENGTH + 1), 0),
+                &mut bytes,
+            )
+            .unwrap();
+        let error = codec.decode(&mut bytes).unwrap_err();
+        assert_eq!(
+            DecodeError::from_io(&error),
+            Some(&DecodeError::UserAgentTooLong {
+                length: MAX_USER_AGENT_LENGTH as u64 + 1,
+                max: MAX_USER_AGENT_LENGTH
+            })
+        );
+    }
+
+    // A payload written as hex, the whitespace only splits the fields
+    fn from_hex(hex: &str) -> BytesMut {
+        let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect::<Vec<u8>>()[..]
+            .into()
+    }
+
+    // Before version 106 the message ends after the receiving address, as Bitcoin 0.1 sent it
+    const VERSION_100: &str = "
+        64000000 0100000000000000 e615104d00000000
+        0100000000000000 00000000000000000000ffff0a000001 208d";
+
+    // The example of the protocol documentation, sent by Bitcoin 0.3.19
+    const VERSION_31900: &str = "
+        9c7c0000 0100000000000000 e615104d00000000
+        0100000000000000 00000000000000000000ffff0a000001 208d
+        0100000000000000 00000000000000000000ffff0a000002 208d
+        dd9d202c3ab45713 00 55810100";
+
+    // The example of the protocol documentation, sent by Bitcoin 0.7.2 before BIP 37
+    const VERSION_60002: &str = "
+        62ea0000 0100000000000000 11b2d05000000000
+        0100000000000000 00000000000000000000ffff00000000 0000
+        0000000000000000 00000000000000000000ffff00000000 0000
+        3b2eb35d8ce61765 0f 2f5361746f7368693a302e372e322f c03e0300";
+
+    // A blocks-only Bitcoin Core 27 node, hiding its own address
+    const VERSION_70016: &str = "
+        80110100 090c000000000000 8090296600000000
+        0904000000000000 00000000000000000000ffffcb007107 208d
+        090c000000000000 00000000000000000000000000000000 0000
+        0b1f3c5d7e9fa2b4 10 2f5361746f7368693a32372e302e302f 40d10c00 00";
+
+    // The message encodes back to the bytes it was decoded from
+    fn decode_whole(hex: &str) -> VersionMessage {
+        let mut bytes = from_hex(hex);
+        let message = VersionCodec.decode(&mut bytes).unwrap().unwrap();
+        assert!(bytes.is_empty());
+        VersionCodec.encode(message.clone(), &mut bytes).unwrap();
+        assert_eq!(bytes, from_hex(hex));
+        message
+    }
+
+    #[test]
+    fn decode_version_of_early_nodes() {
+        let message = decode_whole(VERSION_100);
+        assert_eq!(message.version(), 100);
+        assert_eq!(message.services(), ServiceFlags::NETWORK);
+        assert_eq!(message.timestamp(), 1292899814);
+        assert_eq!(message.addr_recv_services, ServiceFlags::NETWORK);
+        assert_eq!(message.receiver(), "10.0.0.1:8333".parse().unwrap());
+        // the fields the version does not know keep their defaults
+        assert_eq!(message.addr_trans_services, ServiceFlags::NONE);
+        assert_eq!(message.sender(), "[::]:0".parse().unwrap());
+        assert_eq!(message.nonce(), 0);
+        assert_eq!(message.user_agent(), "");
+        assert_eq!(message.start_height(), 0);
+        assert!(message.relay());
+    }
+
+    #[test]
+    fn decode_version_of_satoshi_0_3_19() {
+        let message = decode_whole(VERSION_31900);
+        assert_eq!(message.version(), 31900);
+        assert_eq!(message.services(), ServiceFlags::NETWORK);
+        assert_eq!(message.timestamp(), 1292899814);
+        assert_eq!(message.addr_recv_services, ServiceFlags::NETWORK);
+        assert_eq!(message.receiver(), "10.0.0.1:8333".parse().unwrap());
+        assert_eq!(message.addr_trans_services, ServiceFlags::NETWORK);
+        assert_eq!(message.sender(), "10.0.0.2:8333".parse().unwrap());
+        assert_eq!(message.nonce(), 0x1357b43a2c209ddd);
+        assert_eq!(message.user_agent(), "");
+        assert_eq!(message.start_height(), 98645);
+        assert!(message.relay());
+    }
+
+    #[test]
+    fn decode_version_of_satoshi_0_7_2() {
+        let message = decode_whole(VERSION_60002);
+        assert_eq!(message.version(), 60002);
+        assert_eq!(message.services(), ServiceFlags::NETWORK);
+        assert_eq!(message.timestamp(), 1355854353);
+        assert_eq!(message.addr_recv_services, ServiceFlags::NETWORK);
+        assert_eq!(message.receiver(), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(message.addr_trans_services, ServiceFlags::NONE);
+        assert_eq!(message.sender(), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(message.nonce(), 0x6517e68c5db32e3b);
+        assert_eq!(message.user_agent(), "/Satoshi:0.7.2/");
+        assert_eq!(message.start_height(), 212672);
+        // sent before BIP 37, transactions are announced
+        assert!(message.relay());
+    }
+
+    #[test]
+    fn decode_version_of_blocks_only_node() {
+        let message = decode_whole(VERSION_70016);
+        let services = ServiceFlags::from_bits(0x0c09);
+        assert_eq!(message.version(), 70016);
+        assert_eq!(message.services(), services);
+        assert_eq!(message.timestamp(), 1714000000);
+        assert_eq!(message.addr_recv_services, ServiceFlags::from_bits(0x0409));
+        assert_eq!(message.receiver(), "203.0.113.7:8333".parse().unwrap());
+        assert_eq!(message.addr_trans_services, services);
+        assert_eq!(message.sender(), "[::]:0".parse().unwrap());
+        assert_eq!(message.nonce(), 0xb4a29f7e5d3c1f0b);
+        assert_eq!(message.user_agent(), "/Satoshi:27.0.0/");
+        assert_eq!(message.start_height(), 840000);
+        assert!(!message.relay());
+
+        // a relay version may still leave the flag out
+        let mut bytes = from_hex(VERSION_70016);
+        bytes.truncate(bytes.len() - 1);
+        let message = VersionCodec.decode(&mut bytes).unwrap().unwrap();
+        assert!(message.relay());
+    }
+
+    #[test]
+    fn ignore_trailing_bytes() {
+        let expected = VersionMessage::new("/Satoshi:27.0.0/", 840_000).with_version(70016);
+        let mut payload = BytesMut::new();
+        VersionCodec.encode(expected.clone(), &mut payload).unwrap();
+        payload.extend_from_slice(&[0x01, 0x02, 0x03]);
+
+        assert_eq!(VersionCodec.decode(&mut payload).unwrap(), Some(expected));
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn wait_for_the_fields_of_the_version() {
+        let mut payload = BytesMut::new();
+        VersionCodec
+            .encode(VersionMessage::new("user_agent", 70), &mut payload)
+            .unwrap();
+        // the start height is cut, only the optional relay flag may be missing
+        let mut truncated = BytesMut::from(&payload[..payload.len() - 3]);
+        assert!(VersionCodec.decode(&mut truncated).unwrap().is_none());
+        let mut truncated = BytesMut::from(&from_hex(VERSION_70016)[..40]);
+        assert!(VersionCodec.decode(&mut truncated).unwrap().is_none());
+    }
 }
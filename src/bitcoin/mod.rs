@@ -1,29 +1,483 @@
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{ArgAction, Parser};
+use handshake::LocalNonces;
+use messages::ServiceFlags;
+use network::Network;
+use network_time::{NetworkTime, DEFAULT_MAX_TIME_ADJUSTMENT};
+use socks5::{ProxyCredentials, Socks5Proxy};
 
+pub mod addr_crawler;
+pub mod address_manager;
 pub mod bitcoin_connection_info;
 pub mod bitcoin_factory;
+pub mod bitcoin_listener;
 pub mod bitcoin_peer;
-mod bitcoin_peer_discovery;
+pub mod bitcoin_peer_discovery;
+pub mod connection_manager;
+pub mod dns_seed;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake_node;
 mod handshake;
-mod messages;
+pub mod keepalive;
+pub mod messages;
+pub mod network;
+pub mod network_time;
+pub mod socks5;
+pub mod v2_transport;
 
-#[derive(Debug, Parser)]
+pub use handshake::{
+    BitcoinConnectionProtocol, BitcoinHandshakeError, ChainHeight, ConnectionDirection,
+    Established, EstablishedReader, EstablishedWriter, HandshakeReport, HandshakeState,
+    StandardVersionPolicy, Transport, VersionPolicy, DEFAULT_MAX_CLOCK_OFFSET,
+    DEFAULT_MIN_PROTOCOL_VERSION,
+};
+
+// The user agent announced in the version message when none is configured
+pub const DEFAULT_USER_AGENT: &str = "RZ Bitcoin client";
+
+// The services announced in the version message when none are configured
+pub const DEFAULT_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
+
+// The deadlines of the handshake when none are configured
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How often established connections are pinged, and how long the pong may take
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(120);
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The number of outbound peers to keep connected, and how many of them may be dialed at once
+pub const DEFAULT_TARGET_OUTBOUND: usize = 8;
+pub const DEFAULT_MAX_CONCURRENT_DIALS: usize = 4;
+
+// The number of inbound peers handshaking or waiting to be taken from the listener at once
+pub const DEFAULT_MAX_INBOUND: usize = 125;
+
+// The parameters of the local peer.
+// Parse them from the command line and the environment with clap, or set them from code with `BitcoinConfiguration::builder()`
+#[derive(Debug, Clone, Parser)]
 #[clap(long_about = "Bitcoin own configuration")]
 pub struct BitcoinConfiguration {
+    // The remote peer to connect to, required for outbound connections only
     #[clap(
         long = "remote-address",
         short = 'A',
-        env = "DISCOVER_REMOTE_PEER_ADDRESS"
+        env = "DISCOVER_REMOTE_PEER_ADDRESS",
+        value_parser = parse_remote_address
     )]
-    pub discover_remote_peer_address: SocketAddr,
+    pub discover_remote_peer_address: Option<SocketAddr>,
 
     #[clap(
         long,
         short = 'U',
         env = "USER_AGENT",
-        default_value = "RZ Bitcoin client"
+        default_value = DEFAULT_USER_AGENT
     )]
     pub user_agent: String,
+
+    #[clap(
+        long,
+        short = 'N',
+        env = "BITCOIN_NETWORK",
+        value_enum,
+        default_value_t = Network::Mainnet
+    )]
+    pub network: Network,
+
+    // The services announced to the peers, e.g. "NETWORK|WITNESS" or "0x409". P2P_V2 is added when the v2 transport is enabled
+    #[clap(long, env = "SERVICES", default_value_t = DEFAULT_SERVICES)]
+    pub services: ServiceFlags,
+
+    // Seconds to wait for the tcp connection to the remote peer
+    #[clap(long, env = "CONNECT_TIMEOUT", value_parser = parse_seconds, default_value = "5")]
+    pub connect_timeout: Duration,
+
+    // Seconds to wait for sending or receiving a single handshake message
+    #[clap(long, env = "MESSAGE_TIMEOUT", value_parser = parse_seconds, default_value = "30")]
+    pub message_timeout: Duration,
+
+    // Seconds for the whole handshake, from connecting until the verack is received
+    #[clap(long, env = "HANDSHAKE_TIMEOUT", value_parser = parse_seconds, default_value = "60")]
+    pub handshake_timeout: Duration,
+
+    // Seconds between the pings sent to established connections
+    #[clap(long, env = "PING_INTERVAL", value_parser = parse_seconds, default_value = "120")]
+    pub ping_interval: Duration,
+
+    // Seconds to wait for the pong before the connection is considered dead
+    #[clap(long, env = "PING_TIMEOUT", value_parser = parse_seconds, default_value = "60")]
+    pub ping_timeout: Duration,
+
+    // The number of outbound connections to keep established
+    #[clap(long, env = "TARGET_OUTBOUND", default_value_t = DEFAULT_TARGET_OUTBOUND)]
+    pub target_outbound: usize,
+
+    // The number of handshakes with discovered peers running at the same time
+    #[clap(long, env = "MAX_CONCURRENT_DIALS", default_value_t = DEFAULT_MAX_CONCURRENT_DIALS)]
+    pub max_concurrent_dials: usize,
+
+    // The number of inbound connections handshaking or waiting to be taken from the incoming stream,
+    // further connections wait in the accept backlog
+    #[clap(long, env = "MAX_INBOUND", default_value_t = DEFAULT_MAX_INBOUND)]
+    pub max_inbound: usize,
+
+    // The file remembering the addresses of peers between runs, kept in memory only when not set
+    #[clap(long, env = "ADDRESS_FILE")]
+    pub address_file: Option<PathBuf>,
+
+    // The SOCKS5 proxy outbound connections go through, e.g. Tor on 127.0.0.1:9050. Direct connections when not set
+    #[clap(long, env = "PROXY")]
+    pub proxy: Option<SocketAddr>,
+
+    // Send new random credentials to the proxy for every connection, so Tor isolates each of them on its own circuit
+    #[clap(long, env = "PROXY_RANDOMIZE_CREDENTIALS", default_value_t = true, action = ArgAction::Set)]
+    pub proxy_randomize_credentials: bool,
+
+    // Offer the BIP 324 encrypted transport: try it first on outbound connections, falling back to v1
+    // when the peer does not answer it, and accept it from inbound peers besides v1
+    #[clap(long, env = "V2_TRANSPORT")]
+    pub v2_transport: bool,
+
+    // Our address announced in the version message, e.g. the public address of a node behind a NAT.
+    // Otherwise the address of the listener is announced to inbound peers, and none to outbound peers
+    #[clap(long, env = "EXTERNAL_ADDRESS")]
+    pub external_address: Option<SocketAddr>,
+
+    // Ask the peers not to announce transactions to us, only blocks are relayed
+    #[clap(long, env = "BLOCKS_ONLY")]
+    pub blocks_only: bool,
+
+    // The median clock offset of the peers further than this from our clock is not applied but reported
+    #[clap(long, env = "MAX_TIME_ADJUSTMENT", value_parser = parse_seconds, default_value = "4200")]
+    pub max_time_adjustment: Duration,
+
+    // The height of our best chain announced in the version message, set from code only
+    #[clap(skip = default_chain_height())]
+    pub chain_height: Arc<dyn ChainHeight>,
+
+    // Decide whether the version of a remote peer is acceptable, set from code only
+    #[clap(skip = default_version_policy())]
+    pub version_policy: Arc<dyn VersionPolicy>,
+
+    // The nonces of our handshakes in progress, shared by the clones of the configuration to detect self connections
+    #[clap(skip)]
+    pub(crate) local_nonces: LocalNonces,
+
+    // The clock offsets of the outbound peers, shared by the clones of the configuration
+    #[clap(skip)]
+    pub network_time: NetworkTime,
+}
+
+impl BitcoinConfiguration {
+    pub fn builder() -> BitcoinConfigurationBuilder {
+        BitcoinConfigurationBuilder::default()
+    }
+
+    // The proxy outbound connections go through, if any
+    pub fn socks5_proxy(&self) -> Option<Socks5Proxy> {
+        self.proxy.map(|address| {
+            let credentials = if self.proxy_randomize_credentials {
+                ProxyCredentials::Randomized
+            } else {
+                ProxyCredentials::None
+            };
+            Socks5Proxy::new(address).with_credentials(credentials)
+        })
+    }
+}
+
+impl Default for BitcoinConfiguration {
+    fn default() -> Self {
+        BitcoinConfiguration {
+            discover_remote_peer_address: None,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            network: Network::default(),
+            services: DEFAULT_SERVICES,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            message_timeout: DEFAULT_MESSAGE_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            target_outbound: DEFAULT_TARGET_OUTBOUND,
+            max_concurrent_dials: DEFAULT_MAX_CONCURRENT_DIALS,
+            max_inbound: DEFAULT_MAX_INBOUND,
+            address_file: None,
+            proxy: None,
+            proxy_randomize_credentials: true,
+            v2_transport: false,
+            external_address: None,
+            blocks_only: false,
+            max_time_adjustment: DEFAULT_MAX_TIME_ADJUSTMENT,
+            chain_height: default_chain_height(),
+            version_policy: default_version_policy(),
+            local_nonces: LocalNonces::default(),
+            network_time: NetworkTime::default(),
+        }
+    }
+}
+
+// Build a configuration from code, every parameter that is not set keeps the default of the command line
+#[derive(Debug, Default)]
+pub struct BitcoinConfigurationBuilder {
+    config: BitcoinConfiguration,
+}
+
+impl BitcoinConfigurationBuilder {
+    pub fn remote_address(mut self, address: SocketAddr) -> Self {
+        self.config.discover_remote_peer_address = Some(address);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.config.services = services;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    pub fn message_timeout(mut self, timeout: Duration) -> Self {
+        self.config.message_timeout = timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.config.ping_interval = interval;
+        self
+    }
+
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.config.ping_timeout = timeout;
+        self
+    }
+
+    pub fn target_outbound(mut self, target: usize) -> Self {
+        self.config.target_outbound = target;
+        self
+    }
+
+    pub fn max_concurrent_dials(mut self, dials: usize) -> Self {
+        self.config.max_concurrent_dials = dials;
+        self
+    }
+
+    pub fn max_inbound(mut self, inbound: usize) -> Self {
+        self.config.max_inbound = inbound;
+        self
+    }
+
+    pub fn address_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.address_file = Some(path.into());
+        self
+    }
+
+    pub fn proxy(mut self, address: SocketAddr) -> Self {
+        self.config.proxy = Some(address);
+        self
+    }
+
+    pub fn proxy_randomize_credentials(mut self, randomize: bool) -> Self {
+        self.config.proxy_randomize_credentials = randomize;
+        self
+    }
+
+    pub fn v2_transport(mut self, enabled: bool) -> Self {
+        self.config.v2_transport = enabled;
+        self
+    }
+
+    pub fn external_address(mut self, address: SocketAddr) -> Self {
+        self.config.external_address = Some(address);
+        self
+    }
+
+    pub fn blocks_only(mut self, blocks_only: bool) -> Self {
+        self.config.blocks_only = blocks_only;
+        self
+    }
+
+    pub fn max_time_adjustment(mut self, adjustment: Duration) -> Self {
+        self.config.max_time_adjustment = adjustment;
+        self
+    }
+
+    pub fn chain_height(mut self, height: impl ChainHeight + 'static) -> Self {
+        self.config.chain_height = Arc::new(height);
+        self
+    }
+
+    pub fn version_policy(mut self, policy: impl VersionPolicy + 'static) -> Self {
+        self.config.version_policy = Arc::new(policy);
+        self
+    }
+
+    pub fn build(self) -> BitcoinConfiguration {
+        self.config
+    }
+}
+
+fn default_version_policy() -> Arc<dyn VersionPolicy> {
+    Arc::new(StandardVersionPolicy::default())
+}
+
+fn default_chain_height() -> Arc<dyn ChainHeight> {
+    Arc::new(0)
+}
+
+// Accept either `ip:port` or a bare ip, the latter is left with port 0 to be replaced by the default port of the network
+fn parse_remote_address(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|_| format!("invalid remote address: {}", value))
+}
+
+// Accept fractions of a second as well, e.g. 0.5
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_keeps_command_line_defaults() {
+        let parsed = BitcoinConfiguration::try_parse_from(["test"]).unwrap();
+        let built = BitcoinConfiguration::builder().build();
+        assert_eq!(built.user_agent, parsed.user_agent);
+        assert_eq!(built.network, parsed.network);
+        assert_eq!(built.services, parsed.services);
+        assert_eq!(built.connect_timeout, parsed.connect_timeout);
+        assert_eq!(built.message_timeout, parsed.message_timeout);
+        assert_eq!(built.handshake_timeout, parsed.handshake_timeout);
+        assert_eq!(built.ping_interval, parsed.ping_interval);
+        assert_eq!(built.ping_timeout, parsed.ping_timeout);
+        assert_eq!(built.target_outbound, parsed.target_outbound);
+        assert_eq!(built.max_concurrent_dials, parsed.max_concurrent_dials);
+        assert_eq!(built.max_inbound, parsed.max_inbound);
+        assert_eq!(built.address_file, parsed.address_file);
+        assert_eq!(built.proxy, parsed.proxy);
+        assert_eq!(
+            built.proxy_randomize_credentials,
+            parsed.proxy_randomize_credentials
+        );
+        assert_eq!(built.v2_transport, parsed.v2_transport);
+        assert_eq!(built.external_address, parsed.external_address);
+        assert_eq!(built.blocks_only, parsed.blocks_only);
+        assert_eq!(built.max_time_adjustment, parsed.max_time_adjustment);
+        assert_eq!(built.chain_height.height(), parsed.chain_height.height());
+    }
+
+    #[test]
+    fn builder_sets_every_parameter() {
+        let address: SocketAddr = "10.0.0.1:18444".parse().unwrap();
+        let policy = StandardVersionPolicy {
+            min_protocol_version: 70015,
+            required_services: ServiceFlags::NETWORK,
+            max_clock_offset: Duration::from_secs(60),
+        };
+        let config = BitcoinConfiguration::builder()
+            .remote_address(address)
+            .user_agent("/test:0.1/")
+            .network(Network::Regtest)
+            .services(ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS)
+            .connect_timeout(Duration::from_millis(500))
+            .message_timeout(Duration::from_secs(1))
+            .handshake_timeout(Duration::from_secs(2))
+            .ping_interval(Duration::from_secs(30))
+            .ping_timeout(Duration::from_secs(10))
+            .target_outbound(4)
+            .max_concurrent_dials(2)
+            .max_inbound(16)
+            .address_file("peers.json")
+            .proxy("127.0.0.1:9050".parse().unwrap())
+            .proxy_randomize_credentials(false)
+            .v2_transport(true)
+            .external_address("203.0.113.7:8333".parse().unwrap())
+            .blocks_only(true)
+            .max_time_adjustment(Duration::from_secs(600))
+            .chain_height(840_000)
+            .version_policy(policy.clone())
+            .build();
+        assert_eq!(config.discover_remote_peer_address, Some(address));
+        assert_eq!(config.user_agent, "/test:0.1/");
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(
+            config.services,
+            ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS
+        );
+        assert_eq!(config.connect_timeout, Duration::from_millis(500));
+        assert_eq!(config.message_timeout, Duration::from_secs(1));
+        assert_eq!(config.handshake_timeout, Duration::from_secs(2));
+        assert_eq!(config.ping_interval, Duration::from_secs(30));
+        assert_eq!(config.ping_timeout, Duration::from_secs(10));
+        assert_eq!(config.target_outbound, 4);
+        assert_eq!(config.max_concurrent_dials, 2);
+        assert_eq!(config.max_inbound, 16);
+        assert_eq!(config.address_file, Some(PathBuf::from("peers.json")));
+        assert_eq!(
+            config.socks5_proxy(),
+            Some(Socks5Proxy::new("127.0.0.1:9050".parse().unwrap()))
+        );
+        assert!(config.v2_transport);
+        assert_eq!(
+            config.external_address,
+            Some("203.0.113.7:8333".parse().unwrap())
+        );
+        assert!(config.blocks_only);
+        assert_eq!(config.max_time_adjustment, Duration::from_secs(600));
+        assert_eq!(config.chain_height.height(), 840_000);
+        // the policy is a trait object, compared through its debug output
+        assert_eq!(
+            format!("{:?}", config.version_policy),
+            format!("{:?}", policy)
+        );
+    }
+
+    #[test]
+    fn parse_services() {
+        let config =
+            BitcoinConfiguration::try_parse_from(["test", "--services", "NETWORK|BLOOM"]).unwrap();
+        assert_eq!(config.services, ServiceFlags::NETWORK | ServiceFlags::BLOOM);
+        assert!(
+            BitcoinConfiguration::try_parse_from(["test", "--services", "EVERYTHING"]).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_fractional_timeouts() {
+        let config =
+            BitcoinConfiguration::try_parse_from(["test", "--message-timeout", "0.25"]).unwrap();
+        assert_eq!(config.message_timeout, Duration::from_millis(250));
+        assert!(BitcoinConfiguration::try_parse_from(["test", "--message-timeout", "-1"]).is_err());
+    }
 }
@@ -0,0 +1,102 @@
+use clap::ValueEnum;
+use strum::{Display, EnumIter, IntoEnumIterator};
+
+/// The Bitcoin chain a peer belongs to.
+///
+/// Every message header starts with the magic of the network, peers of other networks are rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Display, EnumIter, ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet3,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// The message start bytes, as read from the wire in little-endian order.
+    pub fn magic(&self) -> u32 {
+        match self {
+            Network::Mainnet => 0xD9B4BEF9,
+            Network::Testnet3 => 0x0709110B,
+            Network::Testnet4 => 0x283F161C,
+            // the magic of the default signet, custom signets derive it from their challenge
+            Network::Signet => 0x40CF030A,
+            Network::Regtest => 0xDAB5BFFA,
+        }
+    }
+
+    /// The network a magic value belongs to, if any.
+    pub fn from_magic(magic: u32) -> Option<Network> {
+        Network::iter().find(|network| network.magic() == magic)
+    }
+
+    /// The port nodes of this network listen on by default.
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet3 => 18333,
+            Network::Testnet4 => 48333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    /// The DNS seeds answering with addresses of reachable nodes of this network.
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        match self {
+            Network::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            Network::Testnet3 => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            Network::Testnet4 => &[
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            Network::Signet => &[
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achownodes.xyz",
+            ],
+            // regtest nodes are local, there is nothing to seed from
+            Network::Regtest => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_identifies_network() {
+        for network in Network::iter() {
+            assert_eq!(Network::from_magic(network.magic()), Some(network));
+        }
+        assert_eq!(Network::from_magic(0x12345678), None);
+    }
+
+    #[test]
+    fn mainnet_magic_bytes_on_the_wire() {
+        assert_eq!(
+            Network::Mainnet.magic().to_le_bytes(),
+            [0xF9, 0xBE, 0xB4, 0xD9]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+// The median of the peers is trusted up to 70 minutes away from our clock, as in Bitcoin Core
+pub const DEFAULT_MAX_TIME_ADJUSTMENT: Duration = Duration::from_secs(70 * 60);
+
+// Older samples are dropped once this many peers were heard
+const MAX_SAMPLES: usize = 200;
+
+// The median is meaningless with fewer peers, our own clock is used until then
+const MIN_SAMPLES: usize = 5;
+
+/// A change in how our clock compares with the clocks of the peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockEvent {
+    /// The median offset of the peers is beyond the maximum adjustment, our clock is likely wrong.
+    Deviates { offset: i64 },
+    /// The median offset is within the maximum adjustment again.
+    Synchronized { offset: i64 },
+}
+
+/// The network-adjusted time: our clock corrected by the median clock offset of the peers.
+///
+/// Every peer counts once, clones share the same samples so every connection made from the same configuration contributes.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkTime(Arc<Mutex<TimeSamples>>);
+
+#[derive(Debug, Default)]
+struct TimeSamples {
+    // the address or host of the peers whose sample is kept, a peer counts once
+    peers: HashSet<String>,
+    // the offset of every kept peer, oldest first
+    offsets: VecDeque<(String, i64)>,
+    // the median applied to our clock, 0 while it is not trusted
+    adjustment: i64,
+    deviates: bool,
+    subscribers: Vec<mpsc::UnboundedSender<ClockEvent>>,
+}
+
+impl NetworkTime {
+    /// Record the clock offset of a peer, the remote clock minus ours in seconds.
+    /// The peer is named by its address, or by its host when it has one, e.g. a .onion address.
+    ///
+    /// A median further than `max_adjustment` from our clock is not applied and reported as a `ClockEvent::Deviates`.
+    pub fn add_sample(&self, peer: impl Into<String>, offset: i64, max_adjustment: Duration) {
+        let mut samples = self.0.lock().expect("network time lock poisoned");
+        let peer = peer.into();
+        if !samples.peers.insert(peer.clone()) {
+            return;
+        }
+        // the oldest peer is forgotten with its sample, both stay bounded
+        if samples.offsets.len() == MAX_SAMPLES {
+            if let Some((oldest, _)) = samples.offsets.pop_front() {
+                samples.peers.remove(&oldest);
+            }
+        }
+        samples.offsets.push_back((peer, offset));
+        if samples.offsets.len() < MIN_SAMPLES {
+            return;
+        }
+
+        let median = median(&samples.offsets);
+        let deviates = median.unsigned_abs() > max_adjustment.as_secs();
+        samples.adjustment = if deviates { 0 } else { median };
+        if deviates == samples.deviates {
+            return;
+        }
+        samples.deviates = deviates;
+        let event = if deviates {
+            warn!(
+                "the clocks of the peers are {} seconds away from ours, please check the date and time of this computer",
+                median
+            );
+            ClockEvent::Deviates { offset: median }
+        } else {
+            info!("our clock agrees with the peers again, offset: {}", median);
+            ClockEvent::Synchronized { offset: median }
+        };
+        samples
+            .subscribers
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+
+    /// The offset applied to our clock, in seconds.
+    pub fn offset(&self) -> i64 {
+        self.0
+            .lock()
+            .expect("network time lock poisoned")
+            .adjustment
+    }
+
+    /// The network-adjusted time, in seconds since the unix epoch.
+    pub fn now(&self) -> i64 {
+        chrono::Utc::now().timestamp().saturating_add(self.offset())
+    }
+
+    /// Receive the clock events from now on.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<ClockEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.0
+            .lock()
+            .expect("network time lock poisoned")
+            .subscribers
+            .push(sender);
+        receiver
+    }
+}
+
+// The middle offset, the mean of both middle offsets for an even count
+fn median(offsets: &VecDeque<(String, i64)>) -> i64 {
+    let mut sorted: Vec<i64> = offsets.iter().map(|(_, offset)| *offset).collect();
+    sorted.sort_unstable();
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        ((sorted[middle - 1] as i128 + sorted[middle] as i128) / 2) as i64
+    } else {
+        sorted[middle]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(index: usize) -> String {
+        format!("10.0.{}.{}:8333", index / 256, index % 256)
+    }
+
+    #[test]
+    fn adjust_by_median_of_peers() {
+        let time = NetworkTime::default();
+        for (index, offset) in [10, -5, 20, 3].into_iter().enumerate() {
+            time.add_sample(peer(index), offset, DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        // too few peers to trust them
+        assert_eq!(time.offset(), 0);
+
+        time.add_sample(peer(4), 7, DEFAULT_MAX_TIME_ADJUSTMENT);
+        assert_eq!(time.offset(), 7);
+        // a peer counts once, however often it connects
+        for _ in 0..10 {
+            time.add_sample(peer(4), 1000, DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        assert_eq!(time.offset(), 7);
+        assert!((time.now() - chrono::Utc::now().timestamp() - 7).abs() <= 1);
+    }
+
+    #[test]
+    fn warn_when_clock_deviates() {
+        let time = NetworkTime::default();
+        let mut events = time.subscribe();
+        let max_adjustment = Duration::from_secs(60);
+        for index in 0..5 {
+            time.add_sample(peer(index), 3600, max_adjustment);
+        }
+        assert_eq!(time.offset(), 0);
+        assert_eq!(events.try_recv(), Ok(ClockEvent::Deviates { offset: 3600 }));
+
+        for index in 5..11 {
+            time.add_sample(peer(index), 30, max_adjustment);
+        }
+        assert_eq!(time.offset(), 30);
+        assert_eq!(
+            events.try_recv(),
+            Ok(ClockEvent::Synchronized { offset: 30 })
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn keep_latest_samples() {
+        let time = NetworkTime::default();
+        for index in 0..MAX_SAMPLES {
+            time.add_sample(peer(index), 100, DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        assert_eq!(time.offset(), 100);
+        for index in MAX_SAMPLES..2 * MAX_SAMPLES {
+            time.add_sample(peer(index), -100, DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        assert_eq!(time.offset(), -100);
+        // only the peers of the kept samples are remembered
+        assert_eq!(time.0.lock().unwrap().peers.len(), MAX_SAMPLES);
+        time.add_sample(peer(0), -100, DEFAULT_MAX_TIME_ADJUSTMENT);
+        assert_eq!(time.0.lock().unwrap().offsets.len(), MAX_SAMPLES);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+/// The credentials sent to the proxy.
+///
+/// Tor isolates the streams opened with different credentials on different circuits,
+/// `Randomized` uses new credentials for every connection so the peers cannot be linked together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyCredentials {
+    None,
+    Fixed { username: String, password: String },
+    Randomized,
+}
+
+/// A SOCKS5 proxy the outbound connections are routed through, e.g. Tor on 127.0.0.1:9050.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    address: SocketAddr,
+    credentials: ProxyCredentials,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: SocketAddr) -> Self {
+        Socks5Proxy {
+            address,
+            credentials: ProxyCredentials::None,
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: ProxyCredentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Connect to the target through the proxy.
+    // The host is resolved by the proxy unless it is an ip, which is how .onion addresses are reached.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let stream = match &self.credentials {
+            ProxyCredentials::None => Socks5Stream::connect(self.address, (host, port)).await,
+            ProxyCredentials::Fixed { username, password } => {
+                Socks5Stream::connect_with_password(self.address, (host, port), username, password)
+                    .await
+            }
+            ProxyCredentials::Randomized => {
+                let username = format!("{:016x}", rand::random::<u64>());
+                let password = format!("{:016x}", rand::random::<u64>());
+                Socks5Stream::connect_with_password(
+                    self.address,
+                    (host, port),
+                    &username,
+                    &password,
+                )
+                .await
+            }
+        };
+        // once connected the proxy relays the bytes as they are, the tcp stream is used directly
+        stream.map(Socks5Stream::into_inner).map_err(into_io_error)
+    }
+}
+
+fn into_io_error(error: tokio_socks::Error) -> io::Error {
+    match error {
+        tokio_socks::Error::Io(e) => e,
+        tokio_socks::Error::ConnectionRefused => {
+            io::Error::new(ErrorKind::ConnectionRefused, error)
+        }
+        tokio_socks::Error::TtlExpired => io::Error::new(ErrorKind::TimedOut, error),
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv6Addr,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::bitcoin::{
+        bitcoin_connection_info::BitcoinConnectionInfo,
+        fake_node::{regtest_builder, regtest_config},
+        fake_node::{FakeNode, FakeNodeBehaviour},
+        network::Network,
+        BitcoinConfiguration, BitcoinConnectionProtocol, BitcoinHandshakeError,
+    };
+
+    // A proxy request: the requested host and port, and the username if any
+    type ProxyRequest = (String, u16, Option<String>);
+
+    // A local stand-in for a SOCKS5 proxy, relaying every connection to the same upstream
+    // whatever the requested target, and remembering the requests
+    async fn stand_in_proxy(
+        upstream: SocketAddr,
+    ) -> io::Result<(SocketAddr, Arc<Mutex<Vec<ProxyRequest>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let requests = recorded.clone();
+                tokio::spawn(async move {
+                    let request = accept_request(&mut client).await?;
+                    requests.lock().unwrap().push(request);
+                    let mut upstream = TcpStream::connect(upstream).await?;
+                    // succeeded, bound to 0.0.0.0:0
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    Ok::<_, io::Error>(())
+                });
+            }
+        });
+        Ok((address, requests))
+    }
+
+    // The server side of RFC 1928 and RFC 1929, up to the connect request
+    async fn accept_request(client: &mut TcpStream) -> io::Result<ProxyRequest> {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        client.read_exact(&mut methods).await?;
+        let username = if methods.contains(&2) {
+            client.write_all(&[5, 2]).await?;
+            client.read_exact(&mut header).await?;
+            let mut username = vec![0u8; header[1] as usize];
+            client.read_exact(&mut username).await?;
+            let mut password = vec![0u8; client.read_u8().await? as usize];
+            client.read_exact(&mut password).await?;
+            client.write_all(&[1, 0]).await?;
+            Some(String::from_utf8_lossy(&username).into_owned())
+        } else {
+            client.write_all(&[5, 0]).await?;
+            None
+        };
+
+        let mut request = [0u8; 4];
+        client.read_exact(&mut request).await?;
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                client.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                let mut domain = vec![0u8; client.read_u8().await? as usize];
+                client.read_exact(&mut domain).await?;
+                String::from_utf8_lossy(&domain).into_owned()
+            }
+            _ => {
+                let mut ip = [0u8; 16];
+                client.read_exact(&mut ip).await?;
+                Ipv6Addr::from(ip).to_string()
+            }
+        };
+        let port = client.read_u16().await?;
+        Ok((host, port, username))
+    }
+
+    fn config(proxy: SocketAddr, randomize: bool) -> Arc<BitcoinConfiguration> {
+        Arc::new(
+            regtest_builder()
+                .proxy(proxy)
+                .proxy_randomize_credentials(randomize)
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn handshake_through_proxy() -> Result<(), Box<dyn std::error::Error>> {
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let (proxy, requests) = stand_in_proxy(node.local_addr()).await?;
+        let target: SocketAddr = "10.0.0.1:18444".parse()?;
+
+        BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(target, Network::Regtest),
+            config(proxy, false),
+        )
+        .connect()
+        .await?;
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [("10.0.0.1".to_owned(), 18444, None)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn onion_host_is_resolved_by_the_proxy() -> Result<(), Box<dyn std::error::Error>> {
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let (proxy, requests) = stand_in_proxy(node.local_addr()).await?;
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+
+        BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 18444),
+                Network::Regtest,
+            )
+            .with_host(onion),
+            config(proxy, false),
+        )
+        .connect()
+        .await?;
+
+        assert_eq!(*requests.lock().unwrap(), [(onion.to_owned(), 18444, None)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn isolate_every_connection() -> Result<(), Box<dyn std::error::Error>> {
+        let node = FakeNode::new(Network::Regtest, FakeNodeBehaviour::Normal)
+            .listen()
+            .await?;
+        let (proxy, requests) = stand_in_proxy(node.local_addr()).await?;
+        let config = config(proxy, true);
+
+        for _ in 0..2 {
+            BitcoinConnectionProtocol::new(
+                BitcoinConnectionInfo::new("10.0.0.1:18444".parse()?, Network::Regtest),
+                config.clone(),
+            )
+            .connect()
+            .await?;
+        }
+
+        let usernames: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, _, username)| username.clone().expect("credentials are sent"))
+            .collect();
+        assert_ne!(usernames[0], usernames[1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn onion_requires_a_proxy() {
+        let result = BitcoinConnectionProtocol::new(
+            BitcoinConnectionInfo::new(
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 18444),
+                Network::Regtest,
+            )
+            .with_host("pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion"),
+            Arc::new(regtest_config()),
+        )
+        .connect()
+        .await;
+        assert!(matches!(
+            result,
+            Err(BitcoinHandshakeError::ConnectionFailed(reason)) if reason.contains("proxy")
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::{self, ErrorKind};
+
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::bitcoin::network::Network;
+
+// Both ciphers switch to a new key after this many messages
+const REKEY_INTERVAL: u32 = 224;
+
+pub(super) const KEY_LENGTH: usize = 32;
+pub(super) const TAG_LENGTH: usize = 16;
+pub(super) const GARBAGE_TERMINATOR_LENGTH: usize = 16;
+
+// The keys of one side of a session, derived from the ECDH secret
+pub(super) struct SessionKeys {
+    pub(super) send_length: [u8; KEY_LENGTH],
+    pub(super) send_packet: [u8; KEY_LENGTH],
+    pub(super) receive_length: [u8; KEY_LENGTH],
+    pub(super) receive_packet: [u8; KEY_LENGTH],
+    pub(super) send_garbage_terminator: [u8; GARBAGE_TERMINATOR_LENGTH],
+    pub(super) receive_garbage_terminator: [u8; GARBAGE_TERMINATOR_LENGTH],
+    pub(super) session_id: [u8; 32],
+}
+
+impl SessionKeys {
+    pub(super) fn derive(secret: &[u8; 32], network: Network, initiator: bool) -> Self {
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&network.magic().to_le_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), secret);
+        let expand = |info: &str| {
+            let mut key = [0u8; 32];
+            hkdf.expand(info.as_bytes(), &mut key)
+                .expect("32 bytes is a valid length for HKDF-SHA256");
+            key
+        };
+
+        let (initiator_length, initiator_packet) = (expand("initiator_L"), expand("initiator_P"));
+        let (responder_length, responder_packet) = (expand("responder_L"), expand("responder_P"));
+        let terminators = expand("garbage_terminators");
+        let mut initiator_terminator = [0u8; GARBAGE_TERMINATOR_LENGTH];
+        initiator_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LENGTH]);
+        let mut responder_terminator = [0u8; GARBAGE_TERMINATOR_LENGTH];
+        responder_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LENGTH..]);
+
+        if initiator {
+            SessionKeys {
+                send_length: initiator_length,
+                send_packet: initiator_packet,
+                receive_length: responder_length,
+                receive_packet: responder_packet,
+                send_garbage_terminator: initiator_terminator,
+                receive_garbage_terminator: responder_terminator,
+                session_id: expand("session_id"),
+            }
+        } else {
+            SessionKeys {
+                send_length: responder_length,
+                send_packet: responder_packet,
+                receive_length: initiator_length,
+                receive_packet: initiator_packet,
+                send_garbage_terminator: responder_terminator,
+                receive_garbage_terminator: initiator_terminator,
+                session_id: expand("session_id"),
+            }
+        }
+    }
+}
+
+// The nonce of both ciphers: a 32 bits counter followed by the 64 bits number of rekeys
+fn nonce(counter: u32, rekeys: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&counter.to_le_bytes());
+    nonce[4..].copy_from_slice(&rekeys.to_le_bytes());
+    nonce
+}
+
+// FSChaCha20: encrypts the 3 bytes length of every packet, one keystream per rekey interval
+pub(super) struct LengthCipher {
+    cipher: ChaCha20,
+    chunks: u32,
+    rekeys: u64,
+}
+
+impl LengthCipher {
+    pub(super) fn new(key: [u8; KEY_LENGTH]) -> Self {
+        LengthCipher {
+            cipher: ChaCha20::new(&key.into(), &nonce(0, 0).into()),
+            chunks: 0,
+            rekeys: 0,
+        }
+    }
+
+    // Encryption and decryption are the same operation
+    pub(super) fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunks += 1;
+        if self.chunks == REKEY_INTERVAL {
+            // the next key is the keystream following the last chunk
+            let mut key = [0u8; KEY_LENGTH];
+            self.cipher.apply_keystream(&mut key);
+            self.chunks = 0;
+            self.rekeys += 1;
+            self.cipher = ChaCha20::new(&key.into(), &nonce(0, self.rekeys).into());
+        }
+    }
+}
+
+// FSChaCha20Poly1305: authenticates and encrypts the contents of every packet
+pub(super) struct PacketCipher {
+    key: [u8; KEY_LENGTH],
+    packets: u32,
+    rekeys: u64,
+}
+
+impl PacketCipher {
+    pub(super) fn new(key: [u8; KEY_LENGTH]) -> Self {
+        PacketCipher {
+            key,
+            packets: 0,
+            rekeys: 0,
+        }
+    }
+
+    // The ciphertext followed by its tag
+    pub(super) fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let ciphertext = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt(
+                &nonce(self.packets, self.rekeys).into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("packets are far below the length limit of the cipher");
+        self.next_packet();
+        ciphertext
+    }
+
+    pub(super) fn decrypt(&mut self, ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let plaintext = ChaCha20Poly1305::new(&self.key.into())
+            .decrypt(
+                &nonce(self.packets, self.rekeys).into(),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Packet authentication failed"))?;
+        self.next_packet();
+        Ok(plaintext)
+    }
+
+    fn next_packet(&mut self) {
+        self.packets += 1;
+        if self.packets == REKEY_INTERVAL {
+            self.key = self.next_key();
+            self.packets = 0;
+            self.rekeys += 1;
+        }
+    }
+
+    // The encryption of 32 zero bytes under a nonce no packet uses, without its tag.
+    // It starts at the second keystream block, the first one is the Poly1305 key
+    fn next_key(&self) -> [u8; KEY_LENGTH] {
+        let ciphertext = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt(
+                &nonce(u32::MAX, self.rekeys).into(),
+                [0u8; KEY_LENGTH].as_slice(),
+            )
+            .expect("32 bytes are far below the length limit of the cipher");
+        let mut key = [0u8; KEY_LENGTH];
+        key.copy_from_slice(&ciphertext[..KEY_LENGTH]);
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_derive_matching_keys() {
+        let secret = [7u8; 32];
+        let initiator = SessionKeys::derive(&secret, Network::Mainnet, true);
+        let responder = SessionKeys::derive(&secret, Network::Mainnet, false);
+        assert_eq!(initiator.send_length, responder.receive_length);
+        assert_eq!(initiator.send_packet, responder.receive_packet);
+        assert_eq!(initiator.receive_packet, responder.send_packet);
+        assert_eq!(
+            initiator.send_garbage_terminator,
+            responder.receive_garbage_terminator
+        );
+        assert_eq!(initiator.session_id, responder.session_id);
+        assert_ne!(initiator.send_packet, initiator.receive_packet);
+        // the magic is part of the salt, sessions of other networks do not match
+        let other = SessionKeys::derive(&secret, Network::Regtest, true);
+        assert_ne!(initiator.send_packet, other.send_packet);
+    }
+
+    #[test]
+    fn ciphers_stay_in_step_across_rekeys() {
+        let (mut encrypt_length, mut decrypt_length) =
+            (LengthCipher::new([1; 32]), LengthCipher::new([1; 32]));
+        let (mut encrypt_packet, mut decrypt_packet) =
+            (PacketCipher::new([2; 32]), PacketCipher::new([2; 32]));
+        let mut previous = Vec::new();
+        for i in 0..(2 * REKEY_INTERVAL + 1) {
+            let mut length = [i as u8, 0, 1];
+            encrypt_length.crypt(&mut length);
+            decrypt_length.crypt(&mut length);
+            assert_eq!(length, [i as u8, 0, 1]);
+
+            let ciphertext = encrypt_packet.encrypt(b"same contents", b"");
+            assert_ne!(ciphertext, previous);
+            assert_eq!(
+                decrypt_packet.decrypt(&ciphertext, b"").unwrap(),
+                b"same contents"
+            );
+            previous = ciphertext;
+        }
+        assert_eq!(encrypt_packet.rekeys, 2);
+    }
+
+    #[test]
+    fn rekey_from_second_keystream_block() {
+        let mut cipher = PacketCipher::new([4; 32]);
+        for _ in 0..REKEY_INTERVAL {
+            cipher.encrypt(b"", b"");
+        }
+        // the keystream of the rekey nonce, block 0 keys Poly1305 and is skipped
+        let mut keystream = [0u8; 2 * 64];
+        ChaCha20::new(&[4; 32].into(), &nonce(u32::MAX, 0).into()).apply_keystream(&mut keystream);
+        assert_eq!(cipher.key, keystream[64..64 + KEY_LENGTH]);
+        assert_eq!(cipher.rekeys, 1);
+    }
+
+    #[test]
+    fn reject_tampered_packet() {
+        let mut ciphertext = PacketCipher::new([3; 32]).encrypt(b"contents", b"garbage");
+        assert!(PacketCipher::new([3; 32])
+            .decrypt(&ciphertext, b"other garbage")
+            .is_err());
+        ciphertext[0] ^= 1;
+        assert!(PacketCipher::new([3; 32])
+            .decrypt(&ciphertext, b"garbage")
+            .is_err());
+    }
+}
@@ -0,0 +1,234 @@
+// BIP 324: the v2 transport encrypting the connection between two peers.
+// The keys are exchanged before the version handshake, which then runs unchanged over the encrypted packets.
+mod cipher;
+mod stream;
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+pub use stream::{accept_v2, connect_v2, V2Stream};
+
+use super::{handshake::Transport, network::Network};
+
+// The length of the v1 header prefix telling a v1 version message from a v2 public key: magic and command
+pub(crate) const V1_PREFIX_LENGTH: usize = 16;
+
+// The commands sent as a single byte, the position in the table is the short id minus one
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+// Whether the first bytes received from an inbound peer are a v1 version message rather than a v2 public key
+pub(crate) fn is_v1_version_prefix(bytes: &[u8], network: Network) -> bool {
+    let mut prefix = [0u8; V1_PREFIX_LENGTH];
+    prefix[..4].copy_from_slice(&network.magic().to_le_bytes());
+    prefix[4..11].copy_from_slice(b"version");
+    bytes.len() >= V1_PREFIX_LENGTH && bytes[..V1_PREFIX_LENGTH] == prefix
+}
+
+// The start of the packet contents for a v1 command: its short id, or 0 followed by the 12 bytes command
+fn contents_from_command(command: &[u8; 12]) -> Vec<u8> {
+    let name = command.split(|byte| *byte == 0).next().unwrap_or_default();
+    match SHORT_IDS.iter().position(|id| id.as_bytes() == name) {
+        Some(position) => vec![position as u8 + 1],
+        None => {
+            let mut contents = Vec::with_capacity(13);
+            contents.push(0);
+            contents.extend_from_slice(command);
+            contents
+        }
+    }
+}
+
+// The v1 command and the payload of the contents of a packet
+fn command_from_contents(contents: &[u8]) -> io::Result<([u8; 12], &[u8])> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_owned());
+    let (&id, rest) = contents
+        .split_first()
+        .ok_or_else(|| invalid("Packet without a message type"))?;
+    if id == 0 {
+        if rest.len() < 12 {
+            return Err(invalid("Packet with a truncated message type"));
+        }
+        let (command, payload) = rest.split_at(12);
+        return Ok((command.try_into().expect("12 bytes slice"), payload));
+    }
+    let name = SHORT_IDS
+        .get(id as usize - 1)
+        .ok_or_else(|| invalid("Packet with an unknown short message type"))?;
+    let mut command = [0u8; 12];
+    command[..name.len()].copy_from_slice(name.as_bytes());
+    Ok((command, rest))
+}
+
+/// The connection to a peer, in plaintext (v1) or encrypted with BIP 324 (v2).
+pub enum PeerStream<S = TcpStream> {
+    V1(S),
+    // boxed, the ciphers and buffers are much larger than a socket
+    V2(Box<V2Stream<S>>),
+}
+
+impl<S> PeerStream<S> {
+    pub fn is_v2(&self) -> bool {
+        matches!(self, PeerStream::V2(_))
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for PeerStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerStream::V1(stream) => f.debug_tuple("V1").field(stream).finish(),
+            PeerStream::V2(stream) => f.debug_tuple("V2").field(stream).finish(),
+        }
+    }
+}
+
+impl<S: Transport> AsyncRead for PeerStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::V1(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::V2(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: Transport> AsyncWrite for PeerStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::V1(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::V2(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::V1(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::V2(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::V1(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::V2(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::bitcoin::messages::{BitcoinCodec, BitcoinMessage, PingMessage};
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn frame(message: BitcoinMessage) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        BitcoinCodec::new(Network::Regtest)
+            .encode(message, &mut buffer)
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn short_ids_round_trip() {
+        let mut ping = [0u8; 12];
+        ping[..4].copy_from_slice(b"ping");
+        assert_eq!(contents_from_command(&ping), [18]);
+        let (command, payload) = command_from_contents(&[18, 1, 2]).unwrap();
+        assert_eq!((command, payload), (ping, &[1u8, 2][..]));
+
+        // commands without a short id are sent in full
+        let mut version = [0u8; 12];
+        version[..7].copy_from_slice(b"version");
+        let contents = contents_from_command(&version);
+        assert_eq!(contents.len(), 13);
+        assert_eq!(
+            command_from_contents(&contents).unwrap(),
+            (version, &[][..])
+        );
+        assert!(command_from_contents(&[29]).is_err());
+    }
+
+    #[test]
+    fn recognize_v1_version() {
+        let version = frame(BitcoinMessage::Version(
+            crate::bitcoin::messages::VersionMessage::new("/test:0.1/", 0),
+        ));
+        assert!(is_v1_version_prefix(&version, Network::Regtest));
+        assert!(!is_v1_version_prefix(&version, Network::Mainnet));
+        assert!(!is_v1_version_prefix(&[0xfa; 64], Network::Regtest));
+    }
+
+    #[tokio::test]
+    async fn exchange_messages_over_v2() -> Result<(), Box<dyn std::error::Error>> {
+        let (initiator, responder) = duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            connect_v2(initiator, Network::Regtest),
+            accept_v2(responder, Network::Regtest)
+        );
+        let (mut initiator, mut responder) = (initiator?, responder?);
+        assert_eq!(initiator.session_id(), responder.session_id());
+
+        let ping = BitcoinMessage::Ping(PingMessage { nonce: 42 });
+        initiator.write_all(&frame(ping.clone())).await?;
+        initiator.flush().await?;
+
+        // the responder reads back the v1 frame of the network
+        let mut buffer = BytesMut::new();
+        let mut codec = BitcoinCodec::new(Network::Regtest);
+        let received = loop {
+            if let Some(message) = codec.decode(&mut buffer)? {
+                break message;
+            }
+            responder.read_buf(&mut buffer).await?;
+        };
+        assert_eq!(received, ping);
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use rand::{Rng, RngCore};
+use secp256k1::{
+    ellswift::{ElligatorSwift, ElligatorSwiftParty},
+    Secp256k1, SecretKey,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::{
+    cipher::{LengthCipher, PacketCipher, SessionKeys, GARBAGE_TERMINATOR_LENGTH, TAG_LENGTH},
+    command_from_contents, contents_from_command,
+};
+use crate::{
+    bitcoin::{
+        handshake::Transport,
+        messages::{sha2_checksum, DecodeError, MAX_MESSAGE_SIZE},
+        network::Network,
+    },
+    HEADER_LENGTH,
+};
+
+const ELLSWIFT_LENGTH: usize = 64;
+const LENGTH_FIELD: usize = 3;
+// The header byte of a packet, only the ignore bit is defined
+const HEADER_BYTE: usize = 1;
+const IGNORE_BIT: u8 = 0x80;
+// The garbage sent after the public key is at most this long
+const MAX_GARBAGE_LENGTH: usize = 4095;
+// Encrypted bytes buffered before the writer waits for the transport
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// A BIP 324 encrypted connection.
+///
+/// Reads and writes the same plaintext v1 frames as the transport it wraps, so the handshake and
+/// `BitcoinCodec` run over it unchanged: every written frame is sent as an encrypted packet,
+/// and every received packet is read back as a v1 frame with the magic of the network.
+pub struct V2Stream<S> {
+    inner: S,
+    network: Network,
+    send_length: LengthCipher,
+    send_packet: PacketCipher,
+    receive_length: LengthCipher,
+    receive_packet: PacketCipher,
+    session_id: [u8; 32],
+    // v1 frames written but not complete yet
+    plaintext_out: BytesMut,
+    ciphertext_out: BytesMut,
+    ciphertext_in: BytesMut,
+    // the decrypted length of the packet being received
+    pending_length: Option<usize>,
+    plaintext_in: BytesMut,
+}
+
+impl<S> V2Stream<S> {
+    /// Identifies the session, both peers compute the same one.
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    fn new(inner: S, network: Network, keys: SessionKeys) -> Self {
+        V2Stream {
+            inner,
+            network,
+            send_length: LengthCipher::new(keys.send_length),
+            send_packet: PacketCipher::new(keys.send_packet),
+            receive_length: LengthCipher::new(keys.receive_length),
+            receive_packet: PacketCipher::new(keys.receive_packet),
+            session_id: keys.session_id,
+            plaintext_out: BytesMut::new(),
+            ciphertext_out: BytesMut::new(),
+            ciphertext_in: BytesMut::new(),
+            pending_length: None,
+            plaintext_in: BytesMut::new(),
+        }
+    }
+
+    fn encrypt_packet(&mut self, contents: &[u8], aad: &[u8], decoy: bool) {
+        let mut length = (contents.len() as u32).to_le_bytes();
+        self.send_length.crypt(&mut length[..LENGTH_FIELD]);
+        self.ciphertext_out.put_slice(&length[..LENGTH_FIELD]);
+
+        let mut plaintext = Vec::with_capacity(HEADER_BYTE + contents.len());
+        plaintext.push(if decoy { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+        let ciphertext = self.send_packet.encrypt(&plaintext, aad);
+        self.ciphertext_out.put_slice(&ciphertext);
+    }
+
+    // Encrypt every complete v1 frame written so far
+    fn encrypt_frames(&mut self) -> io::Result<()> {
+        while self.plaintext_out.len() >= HEADER_LENGTH {
+            let payload_length = u32::from_le_bytes(
+                self.plaintext_out[16..20]
+                    .try_into()
+                    .expect("4 bytes slice"),
+            ) as usize;
+            if self.plaintext_out.len() < HEADER_LENGTH + payload_length {
+                break;
+            }
+            let frame = self.plaintext_out.split_to(HEADER_LENGTH + payload_length);
+            let command: [u8; 12] = frame[4..16].try_into().expect("12 bytes slice");
+            let mut contents = contents_from_command(&command);
+            contents.extend_from_slice(&frame[HEADER_LENGTH..]);
+            self.encrypt_packet(&contents, &[], false);
+        }
+        Ok(())
+    }
+
+    // Decrypt the next packet once it arrived: whether it is a decoy, and its contents
+    fn decrypt_packet(&mut self, aad: &[u8]) -> io::Result<Option<(bool, Vec<u8>)>> {
+        let length = match self.pending_length {
+            Some(length) => length,
+            None => {
+                if self.ciphertext_in.len() < LENGTH_FIELD {
+                    return Ok(None);
+                }
+                let mut length = [0u8; 4];
+                length[..LENGTH_FIELD].copy_from_slice(&self.ciphertext_in[..LENGTH_FIELD]);
+                self.receive_length.crypt(&mut length[..LENGTH_FIELD]);
+                self.ciphertext_in.advance(LENGTH_FIELD);
+                let length = u32::from_le_bytes(length) as usize;
+                // the contents hold the command before the payload
+                if length > MAX_MESSAGE_SIZE + 13 {
+                    return Err(DecodeError::MessageTooLarge {
+                        size: length,
+                        max: MAX_MESSAGE_SIZE,
+                    }
+                    .into());
+                }
+                self.pending_length = Some(length);
+                length
+            }
+        };
+        let packet_length = HEADER_BYTE + length + TAG_LENGTH;
+        if self.ciphertext_in.len() < packet_length {
+            self.ciphertext_in
+                .reserve(packet_length - self.ciphertext_in.len());
+            return Ok(None);
+        }
+        let ciphertext = self.ciphertext_in.split_to(packet_length);
+        self.pending_length = None;
+        let mut plaintext = self.receive_packet.decrypt(&ciphertext, aad)?;
+        let decoy = plaintext[0] & IGNORE_BIT != 0;
+        plaintext.remove(0);
+        Ok(Some((decoy, plaintext)))
+    }
+
+    // Rebuild the v1 frame of the contents of a packet
+    fn push_frame(&mut self, contents: &[u8]) -> io::Result<()> {
+        let (command, payload) = command_from_contents(contents)?;
+        self.plaintext_in.put_u32_le(self.network.magic());
+        self.plaintext_in.put_slice(&command);
+        self.plaintext_in.put_u32_le(payload.len() as u32);
+        self.plaintext_in.put_slice(&sha2_checksum(payload));
+        self.plaintext_in.put_slice(payload);
+        Ok(())
+    }
+}
+
+impl<S: Transport> V2Stream<S> {
+    // Read more encrypted bytes, the end of the stream is an error in the middle of the handshake
+    async fn fill(&mut self) -> io::Result<()> {
+        if self.inner.read_buf(&mut self.ciphertext_in).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "The peer closed the connection during the v2 handshake",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send_handshake(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.ciphertext_out).await?;
+        self.ciphertext_out.clear();
+        self.inner.flush().await
+    }
+
+    // The garbage of the peer, everything before its garbage terminator
+    async fn receive_garbage(&mut self, terminator: &[u8]) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(position) = self
+                .ciphertext_in
+                .windows(GARBAGE_TERMINATOR_LENGTH)
+                .position(|window| window == terminator)
+            {
+                let garbage = self.ciphertext_in.split_to(position).to_vec();
+                self.ciphertext_in.advance(GARBAGE_TERMINATOR_LENGTH);
+                return Ok(garbage);
+            }
+            if self.ciphertext_in.len() >= MAX_GARBAGE_LENGTH + GARBAGE_TERMINATOR_LENGTH {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Garbage terminator not found",
+                ));
+            }
+            self.fill().await?;
+        }
+    }
+
+    // The version packet of the peer, possibly preceded by decoys. The first packet authenticates the garbage.
+    async fn receive_version(&mut self, garbage: Vec<u8>) -> io::Result<()> {
+        let mut aad = garbage;
+        loop {
+            match self.decrypt_packet(&aad)? {
+                Some((true, _)) => aad.clear(),
+                // the contents announce the transport version, none is defined beyond the empty one
+                Some((false, _)) => return Ok(()),
+                None => self.fill().await?,
+            }
+        }
+    }
+
+    // Write the encrypted bytes until none is left
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.ciphertext_out.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.ciphertext_out))?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.ciphertext_out.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Run the handshake: exchange the keys and garbage, then the garbage terminators and version packets
+async fn negotiate<S: Transport>(
+    mut inner: S,
+    network: Network,
+    initiator: bool,
+) -> io::Result<V2Stream<S>> {
+    let secret_key = loop {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        // almost every 32 bytes are a valid key
+        if let Ok(key) = SecretKey::from_slice(&bytes) {
+            break key;
+        }
+    };
+    // `ElligatorSwift::new` uses a context without the tables key generation needs
+    let ours = ElligatorSwift::from_seckey(&Secp256k1::new(), secret_key, Some(rand::random()));
+    let mut garbage = vec![0u8; rand::thread_rng().gen_range(0..=MAX_GARBAGE_LENGTH)];
+    rand::thread_rng().fill_bytes(&mut garbage);
+    inner.write_all(&ours.to_array()).await?;
+    inner.write_all(&garbage).await?;
+    inner.flush().await?;
+
+    let mut theirs = [0u8; ELLSWIFT_LENGTH];
+    inner.read_exact(&mut theirs).await?;
+    let theirs = ElligatorSwift::from_array(theirs);
+    let secret = if initiator {
+        ElligatorSwift::shared_secret(ours, theirs, secret_key, ElligatorSwiftParty::A, None)
+    } else {
+        ElligatorSwift::shared_secret(theirs, ours, secret_key, ElligatorSwiftParty::B, None)
+    };
+    let keys = SessionKeys::derive(secret.as_secret_bytes(), network, initiator);
+    let (send_terminator, receive_terminator) = (
+        keys.send_garbage_terminator,
+        keys.receive_garbage_terminator,
+    );
+
+    let mut stream = V2Stream::new(inner, network, keys);
+    stream.ciphertext_out.put_slice(&send_terminator);
+    // an empty version packet: no transport features, our garbage is authenticated with it
+    stream.encrypt_packet(&[], &garbage, false);
+    stream.send_handshake().await?;
+
+    let received_garbage = stream.receive_garbage(&receive_terminator).await?;
+    stream.receive_version(received_garbage).await?;
+    Ok(stream)
+}
+
+/// Open a v2 session as the initiator of the connection.
+pub async fn connect_v2<S: Transport>(inner: S, network: Network) -> io::Result<V2Stream<S>> {
+    negotiate(inner, network, true).await
+}
+
+/// Open a v2 session as the responder, once the peer is known not to speak v1.
+pub async fn accept_v2<S: Transport>(inner: S, network: Network) -> io::Result<V2Stream<S>> {
+    negotiate(inner, network, false).await
+}
+
+impl<S> fmt::Debug for V2Stream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("V2Stream")
+            .field("inner", &self.inner)
+            .field("network", &self.network)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Transport> AsyncRead for V2Stream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.plaintext_in.is_empty() {
+                let length = this.plaintext_in.len().min(buf.remaining());
+                buf.put_slice(&this.plaintext_in.split_to(length));
+                return Poll::Ready(Ok(()));
+            }
+            match this.decrypt_packet(&[])? {
+                Some((true, _)) => continue,
+                Some((false, contents)) => {
+                    this.push_frame(&contents)?;
+                    continue;
+                }
+                None => {}
+            }
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // the end of the stream, a partial packet is lost like a partial v1 frame
+                return Poll::Ready(Ok(()));
+            }
+            this.ciphertext_in.put_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: Transport> AsyncWrite for V2Stream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.ciphertext_out.len() >= WRITE_BUFFER {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.plaintext_out.put_slice(buf);
+        this.encrypt_frames()?;
+        // send what is ready, a busy transport is drained on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_tampered_packet() -> Result<(), Box<dyn std::error::Error>> {
+        let secret = [9u8; 32];
+        let (sender_side, mut wire) = duplex(1024);
+        let (mut tampered, receiver_side) = duplex(1024);
+        let mut sender = V2Stream::new(
+            sender_side,
+            Network::Regtest,
+            SessionKeys::derive(&secret, Network::Regtest, true),
+        );
+        let mut receiver = V2Stream::new(
+            receiver_side,
+            Network::Regtest,
+            SessionKeys::derive(&secret, Network::Regtest, false),
+        );
+
+        // a pong frame, the payload is the nonce
+        let mut frame = BytesMut::new();
+        frame.put_u32_le(Network::Regtest.magic());
+        frame.put_slice(b"pong\0\0\0\0\0\0\0\0");
+        frame.put_u32_le(8);
+        frame.put_slice(&sha2_checksum(&[0; 8]));
+        frame.put_slice(&[0; 8]);
+        sender.write_all(&frame).await?;
+        sender.flush().await?;
+
+        let mut packet = vec![0u8; LENGTH_FIELD + HEADER_BYTE + 1 + 8 + TAG_LENGTH];
+        wire.read_exact(&mut packet).await?;
+        *packet.last_mut().unwrap() ^= 1;
+        tampered.write_all(&packet).await?;
+
+        let mut buffer = [0u8; 64];
+        let error = receiver.read(&mut buffer).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+}
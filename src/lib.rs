@@ -7,33 +7,44 @@ pub const HEADER_LENGTH: usize = 24;
 #[cfg(test)]
 mod tests {
     use crate::{
-        bitcoin::{bitcoin_factory::BitcoinPeerFactory, BitcoinConfiguration},
+        bitcoin::{
+            bitcoin_factory::BitcoinPeerFactory, bitcoin_listener::BitcoinListener,
+            fake_node::regtest_config, network::Network, BitcoinConfiguration,
+        },
         protocols::peer::Peer,
     };
-    use clap::Parser;
     use tracing_test::traced_test;
 
     #[traced_test]
     #[tokio::test]
     async fn bitcoin_handshake_single_peer() -> Result<(), Box<dyn std::error::Error>> {
-        // set environment variables to point to the well known peer address
-        // try get address from https://bitnodes.io/nodes/?q=Satoshi:26.0.0
-        // let remote_peer_address = "49.13.129.99:8333";
-        let remote_peer_address = "127.0.0.1:8333";
-        let user_agent = "my test user agent";
-        // Set up the environment variable
-        std::env::set_var("DISCOVER_REMOTE_PEER_ADDRESS", remote_peer_address);
-        std::env::set_var("USER_AGENT", user_agent);
+        // the crate plays the remote peer as well, so no external node is required
+        let listener = BitcoinListener::bind("127.0.0.1:0".parse()?, regtest_config()).await?;
+        let remote_peer_address = listener.local_addr()?;
+        let remote_peer = tokio::spawn(async move { listener.accept().await });
 
         // create bitcoin peer using the factory
-        let mut local_peer = BitcoinPeerFactory::new_peer(BitcoinConfiguration::parse());
+        let mut local_peer = BitcoinPeerFactory::new_peer(
+            BitcoinConfiguration::builder()
+                .remote_address(remote_peer_address)
+                .user_agent("my test user agent")
+                .network(Network::Regtest)
+                .build(),
+        );
 
         // connect to the peer
         local_peer.connect().await?;
 
-        // // verify that the connection established correctly
-        // todo!("how to verify?")
-        // // assert_eq!(result, 4);
+        // verify that the remote side completed the handshake as well
+        let remote_connection = remote_peer.await??;
+        assert!(remote_connection.connection_info().version.is_some());
+
+        // the established connection is kept by the local peer
+        let connected_peers = local_peer.connected_peers();
+        let mut connected_peers = connected_peers.lock().await;
+        assert_eq!(connected_peers.len(), 1);
+        assert!(connected_peers[0].connection().is_some());
+        assert!(connected_peers[0].report().is_some());
         Ok(())
     }
 }
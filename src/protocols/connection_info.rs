@@ -1,3 +1 @@
-
-pub trait ConnectionInfo {
-}
+pub trait ConnectionInfo {}
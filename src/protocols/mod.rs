@@ -1,3 +1,3 @@
-pub mod peer;
 pub mod connection_info;
-pub mod peer_discovery;
\ No newline at end of file
+pub mod peer;
+pub mod peer_discovery;